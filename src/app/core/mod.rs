@@ -4,4 +4,4 @@ mod users;
 
 pub use applications::AppApplications;
 pub use sessions::{AppSessions, Session};
-pub use users::{AppUsers, User};
+pub use users::{AppUsers, User, MAX_APP_PASSWORDS};
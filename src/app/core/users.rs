@@ -2,6 +2,7 @@ use argon2::PasswordVerifier;
 use eyre::{eyre, Result};
 use futures::{StreamExt, TryStreamExt};
 use libsql::{params, Connection};
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -26,6 +27,20 @@ pub struct User {
     pub minecraft_uuid: Option<String>,
 }
 
+// the password itself is only shown once, when it's created
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppPassword {
+    pub name: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: time::OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_used: Option<time::OffsetDateTime>,
+}
+
+const APP_PASSWORD_LENGTH: usize = 32;
+// every app password has to be checked when logging in with one
+pub const MAX_APP_PASSWORDS: usize = 10;
+
 impl AppUsers {
     pub fn new(conn: Connection, config: crate::config::Config) -> Self {
         Self { conn, config }
@@ -164,6 +179,98 @@ impl AppUsers {
         Ok(())
     }
 
+    pub async fn get_app_passwords(&self, username: &str) -> Result<Vec<AppPassword>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT name, created_at, last_used FROM user_app_passwords WHERE username = ?",
+            )
+            .await?;
+
+        let rows = stmt.query([username]).await?;
+        let app_passwords = rows.into_stream().map(|row| {
+            let row = row?;
+            eyre::Ok(AppPassword {
+                name: row.get(0)?,
+                created_at: to_time(row.get(1)?)?,
+                last_used: row.get::<Option<i64>>(2)?.map(to_time).transpose()?,
+            })
+        });
+        app_passwords.try_collect::<Vec<_>>().await
+    }
+
+    // returns the generated password
+    pub async fn create_app_password(&self, username: &str, name: &str) -> Result<String> {
+        let password = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(APP_PASSWORD_LENGTH)
+            .map(char::from)
+            .collect::<String>();
+        let password_hash = hash_pw(&password)?;
+
+        self.conn
+            .execute(
+                "INSERT INTO user_app_passwords (username, name, password_hash) VALUES (?, ?, ?)",
+                [username, name, &password_hash],
+            )
+            .await?;
+
+        Ok(password)
+    }
+
+    pub async fn remove_app_password(&self, username: &str, name: &str) -> Result<bool> {
+        let deleted = self
+            .conn
+            .execute(
+                "DELETE FROM user_app_passwords WHERE username = ? AND name = ?",
+                [username, name],
+            )
+            .await?;
+
+        Ok(deleted > 0)
+    }
+
+    // checks the password against all of the user's app passwords, not their main password
+    pub async fn verify_app_password(&self, username: &str, password: &str) -> Result<bool> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name, password_hash FROM user_app_passwords WHERE username = ?")
+            .await?;
+
+        let mut rows = stmt.query([username]).await?;
+        let mut hashes = Vec::new();
+        while let Some(row) = rows.next().await? {
+            hashes.push((row.get::<String>(0)?, row.get::<String>(1)?));
+        }
+
+        // argon2 is slow on purpose, so it shouldn't block the runtime
+        let password = password.to_string();
+        let name = tokio::task::spawn_blocking(move || {
+            let hasher = argon2::Argon2::default();
+            for (name, password_hash) in hashes {
+                let password_hash = argon2::PasswordHash::new(&password_hash)?;
+                match hasher.verify_password(password.as_bytes(), &password_hash) {
+                    Ok(_) => return Ok(Some(name)),
+                    Err(argon2::password_hash::Error::Password) => {}
+                    Err(err) => return Err(eyre!(err)),
+                }
+            }
+            Ok(None)
+        })
+        .await??;
+
+        let Some(name) = name else {
+            return Ok(false);
+        };
+        self.conn
+            .execute(
+                "UPDATE user_app_passwords SET last_used = strftime('%s', 'now') WHERE username = ? AND name = ?",
+                [username, &name],
+            )
+            .await?;
+        Ok(true)
+    }
+
     pub async fn update_password(&self, username: &str, password: &str) -> Result<()> {
         let password_hash = hash_pw(password)?;
         self.conn
@@ -8,7 +8,7 @@ use serde::{Deserialize, Serialize};
 
 mod core;
 mod refinery_libsql;
-pub use core::{Session, User};
+pub use core::{Session, User, MAX_APP_PASSWORDS};
 
 use crate::{chat::state::ChatState, config::Config};
 
@@ -48,6 +48,10 @@ impl App {
         let applications = AppApplications::new(conn.clone(), config.clone());
         let sessions = AppSessions::new(conn.clone());
 
+        let chat = ChatState::load(conn.clone(), config.chat.clone()).await?;
+        let chat = Arc::new(chat);
+        tokio::spawn(chat.clone().run_retention());
+
         let sites = {
             DashMap::from_iter(
                 users
@@ -58,10 +62,12 @@ impl App {
             )
         };
 
-        sites.insert(
-            "lastfm-iceberg".to_string(),
-            Website::Site("henry".to_string(), "sites/lastfm-iceberg".to_string()),
-        );
+        for (hostname, project) in &config.web.sites {
+            sites.insert(
+                hostname.to_ascii_lowercase(),
+                Website::Site(project.user.clone(), project.project.clone()),
+            );
+        }
 
         Ok(Self {
             users,
@@ -69,7 +75,7 @@ impl App {
             sessions,
             config,
             sites: Arc::new(sites),
-            chat: Arc::new(ChatState::new()),
+            chat,
         })
     }
 
@@ -79,3 +85,20 @@ impl App {
         self.sites.insert(subdomain, website);
     }
 }
+
+// an in-memory database with every migration applied
+#[cfg(test)]
+pub async fn memory_db() -> libsql::Connection {
+    let db = libsql::Builder::new_local(":memory:")
+        .build()
+        .await
+        .unwrap();
+    let conn = db.connect().unwrap();
+    let mut runner = migrations::runner();
+    runner.set_migration_table_name("migrations");
+    runner
+        .run_async(&mut LibsqlConn(conn.clone()))
+        .await
+        .unwrap();
+    conn
+}
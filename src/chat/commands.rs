@@ -0,0 +1,452 @@
+use std::time::Duration;
+
+use eyre::{bail, Result};
+use futures::future::BoxFuture;
+
+use super::state::{ChatState, Connection};
+
+// the longest a user can be muted for
+const MAX_MUTE: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+
+// what a command was called with
+struct Context<'a> {
+    chat: &'a ChatState,
+    connection: &'a Connection,
+    room: &'a str,
+    // everything after the command name
+    args: &'a str,
+}
+
+type Handler = for<'a> fn(Context<'a>) -> BoxFuture<'a, Result<()>>;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    // only for users with a moderator role
+    moderator: bool,
+    handler: Handler,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "/help [command]",
+        help: "lists all commands or explains one",
+        moderator: false,
+        handler: help,
+    },
+    Command {
+        name: "me",
+        usage: "/me <action>",
+        help: "describes what you're doing, e.g. `/me waves`",
+        moderator: false,
+        handler: me,
+    },
+    Command {
+        name: "who",
+        usage: "/who [room]",
+        help: "lists the users in this or another room",
+        moderator: false,
+        handler: who,
+    },
+    Command {
+        name: "topic",
+        usage: "/topic [topic]",
+        help: "shows the topic of this room, or changes it if you created the room",
+        moderator: false,
+        handler: topic,
+    },
+    Command {
+        name: "msg",
+        usage: "/msg <user> <message>",
+        help: "sends a direct message",
+        moderator: false,
+        handler: msg,
+    },
+    Command {
+        name: "nick",
+        usage: "/nick <name>",
+        help: "explains why names can't be changed",
+        moderator: false,
+        handler: nick,
+    },
+    Command {
+        name: "kick",
+        usage: "/kick <user>",
+        help: "removes a user from this room",
+        moderator: true,
+        handler: kick,
+    },
+    Command {
+        name: "mute",
+        usage: "/mute <user> <duration>",
+        help: "stops a user from sending messages for a while, e.g. `30m`, `12h` or `7d`",
+        moderator: true,
+        handler: mute,
+    },
+    Command {
+        name: "unmute",
+        usage: "/unmute <user>",
+        help: "lets a muted user send messages again",
+        moderator: true,
+        handler: unmute,
+    },
+    Command {
+        name: "ban",
+        usage: "/ban <user>",
+        help: "removes a user from all rooms and stops them from using the chat",
+        moderator: true,
+        handler: ban,
+    },
+    Command {
+        name: "unban",
+        usage: "/unban <user>",
+        help: "lets a banned user use the chat again",
+        moderator: true,
+        handler: unban,
+    },
+    Command {
+        name: "slow",
+        usage: "/slow <duration|off>",
+        help: "makes users wait between messages in this room, e.g. `30s` or `5m`",
+        moderator: true,
+        handler: slow,
+    },
+    Command {
+        name: "delete",
+        usage: "/delete <id>",
+        help: "deletes a message in this room",
+        moderator: true,
+        handler: delete,
+    },
+];
+
+// runs a message starting with `/` in `room`
+pub async fn run(chat: &ChatState, room: &str, line: &str, connection: &Connection) -> Result<()> {
+    let line = line.trim_start_matches('/').trim();
+    let (name, args) = line
+        .split_once(char::is_whitespace)
+        .map(|(name, args)| (name, args.trim()))
+        .unwrap_or((line, ""));
+
+    let Some(command) = COMMANDS.iter().find(|command| command.name == name) else {
+        bail!("unknown command /{name}, see /help");
+    };
+
+    if command.moderator && !chat.is_moderator(connection).await? {
+        bail!("/{name} is only available to moderators");
+    }
+
+    (command.handler)(Context {
+        chat,
+        connection,
+        room,
+        args,
+    })
+    .await
+}
+
+impl Context<'_> {
+    fn reply(&self, message: &str) {
+        self.connection.send_msg("system", self.room, message);
+    }
+
+    // the first argument and the rest
+    fn split_args(&self) -> (&str, &str) {
+        self.args
+            .split_once(char::is_whitespace)
+            .map(|(first, rest)| (first, rest.trim()))
+            .unwrap_or((self.args, ""))
+    }
+}
+
+fn usage(name: &str) -> String {
+    let usage = COMMANDS
+        .iter()
+        .find(|command| command.name == name)
+        .map(|command| command.usage)
+        .unwrap_or_default();
+    format!("usage: {usage}")
+}
+
+fn help(ctx: Context<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let moderator = ctx.chat.is_moderator(ctx.connection).await?;
+        let available = COMMANDS
+            .iter()
+            .filter(|command| moderator || !command.moderator);
+
+        let name = ctx.args.trim_start_matches('/');
+        if !name.is_empty() {
+            let Some(command) = available.clone().find(|command| command.name == name) else {
+                bail!("unknown command /{name}, see /help");
+            };
+            ctx.reply(&format!("{} - {}", command.usage, command.help));
+            return Ok(());
+        }
+
+        let lines = available
+            .map(|command| format!("{} - {}", command.usage, command.help))
+            .collect::<Vec<_>>();
+        ctx.reply(&lines.join("\n"));
+        Ok(())
+    })
+}
+
+fn me(ctx: Context<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        if ctx.args.is_empty() {
+            bail!(usage("me"));
+        }
+        ctx.chat
+            .send_action(ctx.room, ctx.args, ctx.connection)
+            .await
+    })
+}
+
+fn who(ctx: Context<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let room = match ctx.args {
+            "" => ctx.room,
+            room => room,
+        };
+        let Some(users) = ctx.chat.room_users(room, &ctx.connection.username) else {
+            bail!("room {room} does not exist");
+        };
+
+        ctx.reply(&format!("{} in {room}: {}", users.len(), users.join(", ")));
+        Ok(())
+    })
+}
+
+// names are the account usernames, so there's nothing to change
+fn nick(ctx: Context<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        bail!(
+            "you are {}, names in the chat are account usernames and can't be changed",
+            ctx.connection.username
+        )
+    })
+}
+
+fn topic(ctx: Context<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        if !ctx.args.is_empty() {
+            return ctx
+                .chat
+                .set_topic(ctx.room, Some(ctx.args.to_string()), ctx.connection)
+                .await;
+        }
+
+        match ctx.chat.room_topic(ctx.room).flatten() {
+            Some(topic) => ctx.reply(&format!("topic of {}: {topic}", ctx.room)),
+            None => ctx.reply(&format!("{} has no topic", ctx.room)),
+        }
+        Ok(())
+    })
+}
+
+fn msg(ctx: Context<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let (username, message) = ctx.split_args();
+        if username.is_empty() || message.is_empty() {
+            bail!(usage("msg"));
+        }
+        ctx.chat
+            .send_direct_message(username, message.to_string(), ctx.connection)
+            .await
+    })
+}
+
+fn kick(ctx: Context<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let (username, _) = ctx.split_args();
+        if username.is_empty() {
+            bail!(usage("kick"));
+        }
+        ctx.chat.kick(ctx.room, username, ctx.connection).await?;
+        ctx.reply(&format!("kicked {username} from {}", ctx.room));
+        Ok(())
+    })
+}
+
+fn mute(ctx: Context<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let (username, duration) = ctx.split_args();
+        let Some(duration) = parse_duration(duration).filter(|_| !username.is_empty()) else {
+            bail!(usage("mute"));
+        };
+        if duration > MAX_MUTE {
+            bail!(
+                "users can be muted for at most {}",
+                format_duration(MAX_MUTE.as_secs())
+            );
+        }
+
+        ctx.chat
+            .mute(username, Some(duration), ctx.connection)
+            .await?;
+        ctx.reply(&format!(
+            "muted {username} for {}",
+            format_duration(duration.as_secs())
+        ));
+        Ok(())
+    })
+}
+
+fn unmute(ctx: Context<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let (username, _) = ctx.split_args();
+        if username.is_empty() {
+            bail!(usage("unmute"));
+        }
+        ctx.chat.mute(username, None, ctx.connection).await?;
+        ctx.reply(&format!("unmuted {username}"));
+        Ok(())
+    })
+}
+
+fn ban(ctx: Context<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let (username, _) = ctx.split_args();
+        if username.is_empty() {
+            bail!(usage("ban"));
+        }
+        ctx.chat.ban(username, true, ctx.connection).await?;
+        ctx.reply(&format!("banned {username}"));
+        Ok(())
+    })
+}
+
+fn unban(ctx: Context<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let (username, _) = ctx.split_args();
+        if username.is_empty() {
+            bail!(usage("unban"));
+        }
+        ctx.chat.ban(username, false, ctx.connection).await?;
+        ctx.reply(&format!("unbanned {username}"));
+        Ok(())
+    })
+}
+
+fn slow(ctx: Context<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let duration = match ctx.args {
+            "off" => None,
+            duration => match parse_duration(duration) {
+                Some(duration) => Some(duration),
+                None => bail!(usage("slow")),
+            },
+        };
+
+        ctx.chat
+            .set_slow_mode(ctx.room, duration, ctx.connection)
+            .await?;
+        match duration {
+            Some(duration) => ctx.reply(&format!(
+                "{} is now in slow mode, one message every {}",
+                ctx.room,
+                format_duration(duration.as_secs())
+            )),
+            None => ctx.reply(&format!("turned off slow mode in {}", ctx.room)),
+        }
+        Ok(())
+    })
+}
+
+fn delete(ctx: Context<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let Ok(id) = ctx.args.parse::<u64>() else {
+            bail!(usage("delete"));
+        };
+        ctx.chat.delete_message(ctx.room, id).await
+    })
+}
+
+// `90s`, `30m`, `12h`, `7d` or `2w`, plain numbers are minutes
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (amount, unit) = duration.split_at(split);
+
+    let seconds = match unit {
+        "s" => 1,
+        "" | "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => return None,
+    };
+
+    let amount = amount.parse::<u64>().ok().filter(|amount| *amount > 0)?;
+    Some(Duration::from_secs(amount.checked_mul(seconds)?))
+}
+
+// the largest unit that fits, e.g. `2h`
+pub fn format_duration(seconds: u64) -> String {
+    match seconds {
+        s if s >= 60 * 60 * 24 => format!("{}d", s / (60 * 60 * 24)),
+        s if s >= 60 * 60 => format!("{}h", s / (60 * 60)),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{s}s"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("30"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(
+            parse_duration("12h"),
+            Some(Duration::from_secs(12 * 60 * 60))
+        );
+        assert_eq!(
+            parse_duration("7d"),
+            Some(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+        assert_eq!(
+            parse_duration("2w"),
+            Some(Duration::from_secs(14 * 24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("0s"), None);
+        assert_eq!(parse_duration("0"), None);
+        assert_eq!(parse_duration("s"), None);
+        assert_eq!(parse_duration("-5m"), None);
+        assert_eq!(parse_duration("5 m"), None);
+        assert_eq!(parse_duration("5y"), None);
+        assert_eq!(parse_duration("1h30m"), None);
+        assert_eq!(parse_duration("５m"), None);
+    }
+
+    #[test]
+    fn rejects_overflowing_durations() {
+        assert_eq!(parse_duration("99999999999999999999s"), None);
+        assert_eq!(parse_duration(&format!("{}w", u64::MAX / 60)), None);
+        assert_eq!(
+            parse_duration(&format!("{}s", u64::MAX)),
+            Some(Duration::from_secs(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(59), "59s");
+        assert_eq!(format_duration(90), "1m");
+        assert_eq!(format_duration(2 * 60 * 60), "2h");
+        assert_eq!(format_duration(14 * 24 * 60 * 60), "14d");
+    }
+}
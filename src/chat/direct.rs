@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+
+use eyre::Result;
+use futures::{StreamExt, TryStreamExt};
+use libsql::{params, Connection};
+
+use super::DirectMessage;
+
+#[derive(Clone)]
+pub struct DirectMessages {
+    conn: Connection,
+}
+
+impl DirectMessages {
+    pub fn new(conn: Connection) -> Self {
+        Self { conn }
+    }
+
+    // returns the id of the new message
+    pub async fn insert(&self, message: &DirectMessage) -> Result<u64> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "INSERT INTO direct_messages (sender, recipient, message, time)
+                VALUES (?, ?, ?, ?) RETURNING id",
+            )
+            .await?;
+
+        let row = stmt
+            .query_row(params![
+                message.from.clone(),
+                message.to.clone(),
+                message.message.clone(),
+                message.time as i64
+            ])
+            .await?;
+        Ok(row.get::<i64>(0)? as u64)
+    }
+
+    // the newest `limit` messages between both users before the message with the id `before`,
+    // oldest first
+    pub async fn conversation(
+        &self,
+        username: &str,
+        other: &str,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<DirectMessage>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT id, sender, recipient, message, time FROM direct_messages
+                WHERE ((sender = ? AND recipient = ?) OR (sender = ? AND recipient = ?)) AND id < ?
+                ORDER BY id DESC LIMIT ?",
+            )
+            .await?;
+
+        let before = before.map(|before| before as i64).unwrap_or(i64::MAX);
+        let rows = stmt
+            .query(params![
+                username,
+                other,
+                other,
+                username,
+                before,
+                limit as i64
+            ])
+            .await?;
+        let messages = rows.into_stream().map(|row| {
+            let row = row?;
+            eyre::Ok(DirectMessage {
+                id: row.get::<i64>(0)? as u64,
+                from: row.get(1)?,
+                to: row.get(2)?,
+                message: row.get(3)?,
+                time: row.get::<i64>(4)? as u64,
+            })
+        });
+
+        let mut messages = messages.try_collect::<Vec<_>>().await?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    // unread messages per sender
+    pub async fn unread(&self, username: &str) -> Result<BTreeMap<String, u64>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT sender, count(*) FROM direct_messages
+                WHERE recipient = ? AND read = false GROUP BY sender",
+            )
+            .await?;
+
+        let rows = stmt.query([username]).await?;
+        let unread = rows.into_stream().map(|row| {
+            let row = row?;
+            eyre::Ok((row.get::<String>(0)?, row.get::<i64>(1)? as u64))
+        });
+
+        unread.try_collect::<BTreeMap<_, _>>().await
+    }
+
+    // marks everything `sender` sent to `username` as read
+    pub async fn mark_read(&self, username: &str, sender: &str) -> Result<()> {
+        self.conn
+            .execute(
+                "UPDATE direct_messages SET read = true WHERE recipient = ? AND sender = ? AND read = false",
+                params![username, sender],
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn direct_messages() -> DirectMessages {
+        let db = libsql::Builder::new_local(":memory:")
+            .build()
+            .await
+            .unwrap();
+        let conn = db.connect().unwrap();
+        for migration in [
+            include_str!("../migrations/V1__initial.sql"),
+            include_str!("../migrations/V4__direct_messages.sql"),
+        ] {
+            conn.execute_batch(migration).await.unwrap();
+        }
+        conn.execute_batch(
+            "INSERT INTO users (username, password_hash) VALUES ('alice', ''), ('bob', ''), ('carol', '')",
+        )
+        .await
+        .unwrap();
+        DirectMessages::new(conn)
+    }
+
+    fn message(from: &str, to: &str, message: &str, time: u64) -> DirectMessage {
+        DirectMessage {
+            id: 0,
+            from: from.to_string(),
+            to: to.to_string(),
+            message: message.to_string(),
+            time,
+        }
+    }
+
+    async fn texts(dms: &DirectMessages, before: Option<u64>, limit: usize) -> Vec<String> {
+        let messages = dms
+            .conversation("bob", "alice", before, limit)
+            .await
+            .unwrap();
+        messages.into_iter().map(|m| m.message).collect()
+    }
+
+    #[tokio::test]
+    async fn pages_back_through_conversations() {
+        let dms = direct_messages().await;
+        // messages sent in the same second are still paged in the order they were sent
+        let mut ids = Vec::new();
+        for (from, to, text) in [
+            ("alice", "bob", "a"),
+            ("bob", "alice", "b"),
+            ("alice", "carol", "x"),
+            ("alice", "bob", "c"),
+        ] {
+            ids.push(dms.insert(&message(from, to, text, 1)).await.unwrap());
+        }
+
+        let newest = dms.conversation("bob", "alice", None, 2).await.unwrap();
+        assert_eq!(
+            newest.iter().map(|m| m.id).collect::<Vec<_>>(),
+            [ids[1], ids[3]]
+        );
+        assert_eq!(texts(&dms, Some(ids[1]), 2).await, ["a"]);
+        assert_eq!(texts(&dms, Some(ids[0]), 2).await, Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn counts_unread_messages() {
+        let dms = direct_messages().await;
+        for (from, text) in [("alice", "a"), ("alice", "b"), ("carol", "c")] {
+            dms.insert(&message(from, "bob", text, 1)).await.unwrap();
+        }
+        dms.insert(&message("bob", "alice", "d", 1)).await.unwrap();
+
+        let unread = |pairs: &[(&str, u64)]| {
+            pairs
+                .iter()
+                .map(|(sender, count)| (sender.to_string(), *count))
+                .collect::<BTreeMap<_, _>>()
+        };
+        assert_eq!(
+            dms.unread("bob").await.unwrap(),
+            unread(&[("alice", 2), ("carol", 1)])
+        );
+
+        dms.mark_read("bob", "alice").await.unwrap();
+        assert_eq!(dms.unread("bob").await.unwrap(), unread(&[("carol", 1)]));
+        assert_eq!(dms.unread("alice").await.unwrap(), unread(&[("bob", 1)]));
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+
+use eyre::Result;
+use futures::{StreamExt, TryStreamExt};
+use libsql::{params, params_from_iter, Connection, Row};
+
+use super::ChatMessage;
+use crate::{config::RetentionConfig, ssg::safe_md_to_html};
+
+// older sqlite versions allow at most 999 parameters per statement
+const MAX_QUERY_PARAMS: usize = 500;
+
+#[derive(Clone)]
+pub struct ChatHistory {
+    conn: Connection,
+}
+
+impl ChatHistory {
+    pub fn new(conn: Connection) -> Self {
+        Self { conn }
+    }
+
+    // returns the id of the new message
+    pub async fn insert(&self, message: &ChatMessage) -> Result<u64> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "INSERT INTO chat_messages (room, username, message, time, action, reply_to)
+                VALUES (?, ?, ?, ?, ?, ?) RETURNING id",
+            )
+            .await?;
+
+        let row = stmt
+            .query_row(params![
+                message.room.clone(),
+                message.username.clone(),
+                message.message.clone(),
+                message.time as i64,
+                message.action,
+                message.reply_to.map(|id| id as i64)
+            ])
+            .await?;
+        Ok(row.get::<i64>(0)? as u64)
+    }
+
+    pub async fn message(&self, room: &str, id: u64) -> Result<Option<ChatMessage>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT id, username, room, message, time, action, reply_to, edited
+                FROM chat_messages WHERE room = ? AND id = ?",
+            )
+            .await?;
+
+        let mut rows = stmt.query(params![room, id as i64]).await?;
+        let Some(row) = rows.next().await? else {
+            return Ok(None);
+        };
+
+        let mut messages = [message_from_row(&row)?];
+        self.load_reactions(&mut messages).await?;
+        let [message] = messages;
+        Ok(Some(message))
+    }
+
+    // returns false if there is no such message in the room
+    pub async fn edit(&self, room: &str, id: u64, message: &str, edited: u64) -> Result<bool> {
+        let updated = self
+            .conn
+            .execute(
+                "UPDATE chat_messages SET message = ?, edited = ? WHERE room = ? AND id = ?",
+                params![message, edited as i64, room, id as i64],
+            )
+            .await?;
+        Ok(updated > 0)
+    }
+
+    // returns false if there is no such message in the room
+    pub async fn delete(&self, room: &str, id: u64) -> Result<bool> {
+        let deleted = self
+            .conn
+            .execute(
+                "DELETE FROM chat_messages WHERE room = ? AND id = ?",
+                params![room, id as i64],
+            )
+            .await?;
+        if deleted == 0 {
+            return Ok(false);
+        }
+
+        self.conn
+            .execute(
+                "DELETE FROM chat_reactions WHERE message_id = ?",
+                [id as i64],
+            )
+            .await?;
+        Ok(true)
+    }
+
+    // returns false if the user already reacted with the emoji
+    pub async fn add_reaction(
+        &self,
+        id: u64,
+        username: &str,
+        emoji: &str,
+        time: u64,
+    ) -> Result<bool> {
+        let added = self
+            .conn
+            .execute(
+                "INSERT INTO chat_reactions (message_id, username, emoji, time) VALUES (?, ?, ?, ?)
+                ON CONFLICT DO NOTHING",
+                params![id as i64, username, emoji, time as i64],
+            )
+            .await?;
+        Ok(added > 0)
+    }
+
+    // returns false if the user didn't react with the emoji
+    pub async fn remove_reaction(&self, id: u64, username: &str, emoji: &str) -> Result<bool> {
+        let removed = self
+            .conn
+            .execute(
+                "DELETE FROM chat_reactions WHERE message_id = ? AND username = ? AND emoji = ?",
+                params![id as i64, username, emoji],
+            )
+            .await?;
+        Ok(removed > 0)
+    }
+
+    // the newest `limit` messages before the message with the id `before`, oldest first
+    pub async fn messages(
+        &self,
+        room: &str,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT id, username, room, message, time, action, reply_to, edited
+                FROM chat_messages WHERE room = ? AND id < ?
+                ORDER BY id DESC LIMIT ?",
+            )
+            .await?;
+
+        let before = before.map(|before| before as i64).unwrap_or(i64::MAX);
+        let rows = stmt.query(params![room, before, limit as i64]).await?;
+        let messages = rows.into_stream().map(|row| message_from_row(&row?));
+
+        let mut messages = messages.try_collect::<Vec<_>>().await?;
+        messages.reverse();
+        self.load_reactions(&mut messages).await?;
+        Ok(messages)
+    }
+
+    async fn load_reactions(&self, messages: &mut [ChatMessage]) -> Result<()> {
+        let index = messages
+            .iter()
+            .enumerate()
+            .map(|(i, message)| (message.id, i))
+            .collect::<HashMap<_, _>>();
+        let ids = messages
+            .iter()
+            .map(|message| message.id as i64)
+            .collect::<Vec<_>>();
+
+        for ids in ids.chunks(MAX_QUERY_PARAMS) {
+            let placeholders = vec!["?"; ids.len()].join(", ");
+            let mut stmt = self
+                .conn
+                .prepare(&format!(
+                    "SELECT message_id, emoji, username FROM chat_reactions
+                    WHERE message_id IN ({placeholders}) ORDER BY time, rowid"
+                ))
+                .await?;
+            let mut rows = stmt.query(params_from_iter(ids.to_vec())).await?;
+
+            while let Some(row) = rows.next().await? {
+                let id = row.get::<i64>(0)? as u64;
+                if let Some(&i) = index.get(&id) {
+                    messages[i]
+                        .reactions
+                        .entry(row.get(1)?)
+                        .or_default()
+                        .push(row.get(2)?);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn rooms(&self) -> Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT DISTINCT room FROM chat_messages")
+            .await?;
+
+        let rows = stmt.query(()).await?;
+        let rooms = rows.into_stream().map(|row| row?.get::<String>(0));
+        Ok(rooms.try_collect::<Vec<_>>().await?)
+    }
+
+    // removes messages that are older or further back than the room keeps them
+    pub async fn prune(&self, room: &str, retention: &RetentionConfig) -> Result<u64> {
+        let mut deleted = 0;
+
+        if let Some(days) = retention.max_age_days {
+            let cutoff = time::OffsetDateTime::now_utc().unix_timestamp() - days as i64 * 86400;
+            deleted += self
+                .conn
+                .execute(
+                    "DELETE FROM chat_messages WHERE room = ? AND time < ?",
+                    params![room, cutoff],
+                )
+                .await?;
+        }
+
+        if let Some(max_messages) = retention.max_messages {
+            deleted += self
+                .conn
+                .execute(
+                    "DELETE FROM chat_messages WHERE room = ? AND id NOT IN (
+                        SELECT id FROM chat_messages WHERE room = ? ORDER BY id DESC LIMIT ?
+                    )",
+                    params![room, room, max_messages as i64],
+                )
+                .await?;
+        }
+
+        if deleted > 0 {
+            self.conn
+                .execute(
+                    "DELETE FROM chat_reactions
+                    WHERE message_id NOT IN (SELECT id FROM chat_messages)",
+                    (),
+                )
+                .await?;
+        }
+
+        Ok(deleted)
+    }
+}
+
+fn message_from_row(row: &Row) -> Result<ChatMessage> {
+    Ok(ChatMessage {
+        id: row.get::<i64>(0)? as u64,
+        username: row.get(1)?,
+        room: row.get(2)?,
+        message: row.get(3)?,
+        html: safe_md_to_html(&row.get::<String>(3)?),
+        time: row.get::<i64>(4)? as u64,
+        action: row.get(5)?,
+        reply_to: row.get::<Option<i64>>(6)?.map(|id| id as u64),
+        edited: row.get::<Option<i64>>(7)?.map(|time| time as u64),
+        reactions: Default::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn history() -> ChatHistory {
+        ChatHistory::new(crate::app::memory_db().await)
+    }
+
+    fn message(room: &str, message: &str, time: u64) -> ChatMessage {
+        ChatMessage {
+            id: 0,
+            username: "alice".to_string(),
+            room: room.to_string(),
+            message: message.to_string(),
+            html: String::new(),
+            time,
+            action: false,
+            reply_to: None,
+            edited: None,
+            reactions: Default::default(),
+        }
+    }
+
+    async fn texts(history: &ChatHistory, before: Option<u64>, limit: usize) -> Vec<String> {
+        let messages = history.messages("lobby", before, limit).await.unwrap();
+        messages.into_iter().map(|m| m.message).collect()
+    }
+
+    #[tokio::test]
+    async fn pages_back_through_history() {
+        let history = history().await;
+        // clocks can go backwards, messages are still paged in the order they were sent
+        for (text, time) in [("a", 10), ("b", 30), ("c", 20), ("d", 40)] {
+            history.insert(&message("lobby", text, time)).await.unwrap();
+        }
+        history.insert(&message("other", "x", 50)).await.unwrap();
+
+        assert_eq!(texts(&history, None, 2).await, ["c", "d"]);
+        assert_eq!(texts(&history, Some(3), 2).await, ["a", "b"]);
+        assert_eq!(texts(&history, Some(1), 2).await, Vec::<String>::new());
+
+        let id = history.insert(&message("lobby", "e", 60)).await.unwrap();
+        assert!(!history.delete("other", id).await.unwrap());
+        assert!(history.delete("lobby", id).await.unwrap());
+        assert_eq!(texts(&history, None, 1).await, ["d"]);
+
+        let mut rooms = history.rooms().await.unwrap();
+        rooms.sort();
+        assert_eq!(rooms, ["lobby", "other"]);
+    }
+
+    #[tokio::test]
+    async fn prunes_old_messages() {
+        let history = history().await;
+        let now = time::OffsetDateTime::now_utc().unix_timestamp() as u64;
+        for (text, time) in [("old", 0), ("a", now), ("b", now), ("c", now)] {
+            history.insert(&message("lobby", text, time)).await.unwrap();
+        }
+
+        let retention = RetentionConfig {
+            max_age_days: Some(1),
+            max_messages: Some(2),
+        };
+        assert_eq!(history.prune("lobby", &retention).await.unwrap(), 2);
+        assert_eq!(texts(&history, None, 10).await, ["b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn edits_and_reacts_to_messages() {
+        let history = history().await;
+        let first = history.insert(&message("lobby", "a", 1)).await.unwrap();
+        let reply = ChatMessage {
+            reply_to: Some(first),
+            ..message("lobby", "b", 2)
+        };
+        let second = history.insert(&reply).await.unwrap();
+
+        assert!(history.edit("lobby", first, "edited", 3).await.unwrap());
+        assert!(!history.edit("other", first, "edited", 3).await.unwrap());
+        let edited = history.message("lobby", first).await.unwrap().unwrap();
+        assert_eq!(
+            (edited.message.as_str(), edited.edited),
+            ("edited", Some(3))
+        );
+        assert_eq!(edited.html, "<p>edited</p>\n");
+        assert!(history.message("other", first).await.unwrap().is_none());
+
+        assert!(history
+            .add_reaction(second, "alice", "👍", 4)
+            .await
+            .unwrap());
+        assert!(!history
+            .add_reaction(second, "alice", "👍", 5)
+            .await
+            .unwrap());
+        assert!(history.add_reaction(second, "bob", "👍", 5).await.unwrap());
+        assert!(history.add_reaction(first, "bob", "🎉", 6).await.unwrap());
+        assert!(history.remove_reaction(first, "bob", "🎉").await.unwrap());
+        assert!(!history.remove_reaction(first, "bob", "🎉").await.unwrap());
+
+        let messages = history.messages("lobby", None, 10).await.unwrap();
+        assert!(messages[0].reactions.is_empty());
+        assert_eq!(messages[1].reply_to, Some(first));
+        assert_eq!(messages[1].reactions["👍"], ["alice", "bob"]);
+    }
+}
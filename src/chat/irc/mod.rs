@@ -0,0 +1,81 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use eyre::Result;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+use crate::{app::App, config::IrcConfig, web::tls::Certificates};
+
+mod protocol;
+mod session;
+
+// certificates are renewed by the web server, this picks them up
+const CERTIFICATE_RELOAD_INTERVAL: Duration = Duration::from_secs(60 * 60 * 12);
+
+// lets members use the chat from IRC clients, every client is a chat connection like a websocket
+pub async fn run(app: App, config: IrcConfig) -> Result<()> {
+    let interface = IpAddr::from_str(&config.interface)?;
+    let addr = SocketAddr::new(interface, config.port);
+    let plain_server = listen(addr, None, app.clone());
+    log::info!("irc server listening on {addr}");
+
+    let Some(tls_port) = config.tls_port else {
+        return plain_server.await;
+    };
+
+    let certificates = Arc::new(Certificates::load(app.config.certs_dir())?);
+    let acceptor = crate::web::tls::acceptor(certificates.clone(), Vec::new())?;
+    let tls_addr = SocketAddr::new(interface, tls_port);
+    let tls_server = listen(tls_addr, Some(acceptor), app);
+    log::info!("irc tls server listening on {tls_addr}");
+
+    tokio::select! {
+        r = plain_server => r,
+        r = tls_server => r,
+        r = reload_certificates(certificates) => r,
+    }
+}
+
+async fn listen(addr: SocketAddr, acceptor: Option<TlsAcceptor>, app: App) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::error!("failed to accept irc connection: {err}");
+                continue;
+            }
+        };
+
+        let app = app.clone();
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            let Some(acceptor) = acceptor else {
+                return session::run(stream, app, remote).await;
+            };
+
+            match acceptor.accept(stream).await {
+                Ok(stream) => session::run(stream, app, remote).await,
+                Err(err) => log::debug!("tls handshake with {remote} failed: {err}"),
+            }
+        });
+    }
+}
+
+async fn reload_certificates(certificates: Arc<Certificates>) -> Result<()> {
+    let mut interval = tokio::time::interval(CERTIFICATE_RELOAD_INTERVAL);
+    // the first tick is immediate, and the certificates were just loaded
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(err) = certificates.reload() {
+            log::error!("failed to reload certificates for irc: {err}");
+        }
+    }
+}
@@ -0,0 +1,160 @@
+// a line from a client, see https://modern.ircdocs.horse/#message-format
+#[derive(Debug)]
+pub struct Line {
+    // uppercase, e.g. `PRIVMSG` or `JOIN`
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+impl Line {
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+
+        // tags and the source aren't used, clients can't choose their source anyway
+        if rest.starts_with('@') {
+            rest = rest.split_once(' ')?.1.trim_start();
+        }
+        if rest.starts_with(':') {
+            rest = rest.split_once(' ')?.1.trim_start();
+        }
+
+        let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        if command.is_empty() {
+            return None;
+        }
+
+        let mut params = Vec::new();
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing.to_string());
+                break;
+            }
+
+            let (param, next) = rest.split_once(' ').unwrap_or((rest, ""));
+            params.push(param.to_string());
+            rest = next;
+        }
+
+        Some(Self {
+            command: command.to_ascii_uppercase(),
+            params,
+        })
+    }
+
+    pub fn param(&self, index: usize) -> Option<&str> {
+        self.params.get(index).map(String::as_str)
+    }
+}
+
+// formats the parameters of an outgoing line, the last one can contain spaces
+pub fn params(params: &[&str]) -> String {
+    let Some((last, rest)) = params.split_last() else {
+        return String::new();
+    };
+
+    let mut line = rest.join(" ");
+    if !line.is_empty() {
+        line.push(' ');
+    }
+    line.push(':');
+    line.push_str(last);
+    line
+}
+
+// `#room` to `room`
+pub fn room_name(channel: &str) -> Option<&str> {
+    channel.strip_prefix('#').filter(|room| !room.is_empty())
+}
+
+// splits a chat message into lines short enough for IRC clients
+pub fn split_message(message: &str, max_bytes: usize) -> Vec<&str> {
+    let mut lines = Vec::new();
+    for mut line in message.lines() {
+        while line.len() > max_bytes {
+            let mut split = max_bytes;
+            while !line.is_char_boundary(split) {
+                split -= 1;
+            }
+            // a character longer than the limit is sent on its own
+            if split == 0 {
+                split = line.chars().next().map_or(line.len(), char::len_utf8);
+            }
+            let (first, rest) = line.split_at(split);
+            lines.push(first);
+            line = rest;
+        }
+        if !line.is_empty() {
+            lines.push(line);
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lines() {
+        let line = Line::parse("privmsg #general :hello there\r\n").unwrap();
+        assert_eq!(line.command, "PRIVMSG");
+        assert_eq!(line.params, ["#general", "hello there"]);
+
+        let line = Line::parse("@time=now :nick!user@host JOIN  #a,#b").unwrap();
+        assert_eq!(line.command, "JOIN");
+        assert_eq!(line.params, ["#a,#b"]);
+
+        let line = Line::parse("PING").unwrap();
+        assert!(line.params.is_empty());
+        assert_eq!(line.param(0), None);
+    }
+
+    #[test]
+    fn keeps_empty_trailing_params() {
+        let line = Line::parse("TOPIC #general :").unwrap();
+        assert_eq!(line.params, ["#general", ""]);
+
+        let line = Line::parse("PRIVMSG #general ::)").unwrap();
+        assert_eq!(line.param(1), Some(":)"));
+    }
+
+    #[test]
+    fn rejects_lines_without_command() {
+        assert!(Line::parse("").is_none());
+        assert!(Line::parse("\r\n").is_none());
+        assert!(Line::parse(":source").is_none());
+        assert!(Line::parse("@tags").is_none());
+        assert!(Line::parse(" PRIVMSG").is_none());
+    }
+
+    #[test]
+    fn formats_params() {
+        assert_eq!(params(&[]), "");
+        assert_eq!(params(&["hello there"]), ":hello there");
+        assert_eq!(params(&["bob", "#general", "hi"]), "bob #general :hi");
+    }
+
+    #[test]
+    fn splits_messages() {
+        assert_eq!(split_message("hello\nworld", 10), ["hello", "world"]);
+        assert_eq!(split_message("abcdefgh", 3), ["abc", "def", "gh"]);
+        assert_eq!(split_message("a\n\nb", 10), ["a", "b"]);
+        assert!(split_message("", 10).is_empty());
+    }
+
+    #[test]
+    fn splits_at_char_boundaries() {
+        // é is two bytes, 🎉 four
+        assert_eq!(split_message("aéé", 2), ["a", "é", "é"]);
+        assert_eq!(split_message("é🎉é", 6), ["é🎉", "é"]);
+        assert_eq!(split_message("é🎉é", 5), ["é", "🎉", "é"]);
+        assert_eq!(split_message("🎉🎉", 2), ["🎉", "🎉"]);
+        for line in split_message(&"ü".repeat(300), 255) {
+            assert!(line.len() <= 255);
+        }
+    }
+}
@@ -0,0 +1,640 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use eyre::{bail, Result};
+use futures::StreamExt;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf},
+    sync::broadcast::{
+        self,
+        error::{RecvError, TryRecvError},
+    },
+};
+use tokio_util::codec::{FramedRead, LinesCodec};
+
+use super::protocol::{self, Line};
+use crate::{
+    app::App,
+    chat::{spam::TokenBucket, state::Connection, ChatRequest, ChatResponse},
+    config::RateLimitConfig,
+};
+
+// longer than the 512 bytes of the RFC, to leave room for IRCv3 tags
+const MAX_LINE_LENGTH: usize = 4096;
+// most clients cut off longer lines, so messages are split
+const MAX_MESSAGE_BYTES: usize = 400;
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(60);
+const PING_INTERVAL: Duration = Duration::from_secs(90);
+// clients that haven't sent anything for this long are disconnected
+const PING_TIMEOUT: Duration = Duration::from_secs(240);
+// slows down guessing app passwords
+const FAILED_LOGIN_DELAY: Duration = Duration::from_secs(2);
+// failed logins per address, so guessing isn't faster with more connections
+const FAILED_LOGINS_BURST: u32 = 10;
+const FAILED_LOGINS_PER_MINUTE: u32 = 5;
+
+static FAILED_LOGINS: LazyLock<DashMap<IpAddr, TokenBucket>> = LazyLock::new(DashMap::new);
+
+type Lines<S> = FramedRead<ReadHalf<S>, LinesCodec>;
+
+#[derive(Default)]
+struct Registration {
+    password: Option<String>,
+    nick: Option<String>,
+    user: bool,
+    // once a client starts capability negotiation, registration waits for `CAP END`
+    negotiating: bool,
+}
+
+struct Session<W> {
+    app: App,
+    writer: W,
+    // the web domain, used as the server name and as the host of every user
+    server: String,
+    // `*` until the client is registered, then the username
+    nick: String,
+    address: IpAddr,
+    // rooms the client was told it joined
+    channels: HashSet<String>,
+    topics: HashMap<String, Option<String>>,
+}
+
+pub async fn run<S>(stream: S, app: App, remote: SocketAddr)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let mut lines = FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_LINE_LENGTH));
+    let mut session = Session {
+        server: app.config.web.domain.clone(),
+        app: app.clone(),
+        writer,
+        nick: "*".to_string(),
+        address: remote.ip(),
+        channels: HashSet::new(),
+        topics: HashMap::new(),
+    };
+
+    let registration = tokio::time::timeout(REGISTRATION_TIMEOUT, session.register(&mut lines));
+    let connection = match registration.await {
+        Ok(Ok(Some(connection))) => connection,
+        Ok(Ok(None)) => return,
+        Ok(Err(err)) => {
+            let _ = session.send(&format!("ERROR :{err}")).await;
+            return;
+        }
+        Err(_) => {
+            let _ = session.send("ERROR :Registration timed out").await;
+            return;
+        }
+    };
+    log::info!("{} connected to the chat over irc", connection.username);
+
+    let mut rx = connection.channel.subscribe();
+    if let Err(err) = session.serve(&mut lines, &mut rx, &connection).await {
+        log::debug!("irc connection with {remote} failed: {err}");
+    }
+    app.chat.disconnect(&connection);
+}
+
+fn is_limited(address: IpAddr) -> bool {
+    FAILED_LOGINS
+        .get_mut(&address)
+        .is_some_and(|mut attempts| attempts.is_empty())
+}
+
+fn failed_login(address: IpAddr) {
+    // addresses that haven't failed for a while don't need to be remembered
+    FAILED_LOGINS.retain(|_, attempts| !attempts.is_full());
+    FAILED_LOGINS
+        .entry(address)
+        .or_insert_with(|| {
+            TokenBucket::new(&RateLimitConfig {
+                burst: FAILED_LOGINS_BURST,
+                per_minute: FAILED_LOGINS_PER_MINUTE,
+            })
+        })
+        .take();
+}
+
+impl<W: AsyncWrite + Unpin> Session<W> {
+    // handles everything up to `NICK` and `USER`, returns `None` if the client quit
+    async fn register<S: AsyncRead>(&mut self, lines: &mut Lines<S>) -> Result<Option<Connection>> {
+        let mut registration = Registration::default();
+
+        while let Some(line) = lines.next().await {
+            let Some(line) = Line::parse(&line?) else {
+                continue;
+            };
+
+            match line.command.as_str() {
+                "CAP" => match line.param(0) {
+                    Some("LS") => {
+                        registration.negotiating = true;
+                        self.send(&format!(":{} CAP * LS :", self.server)).await?;
+                    }
+                    // no capabilities are supported
+                    Some("REQ") => {
+                        let requested = line.param(1).unwrap_or_default();
+                        self.send(&format!(":{} CAP * NAK :{requested}", self.server))
+                            .await?;
+                    }
+                    Some("END") => registration.negotiating = false,
+                    _ => {}
+                },
+                "PASS" => registration.password = line.param(0).map(str::to_string),
+                "NICK" => registration.nick = line.param(0).map(str::to_string),
+                "USER" => registration.user = true,
+                "PING" => self.pong(&line).await?,
+                "QUIT" => return Ok(None),
+                _ => self.numeric("451", &["You have not registered"]).await?,
+            }
+
+            if registration.user && !registration.negotiating {
+                if let Some(nick) = registration.nick.take() {
+                    let password = registration.password.take();
+                    return self.authenticate(nick, password).await.map(Some);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    // the nick is the username, the password has to be one of the user's app passwords
+    async fn authenticate(&mut self, nick: String, password: Option<String>) -> Result<Connection> {
+        self.nick = nick;
+
+        let Some(password) = password else {
+            self.numeric(
+                "464",
+                &["Password required, create an app password in your account settings"],
+            )
+            .await?;
+            bail!("Password required");
+        };
+
+        if is_limited(self.address) {
+            self.numeric("464", &["Too many failed logins, try again later"])
+                .await?;
+            bail!("Too many failed logins");
+        }
+
+        let users = self.app.users.clone();
+        if !users.verify_app_password(&self.nick, &password).await? {
+            failed_login(self.address);
+            tokio::time::sleep(FAILED_LOGIN_DELAY).await;
+            self.numeric("464", &["Password incorrect"]).await?;
+            bail!("Password incorrect");
+        }
+
+        if self.app.chat.is_banned(&self.nick) {
+            bail!("You are banned from the chat");
+        }
+        Ok(self.app.chat.connect(Some(self.nick.clone())))
+    }
+
+    async fn serve<S: AsyncRead>(
+        &mut self,
+        lines: &mut Lines<S>,
+        rx: &mut broadcast::Receiver<ChatResponse>,
+        connection: &Connection,
+    ) -> Result<()> {
+        self.welcome().await?;
+        for room in self.app.chat.clone().join_rooms(connection) {
+            self.joined(&room).await?;
+        }
+
+        let start = tokio::time::Instant::now() + PING_INTERVAL;
+        let mut ping = tokio::time::interval_at(start, PING_INTERVAL);
+        let mut last_seen = Instant::now();
+
+        loop {
+            tokio::select! {
+                line = lines.next() => {
+                    let Some(line) = line else {
+                        return Ok(());
+                    };
+                    last_seen = Instant::now();
+
+                    let Some(line) = Line::parse(&line?) else {
+                        continue;
+                    };
+                    if !self.handle(line, connection).await? {
+                        return Ok(());
+                    }
+
+                    // what the request caused, the client's own messages aren't echoed back to it
+                    loop {
+                        match rx.try_recv() {
+                            Ok(response) => self.deliver(response, true).await?,
+                            Err(TryRecvError::Lagged(_)) => continue,
+                            Err(_) => break,
+                        }
+                    }
+                }
+                response = rx.recv() => match response {
+                    Ok(response) => self.deliver(response, false).await?,
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::debug!("irc connection of {} skipped {skipped} responses", self.nick);
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = ping.tick() => {
+                    if last_seen.elapsed() > PING_TIMEOUT {
+                        self.send("ERROR :Ping timeout").await?;
+                        return Ok(());
+                    }
+                    self.send(&format!("PING :{}", self.server)).await?;
+                }
+                _ = connection.closed.cancelled() => {
+                    // e.g. why the connection was closed
+                    while let Ok(response) = rx.try_recv() {
+                        self.deliver(response, false).await?;
+                    }
+                    self.send("ERROR :Closing link").await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // returns false if the client quit
+    async fn handle(&mut self, line: Line, connection: &Connection) -> Result<bool> {
+        let chat = self.app.chat.clone();
+
+        match line.command.as_str() {
+            "PING" => self.pong(&line).await?,
+            // clients must not reply to notices, so there's nothing to do with them either
+            "PONG" | "NOTICE" | "CAP" => {}
+            "QUIT" => return Ok(false),
+            "JOIN" | "PART" => {
+                let channels = line.param(0).unwrap_or_default();
+                for channel in channels.split(',') {
+                    let Some(room) = protocol::room_name(channel) else {
+                        self.numeric("403", &[channel, "No such channel"]).await?;
+                        continue;
+                    };
+
+                    let room = room.to_string();
+                    let request = match line.command.as_str() {
+                        "JOIN" => ChatRequest::Join { room },
+                        _ => ChatRequest::Leave { room },
+                    };
+                    chat.handle_req(request, connection.clone()).await;
+                }
+            }
+            "PRIVMSG" => {
+                let (Some(target), Some(text)) = (line.param(0), line.param(1)) else {
+                    self.numeric("412", &["No text to send"]).await?;
+                    return Ok(true);
+                };
+
+                let action = ctcp_action(text);
+                let request = match protocol::room_name(target) {
+                    Some(room) => {
+                        let message = match action {
+                            Some(action) => format!("/me {action}"),
+                            // other CTCP requests like VERSION aren't supported
+                            None if text.starts_with('\x01') => return Ok(true),
+                            None => text.to_string(),
+                        };
+                        ChatRequest::Message {
+                            room: room.to_string(),
+                            message,
+                            reply_to: None,
+                        }
+                    }
+                    None => ChatRequest::DirectMessage {
+                        username: target.to_string(),
+                        message: action.unwrap_or(text).to_string(),
+                    },
+                };
+                chat.handle_req(request, connection.clone()).await;
+            }
+            "NAMES" => {
+                let channels = line.param(0).unwrap_or_default();
+                for channel in channels.split(',').filter(|channel| !channel.is_empty()) {
+                    self.names(channel).await?;
+                }
+            }
+            "TOPIC" => {
+                let Some(channel) = line.param(0) else {
+                    self.numeric("461", &["TOPIC", "Not enough parameters"])
+                        .await?;
+                    return Ok(true);
+                };
+
+                match (protocol::room_name(channel), line.param(1)) {
+                    (Some(room), Some(topic)) => {
+                        let request = ChatRequest::Topic {
+                            room: room.to_string(),
+                            topic: Some(topic.to_string()),
+                        };
+                        chat.handle_req(request, connection.clone()).await;
+                    }
+                    (Some(_), None) => self.topic(channel).await?,
+                    (None, _) => self.numeric("403", &[channel, "No such channel"]).await?,
+                }
+            }
+            "WHO" => self.who(line.param(0).unwrap_or("*")).await?,
+            "LIST" => self.list(connection).await?,
+            "MODE" => self.mode(&line).await?,
+            "NICK" => {
+                self.notice("Nick changes aren't supported, your nick is your username")
+                    .await?
+            }
+            "PASS" | "USER" => self.numeric("462", &["You may not reregister"]).await?,
+            command => self.numeric("421", &[command, "Unknown command"]).await?,
+        }
+
+        Ok(true)
+    }
+
+    // `own` is set for responses to the client's own requests
+    async fn deliver(&mut self, response: ChatResponse, own: bool) -> Result<()> {
+        match response {
+            ChatResponse::Join { username, room, .. } => {
+                if username == self.nick {
+                    self.joined(&room).await?;
+                } else if self.channels.contains(&room) {
+                    let source = self.source(&username);
+                    self.send(&format!(":{source} JOIN #{room}")).await?;
+                }
+            }
+            ChatResponse::Leave { username, room, .. } => {
+                if !self.channels.contains(&room) {
+                    return Ok(());
+                }
+                if username == self.nick {
+                    self.channels.remove(&room);
+                    self.topics.remove(&room);
+                }
+                let source = self.source(&username);
+                self.send(&format!(":{source} PART #{room}")).await?;
+            }
+            ChatResponse::Message(message) => {
+                if own && message.id != 0 && message.username == self.nick {
+                    return Ok(());
+                }
+
+                let source = self.source(&message.username);
+                // notices only sent to this connection, e.g. replies to commands
+                if message.id == 0 {
+                    let target = match self.channels.contains(&message.room) {
+                        true => format!("#{}", message.room),
+                        false => self.nick.clone(),
+                    };
+                    for line in protocol::split_message(&message.message, MAX_MESSAGE_BYTES) {
+                        self.send(&format!(":{source} NOTICE {target} :{line}"))
+                            .await?;
+                    }
+                    return Ok(());
+                }
+
+                for line in protocol::split_message(&message.message, MAX_MESSAGE_BYTES) {
+                    let text = match message.action {
+                        true => format!("\x01ACTION {line}\x01"),
+                        false => line.to_string(),
+                    };
+                    self.send(&format!(":{source} PRIVMSG #{} :{text}", message.room))
+                        .await?;
+                }
+            }
+            // direct messages sent from the user's other connections can't be shown in IRC
+            ChatResponse::DirectMessage(direct_message) => {
+                if direct_message.to != self.nick || direct_message.from == self.nick {
+                    return Ok(());
+                }
+
+                let source = self.source(&direct_message.from);
+                for line in protocol::split_message(&direct_message.message, MAX_MESSAGE_BYTES) {
+                    self.send(&format!(":{source} PRIVMSG {} :{line}", self.nick))
+                        .await?;
+                }
+            }
+            ChatResponse::Room { room, topic, .. } => {
+                if !self.channels.contains(&room) || self.topics.get(&room) == Some(&topic) {
+                    return Ok(());
+                }
+
+                self.topics.insert(room.clone(), topic.clone());
+                let topic = topic.unwrap_or_default();
+                self.send(&format!(":{} TOPIC #{room} :{topic}", self.server))
+                    .await?;
+            }
+            ChatResponse::Error { message } => self.notice(&message).await?,
+            // only useful for the web client, or there's nothing like it in IRC
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn welcome(&mut self) -> Result<()> {
+        let server = self.server.clone();
+        let version = concat!("dawdle-server-", env!("CARGO_PKG_VERSION"));
+
+        self.numeric(
+            "001",
+            &[&format!("Welcome to the {server} chat, {}", self.nick)],
+        )
+        .await?;
+        self.numeric(
+            "002",
+            &[&format!("Your host is {server}, running {version}")],
+        )
+        .await?;
+        self.numeric(
+            "003",
+            &["This server is a bridge to the chat on the website"],
+        )
+        .await?;
+        self.numeric("004", &[&server, version, "o", "t"]).await?;
+        self.numeric(
+            "005",
+            &[
+                "CHANTYPES=#",
+                "CASEMAPPING=ascii",
+                "NICKLEN=32",
+                "CHANNELLEN=33",
+                "TOPICLEN=200",
+                &format!("NETWORK={server}"),
+                "are supported by this server",
+            ],
+        )
+        .await?;
+        self.numeric("422", &["MOTD File is missing"]).await
+    }
+
+    // tells the client it joined a room, with the topic and the users in it
+    async fn joined(&mut self, room: &str) -> Result<()> {
+        if !self.channels.insert(room.to_string()) {
+            return Ok(());
+        }
+
+        let channel = format!("#{room}");
+        let source = self.source(&self.nick);
+        self.send(&format!(":{source} JOIN {channel}")).await?;
+
+        let topic = self.app.chat.room_topic(room).flatten();
+        self.topics.insert(room.to_string(), topic.clone());
+        if let Some(topic) = topic {
+            self.numeric("332", &[&channel, &topic]).await?;
+        }
+        self.names(&channel).await
+    }
+
+    async fn names(&mut self, channel: &str) -> Result<()> {
+        let users = protocol::room_name(channel)
+            .and_then(|room| self.app.chat.room_users(room, &self.nick))
+            .unwrap_or_default();
+
+        for users in users.chunks(20) {
+            self.numeric("353", &["=", channel, &users.join(" ")])
+                .await?;
+        }
+        self.numeric("366", &[channel, "End of /NAMES list"]).await
+    }
+
+    async fn topic(&mut self, channel: &str) -> Result<()> {
+        let chat = self.app.chat.clone();
+        let room = protocol::room_name(channel).unwrap_or_default();
+        if chat.room_users(room, &self.nick).is_none() {
+            return self.numeric("403", &[channel, "No such channel"]).await;
+        }
+
+        match chat.room_topic(room).flatten() {
+            Some(topic) => self.numeric("332", &[channel, &topic]).await,
+            None => self.numeric("331", &[channel, "No topic is set"]).await,
+        }
+    }
+
+    async fn who(&mut self, mask: &str) -> Result<()> {
+        let chat = self.app.chat.clone();
+        let (channel, users) = match protocol::room_name(mask) {
+            Some(room) => (mask, chat.room_users(room, &self.nick).unwrap_or_default()),
+            None if chat.users.contains_key(mask) => ("*", vec![mask.to_string()]),
+            None => ("*", Vec::new()),
+        };
+
+        let server = self.server.clone();
+        for user in users {
+            let realname = format!("0 {user}");
+            self.numeric(
+                "352",
+                &[channel, &user, &server, &server, &user, "H", &realname],
+            )
+            .await?;
+        }
+        self.numeric("315", &[mask, "End of /WHO list"]).await
+    }
+
+    async fn list(&mut self, connection: &Connection) -> Result<()> {
+        let chat = self.app.chat.clone();
+        let mut rooms = chat.public_rooms();
+        rooms.extend(chat.private_rooms(connection).unwrap_or_default());
+
+        self.numeric("321", &["Channel", "Users  Name"]).await?;
+        for room in rooms {
+            let Some(users) = chat.room_users(&room, &self.nick) else {
+                continue;
+            };
+            let topic = chat.room_topic(&room).flatten().unwrap_or_default();
+            self.numeric(
+                "322",
+                &[&format!("#{room}"), &users.len().to_string(), &topic],
+            )
+            .await?;
+        }
+        self.numeric("323", &["End of /LIST"]).await
+    }
+
+    // modes can't be changed, but clients ask for them after joining
+    async fn mode(&mut self, line: &Line) -> Result<()> {
+        let Some(target) = line.param(0) else {
+            return self
+                .numeric("461", &["MODE", "Not enough parameters"])
+                .await;
+        };
+
+        if protocol::room_name(target).is_some() {
+            match line.param(1) {
+                None => self.numeric("324", &[target, "+t"]).await,
+                Some("b") | Some("+b") => {
+                    self.numeric("368", &[target, "End of channel ban list"])
+                        .await
+                }
+                Some(_) => {
+                    self.numeric("482", &[target, "Channel modes can't be changed"])
+                        .await
+                }
+            }
+        } else if target == self.nick {
+            self.numeric("221", &["+"]).await
+        } else {
+            self.numeric("502", &["Can't change mode for other users"])
+                .await
+        }
+    }
+
+    async fn pong(&mut self, line: &Line) -> Result<()> {
+        let token = line.param(0).unwrap_or_default().to_string();
+        self.send(&format!(":{} PONG {} :{token}", self.server, self.server))
+            .await
+    }
+
+    async fn notice(&mut self, message: &str) -> Result<()> {
+        for line in protocol::split_message(message, MAX_MESSAGE_BYTES) {
+            self.send(&format!(":{} NOTICE {} :{line}", self.server, self.nick))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn numeric(&mut self, code: &str, params: &[&str]) -> Result<()> {
+        let params = protocol::params(params);
+        self.send(&format!(":{} {code} {} {params}", self.server, self.nick))
+            .await
+    }
+
+    fn source(&self, username: &str) -> String {
+        format!("{username}!{username}@{}", self.server)
+    }
+
+    async fn send(&mut self, line: &str) -> Result<()> {
+        // topics and usernames could otherwise end the line early
+        let line = line.replace(['\r', '\n'], " ");
+        self.writer.write_all(line.as_bytes()).await?;
+        self.writer.write_all(b"\r\n").await?;
+        Ok(())
+    }
+}
+
+// the text of `\x01ACTION waves\x01`, sent by clients for `/me`
+fn ctcp_action(text: &str) -> Option<&str> {
+    text.strip_prefix("\x01ACTION ")
+        .map(|action| action.trim_end_matches('\x01'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_failed_logins_per_address() {
+        let address = IpAddr::from([192, 0, 2, 1]);
+        let other = IpAddr::from([192, 0, 2, 2]);
+
+        for _ in 0..FAILED_LOGINS_BURST {
+            assert!(!is_limited(address));
+            failed_login(address);
+        }
+        assert!(is_limited(address));
+        assert!(!is_limited(other));
+    }
+}
@@ -2,43 +2,183 @@ use crate::app::App;
 use axum::extract::ws::{Message, WebSocket};
 use futures::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
 
+mod commands;
+pub mod direct;
+pub mod history;
+pub mod irc;
+pub mod moderation;
+pub mod rooms;
+pub mod spam;
 pub mod state;
+pub mod terminal;
+
+use std::collections::BTreeMap;
 
 type Room = String;
 type Username = String;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ChatMessage {
+    // 0 for notices only sent to one connection, which aren't stored
+    id: u64,
     username: Username,
     room: Room,
     message: String,
+    // the message as markdown, rendered in safe mode for web clients
+    #[serde(default)]
+    html: String,
+    time: u64,
+    // sent with `/me`, e.g. "henry waves"
+    action: bool,
+    #[serde(default)]
+    reply_to: Option<u64>,
+    // unix time of the last edit
+    #[serde(default)]
+    edited: Option<u64>,
+    // the users who reacted with each emoji, in the order they did
+    #[serde(default)]
+    reactions: BTreeMap<String, Vec<Username>>,
+}
+
+// only between members, guests can't send or receive them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectMessage {
+    id: u64,
+    from: Username,
+    to: Username,
+    message: String,
     time: u64,
 }
 
 #[derive(Clone, Deserialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum ChatRequest {
-    Message { room: Room, message: String },
-    Join { room: Room },
-    History { room: Room },
+    #[serde(rename_all = "camelCase")]
+    Message {
+        room: Room,
+        message: String,
+        #[serde(default)]
+        reply_to: Option<u64>,
+    },
+    // only the author can edit a message
+    Edit {
+        room: Room,
+        id: u64,
+        message: String,
+    },
+    // by the author, moderators can delete any message
+    Delete {
+        room: Room,
+        id: u64,
+    },
+    React {
+        room: Room,
+        id: u64,
+        emoji: String,
+    },
+    Unreact {
+        room: Room,
+        id: u64,
+        emoji: String,
+    },
+    Join {
+        room: Room,
+    },
+    Leave {
+        room: Room,
+    },
+    // only members can create rooms, private rooms can only be joined after an invite
+    CreateRoom {
+        room: Room,
+        #[serde(default)]
+        private: bool,
+        topic: Option<String>,
+    },
+    Invite {
+        room: Room,
+        username: Username,
+    },
+    Topic {
+        room: Room,
+        topic: Option<String>,
+    },
+    // the newest `limit` messages before the message with the id `before`, for scrolling back
+    History {
+        room: Room,
+        before: Option<u64>,
+        limit: Option<usize>,
+    },
+    DirectMessage {
+        username: Username,
+        message: String,
+    },
+    DirectHistory {
+        username: Username,
+        before: Option<u64>,
+        limit: Option<usize>,
+    },
+    // marks the messages received from `username` as read
+    MarkRead {
+        username: Username,
+    },
+    // the user is typing in `room`, should be repeated every few seconds while they are
+    Typing {
+        room: Room,
+    },
     Info,
 }
 
 #[derive(Clone, Serialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum ChatResponse {
-    // Join {
-    //     username: Username,
-    //     room: Room,
-    //     time: u64,
-    // },
-    // Leave {
-    //     username: Username,
-    //     room: Room,
-    //     time: u64,
-    // },
+    Join {
+        username: Username,
+        room: Room,
+        time: u64,
+    },
+    Leave {
+        username: Username,
+        room: Room,
+        time: u64,
+    },
+    // the user opened their first or closed their last connection
+    Presence {
+        username: Username,
+        online: bool,
+        time: u64,
+    },
+    Typing {
+        username: Username,
+        room: Room,
+    },
     Message(ChatMessage),
+    Edit {
+        room: Room,
+        id: u64,
+        message: String,
+        html: String,
+        edited: u64,
+    },
+    // removed by the author or a moderator
+    Delete {
+        room: Room,
+        id: u64,
+    },
+    React {
+        room: Room,
+        id: u64,
+        username: Username,
+        emoji: String,
+    },
+    Unreact {
+        room: Room,
+        id: u64,
+        username: Username,
+        emoji: String,
+    },
     #[serde(rename_all = "camelCase")]
     Info {
         default_room: Room,
@@ -46,15 +186,30 @@ pub enum ChatResponse {
         private_rooms: Option<Vec<Room>>,
     },
 
-    // Room {
-    //     room: Room,
-    //     users: Vec<Username>,
-    // },
+    #[serde(rename_all = "camelCase")]
+    Room {
+        room: Room,
+        topic: Option<String>,
+        private: bool,
+        // seconds between messages of a user, 0 is off
+        slow_mode: u64,
+        users: Vec<Username>,
+    },
     RoomHistory {
         room: Room,
         history: Vec<ChatMessage>,
     },
 
+    DirectMessage(DirectMessage),
+    DirectHistory {
+        username: Username,
+        history: Vec<DirectMessage>,
+    },
+    // unread direct messages per sender
+    Unread {
+        counts: BTreeMap<Username, u64>,
+    },
+
     Error {
         message: String,
     },
@@ -68,12 +223,41 @@ pub async fn handle_chat_socket(stream: WebSocket, username: Option<String>, sta
     let chat = state.chat;
     let (mut sender, mut receiver) = stream.split();
 
+    if username
+        .as_deref()
+        .is_some_and(|username| chat.is_banned(username))
+    {
+        let error = ChatResponse::Error {
+            message: "you are banned from the chat".to_string(),
+        };
+        let _ = sender.send(response(error)).await;
+        return;
+    }
+
     let connection = chat.connect(username.clone());
-    chat.join_room("general", &connection.username);
 
     let mut rx = connection.channel.subscribe();
+    let closed = connection.closed.clone();
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
+        loop {
+            let msg = tokio::select! {
+                msg = rx.recv() => msg,
+                _ = closed.cancelled() => {
+                    // whatever is still queued, e.g. why the connection was closed
+                    while let Ok(msg) = rx.try_recv() {
+                        let _ = sender.send(response(msg)).await;
+                    }
+                    let _ = sender.close().await;
+                    break;
+                }
+            };
+
+            let msg = match msg {
+                Ok(msg) => msg,
+                // a slow client misses some events instead of being disconnected
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
             // In any websocket error, break loop.
             if sender.send(response(msg)).await.is_err() {
                 break;
@@ -81,8 +265,12 @@ pub async fn handle_chat_socket(stream: WebSocket, username: Option<String>, sta
         }
     });
 
-    connection.send_info("general", Vec::new());
-    connection.send_room_history("general", chat.room_history("general"));
+    chat.send_info(&connection);
+    chat.send_unread(&connection).await;
+    for room in chat.join_rooms(&connection) {
+        chat.send_room(&room, &connection);
+        connection.send_room_history(&room, chat.room_history(&room));
+    }
 
     let recv_connection = connection.clone();
     let recv_chat = chat.clone();
@@ -99,7 +287,7 @@ pub async fn handle_chat_socket(stream: WebSocket, username: Option<String>, sta
                     continue;
                 }
             };
-            recv_chat.handle_req(request, recv_connection.clone());
+            recv_chat.handle_req(request, recv_connection.clone()).await;
         }
     });
 
@@ -108,5 +296,5 @@ pub async fn handle_chat_socket(stream: WebSocket, username: Option<String>, sta
         _ = (&mut recv_task) => send_task.abort(),
     };
 
-    chat.disconnect(&connection.username)
+    chat.disconnect(&connection)
 }
@@ -0,0 +1,146 @@
+use eyre::Result;
+use futures::{StreamExt, TryStreamExt};
+use libsql::{params, Connection};
+
+// granted and revoked by admins, see `POST /api/admin/chat/moderators`
+pub const MODERATOR_ROLE: &str = "moderator";
+// roles from the `role` column of users that can moderate the chat
+const MODERATOR_ROLES: &[&str] = &["admin", MODERATOR_ROLE];
+
+#[derive(Debug, Clone, Default)]
+pub struct Sanction {
+    // unix time
+    pub muted_until: Option<u64>,
+    pub banned: bool,
+}
+
+#[derive(Clone)]
+pub struct Moderation {
+    conn: Connection,
+}
+
+impl Moderation {
+    pub fn new(conn: Connection) -> Self {
+        Self { conn }
+    }
+
+    pub async fn is_moderator(&self, username: &str) -> Result<bool> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT role FROM users WHERE username = ?")
+            .await?;
+
+        let mut rows = stmt.query([username]).await?;
+        let Some(row) = rows.next().await? else {
+            return Ok(false);
+        };
+
+        let role = row.get::<Option<String>>(0)?;
+        Ok(role.is_some_and(|role| MODERATOR_ROLES.contains(&role.as_str())))
+    }
+
+    pub async fn all(&self) -> Result<Vec<(String, Sanction)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT username, muted_until, banned FROM chat_sanctions")
+            .await?;
+
+        let rows = stmt.query(()).await?;
+        let sanctions = rows.into_stream().map(|row| {
+            let row = row?;
+            let sanction = Sanction {
+                muted_until: row.get::<Option<i64>>(1)?.map(|until| until as u64),
+                banned: row.get(2)?,
+            };
+            eyre::Ok((row.get(0)?, sanction))
+        });
+
+        sanctions.try_collect::<Vec<_>>().await
+    }
+
+    pub async fn save(&self, username: &str, sanction: &Sanction) -> Result<()> {
+        if sanction.muted_until.is_none() && !sanction.banned {
+            self.conn
+                .execute("DELETE FROM chat_sanctions WHERE username = ?", [username])
+                .await?;
+            return Ok(());
+        }
+
+        self.conn
+            .execute(
+                "INSERT INTO chat_sanctions (username, muted_until, banned) VALUES (?, ?, ?)
+                ON CONFLICT (username) DO UPDATE SET muted_until = excluded.muted_until, banned = excluded.banned",
+                params![
+                    username,
+                    sanction.muted_until.map(|until| until as i64),
+                    sanction.banned
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn moderation() -> Moderation {
+        let db = libsql::Builder::new_local(":memory:")
+            .build()
+            .await
+            .unwrap();
+        let conn = db.connect().unwrap();
+        for migration in [
+            include_str!("../migrations/V1__initial.sql"),
+            include_str!("../migrations/V2__chat_messages.sql"),
+            include_str!("../migrations/V5__chat_moderation.sql"),
+        ] {
+            conn.execute_batch(migration).await.unwrap();
+        }
+        conn.execute_batch(
+            "INSERT INTO users (username, password_hash, role) VALUES
+            ('alice', '', 'moderator'), ('bob', '', NULL), ('carol', '', 'member')",
+        )
+        .await
+        .unwrap();
+        Moderation::new(conn)
+    }
+
+    #[tokio::test]
+    async fn finds_moderators_by_role() {
+        let moderation = moderation().await;
+        assert!(moderation.is_moderator("alice").await.unwrap());
+        assert!(!moderation.is_moderator("bob").await.unwrap());
+        assert!(!moderation.is_moderator("carol").await.unwrap());
+        assert!(!moderation.is_moderator("guest-1").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn saves_and_lifts_sanctions() {
+        let moderation = moderation().await;
+        let muted = Sanction {
+            muted_until: Some(100),
+            banned: false,
+        };
+        moderation.save("bob", &muted).await.unwrap();
+        moderation
+            .save(
+                "bob",
+                &Sanction {
+                    banned: true,
+                    ..muted
+                },
+            )
+            .await
+            .unwrap();
+
+        let all = moderation.all().await.unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].0, "bob");
+        assert_eq!((all[0].1.muted_until, all[0].1.banned), (Some(100), true));
+
+        moderation.save("bob", &Sanction::default()).await.unwrap();
+        assert!(moderation.all().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+use eyre::Result;
+use futures::{StreamExt, TryStreamExt};
+use libsql::{params, Connection};
+
+#[derive(Debug, Clone)]
+pub struct RoomInfo {
+    pub name: String,
+    pub topic: Option<String>,
+    pub private: bool,
+    pub created_by: Option<String>,
+    // seconds between messages of a user, 0 is off
+    pub slow_mode: u64,
+}
+
+#[derive(Clone)]
+pub struct ChatRooms {
+    conn: Connection,
+}
+
+impl ChatRooms {
+    pub fn new(conn: Connection) -> Self {
+        Self { conn }
+    }
+
+    pub async fn all(&self) -> Result<Vec<RoomInfo>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name, topic, private, created_by, slow_mode FROM chat_rooms")
+            .await?;
+
+        let rows = stmt.query(()).await?;
+        let rooms = rows.into_stream().map(|row| {
+            let row = row?;
+            eyre::Ok(RoomInfo {
+                name: row.get(0)?,
+                topic: row.get(1)?,
+                private: row.get(2)?,
+                created_by: row.get(3)?,
+                slow_mode: row.get(4)?,
+            })
+        });
+
+        rooms.try_collect::<Vec<_>>().await
+    }
+
+    // (room, username) pairs
+    pub async fn members(&self) -> Result<Vec<(String, String)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT room, username FROM chat_room_members")
+            .await?;
+
+        let rows = stmt.query(()).await?;
+        let members = rows.into_stream().map(|row| {
+            let row = row?;
+            eyre::Ok((row.get(0)?, row.get(1)?))
+        });
+
+        members.try_collect::<Vec<_>>().await
+    }
+
+    pub async fn create(&self, room: &RoomInfo) -> Result<()> {
+        self.conn
+            .execute(
+                "INSERT INTO chat_rooms (name, topic, private, created_by) VALUES (?, ?, ?, ?)",
+                params![
+                    room.name.clone(),
+                    room.topic.clone(),
+                    room.private,
+                    room.created_by.clone()
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_topic(&self, room: &str, topic: Option<&str>) -> Result<()> {
+        self.conn
+            .execute(
+                "UPDATE chat_rooms SET topic = ? WHERE name = ?",
+                params![topic, room],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_slow_mode(&self, room: &str, slow_mode: u64) -> Result<()> {
+        self.conn
+            .execute(
+                "UPDATE chat_rooms SET slow_mode = ? WHERE name = ?",
+                params![slow_mode, room],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn add_member(&self, room: &str, username: &str) -> Result<()> {
+        self.conn
+            .execute(
+                "INSERT OR IGNORE INTO chat_room_members (room, username) VALUES (?, ?)",
+                params![room, username],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn remove_member(&self, room: &str, username: &str) -> Result<()> {
+        self.conn
+            .execute(
+                "DELETE FROM chat_room_members WHERE room = ? AND username = ?",
+                params![room, username],
+            )
+            .await?;
+        Ok(())
+    }
+
+    // guests and deleted users can't be invited
+    pub async fn user_exists(&self, username: &str) -> Result<bool> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT 1 FROM users WHERE username = ?")
+            .await?;
+
+        let mut rows = stmt.query([username]).await?;
+        Ok(rows.next().await?.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn rooms() -> ChatRooms {
+        let db = libsql::Builder::new_local(":memory:")
+            .build()
+            .await
+            .unwrap();
+        let conn = db.connect().unwrap();
+        for migration in [
+            include_str!("../migrations/V1__initial.sql"),
+            include_str!("../migrations/V3__chat_rooms.sql"),
+            include_str!("../migrations/V6__chat_spam.sql"),
+        ] {
+            conn.execute_batch(migration).await.unwrap();
+        }
+        conn.execute(
+            "INSERT INTO users (username, password_hash) VALUES ('alice', '')",
+            (),
+        )
+        .await
+        .unwrap();
+        ChatRooms::new(conn)
+    }
+
+    #[tokio::test]
+    async fn creates_rooms_with_members() {
+        let rooms = rooms().await;
+        rooms
+            .create(&RoomInfo {
+                name: "secret".to_string(),
+                topic: None,
+                private: true,
+                created_by: Some("alice".to_string()),
+                slow_mode: 0,
+            })
+            .await
+            .unwrap();
+        rooms.set_topic("secret", Some("shh")).await.unwrap();
+        rooms.set_slow_mode("secret", 30).await.unwrap();
+        rooms.add_member("secret", "alice").await.unwrap();
+        rooms.add_member("secret", "alice").await.unwrap();
+
+        let mut all = rooms.all().await.unwrap();
+        all.sort_by(|a, b| a.name.cmp(&b.name));
+        let all = all
+            .iter()
+            .map(|room| (room.name.as_str(), room.topic.as_deref(), room.slow_mode))
+            .collect::<Vec<_>>();
+        assert_eq!(all, [("general", None, 0), ("secret", Some("shh"), 30)]);
+
+        let members = [("secret".to_string(), "alice".to_string())];
+        assert_eq!(rooms.members().await.unwrap(), members);
+        rooms.remove_member("secret", "alice").await.unwrap();
+        assert!(rooms.members().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_finds_registered_users() {
+        let rooms = rooms().await;
+        assert!(rooms.user_exists("alice").await.unwrap());
+        assert!(!rooms.user_exists("guest-1").await.unwrap());
+    }
+}
@@ -0,0 +1,340 @@
+use std::time::Instant;
+
+use eyre::{bail, Result};
+use futures::{StreamExt, TryStreamExt};
+use libsql::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::config::RateLimitConfig;
+
+const MAX_PATTERN_LENGTH: usize = 100;
+
+// refills continuously, every message takes one token
+#[derive(Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    per_second: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let capacity = config.burst.max(1) as f64;
+        Self {
+            tokens: capacity,
+            capacity,
+            per_second: config.per_minute as f64 / 60.0,
+            updated: Instant::now(),
+        }
+    }
+
+    // returns false if the bucket is empty
+    pub fn take(&mut self) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    pub fn is_empty(&mut self) -> bool {
+        self.refill();
+        self.tokens < 1.0
+    }
+
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let refilled = now.duration_since(self.updated).as_secs_f64() * self.per_second;
+        self.tokens = (self.tokens + refilled).min(self.capacity);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterKind {
+    // whole words or phrases, case insensitive
+    Word,
+    // links to the domain and its subdomains, `*` blocks all links
+    Link,
+}
+
+impl FilterKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FilterKind::Word => "word",
+            FilterKind::Link => "link",
+        }
+    }
+
+    fn parse(kind: &str) -> Result<Self> {
+        match kind {
+            "word" => Ok(FilterKind::Word),
+            "link" => Ok(FilterKind::Link),
+            _ => bail!("unknown filter kind {kind}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterRule {
+    pub id: u64,
+    pub kind: FilterKind,
+    pub pattern: String,
+    pub created_by: Option<String>,
+}
+
+impl FilterRule {
+    // the reason the message is blocked, if it is
+    pub fn check(&self, message: &str) -> Option<String> {
+        match self.kind {
+            FilterKind::Word => {
+                let words = format!(" {} ", normalize_words(message));
+                words
+                    .contains(&format!(" {} ", self.pattern))
+                    .then(|| "your message contains a blocked word".to_string())
+            }
+            FilterKind::Link => links(message)
+                .find(|host| {
+                    self.pattern == "*"
+                        || *host == self.pattern
+                        || host.ends_with(&format!(".{}", self.pattern))
+                })
+                .map(|host| format!("links to {host} aren't allowed")),
+        }
+    }
+}
+
+// trims and lowercases a pattern, so it can be compared against normalized messages
+pub fn normalize_pattern(kind: FilterKind, pattern: &str) -> Result<String> {
+    let pattern = match kind {
+        FilterKind::Word => normalize_words(pattern),
+        FilterKind::Link => {
+            let pattern = pattern.trim().to_lowercase();
+            let pattern = pattern
+                .split_once("://")
+                .map_or(pattern.as_str(), |(_, rest)| rest);
+            pattern
+                .trim_start_matches("www.")
+                .trim_end_matches('/')
+                .to_string()
+        }
+    };
+
+    if pattern.is_empty() || pattern.len() > MAX_PATTERN_LENGTH {
+        bail!("filter patterns must be between 1 and {MAX_PATTERN_LENGTH} characters long");
+    }
+    if kind == FilterKind::Link && pattern.contains(['/', ' ']) {
+        bail!("link filters are domains like `example.com`, or `*` for all links");
+    }
+    Ok(pattern)
+}
+
+// lowercase words separated by single spaces, punctuation is dropped
+fn normalize_words(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// the lowercase hosts of everything that looks like a link
+fn links(message: &str) -> impl Iterator<Item = String> + '_ {
+    message.split_whitespace().filter_map(|word| {
+        let word = word.to_lowercase();
+        let rest = match word.split_once("://") {
+            Some((_, rest)) => rest.to_string(),
+            None if word.starts_with("www.") => word,
+            None => return None,
+        };
+
+        let host = rest
+            .split(['/', '?', '#', ':'])
+            .next()
+            .unwrap_or_default()
+            .trim_start_matches("www.")
+            .to_string();
+        (!host.is_empty()).then_some(host)
+    })
+}
+
+#[derive(Clone)]
+pub struct ChatFilters {
+    conn: Connection,
+}
+
+impl ChatFilters {
+    pub fn new(conn: Connection) -> Self {
+        Self { conn }
+    }
+
+    pub async fn all(&self) -> Result<Vec<FilterRule>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, kind, pattern, created_by FROM chat_filters ORDER BY id")
+            .await?;
+
+        let rows = stmt.query(()).await?;
+        let filters = rows.into_stream().map(|row| {
+            let row = row?;
+            eyre::Ok(FilterRule {
+                id: row.get(0)?,
+                kind: FilterKind::parse(&row.get::<String>(1)?)?,
+                pattern: row.get(2)?,
+                created_by: row.get(3)?,
+            })
+        });
+
+        filters.try_collect::<Vec<_>>().await
+    }
+
+    pub async fn create(
+        &self,
+        kind: FilterKind,
+        pattern: &str,
+        created_by: &str,
+    ) -> Result<FilterRule> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "INSERT INTO chat_filters (kind, pattern, created_by) VALUES (?, ?, ?)
+                ON CONFLICT (kind, pattern) DO UPDATE SET kind = excluded.kind
+                RETURNING id, created_by",
+            )
+            .await?;
+        let row = stmt
+            .query_row(params![kind.as_str(), pattern, created_by])
+            .await?;
+
+        Ok(FilterRule {
+            id: row.get(0)?,
+            kind,
+            pattern: pattern.to_string(),
+            created_by: row.get(1)?,
+        })
+    }
+
+    // returns false if the filter didn't exist
+    pub async fn delete(&self, id: u64) -> Result<bool> {
+        let deleted = self
+            .conn
+            .execute("DELETE FROM chat_filters WHERE id = ?", [id])
+            .await?;
+        Ok(deleted > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(kind: FilterKind, pattern: &str) -> FilterRule {
+        FilterRule {
+            id: 1,
+            kind,
+            pattern: normalize_pattern(kind, pattern).unwrap(),
+            created_by: None,
+        }
+    }
+
+    #[test]
+    fn normalizes_word_patterns() {
+        let normalize = |pattern| normalize_pattern(FilterKind::Word, pattern).ok();
+        assert_eq!(normalize("  Spam "), Some("spam".to_string()));
+        assert_eq!(normalize("Buy-NOW!!"), Some("buy now".to_string()));
+        assert_eq!(
+            normalize("Ünïcode  words"),
+            Some("ünïcode words".to_string())
+        );
+        assert_eq!(normalize(""), None);
+        assert_eq!(normalize(" ?! "), None);
+        assert_eq!(normalize(&"a".repeat(MAX_PATTERN_LENGTH + 1)), None);
+    }
+
+    #[test]
+    fn normalizes_link_patterns() {
+        let normalize = |pattern| normalize_pattern(FilterKind::Link, pattern).ok();
+        assert_eq!(normalize("Example.com"), Some("example.com".to_string()));
+        assert_eq!(
+            normalize("https://www.example.com/"),
+            Some("example.com".to_string())
+        );
+        assert_eq!(normalize("*"), Some("*".to_string()));
+        assert_eq!(normalize("example.com/path"), None);
+        assert_eq!(normalize("example .com"), None);
+        assert_eq!(normalize("https://"), None);
+    }
+
+    #[test]
+    fn matches_whole_words() {
+        let rule = filter(FilterKind::Word, "buy now");
+        assert!(rule.check("BUY, now!").is_some());
+        assert!(rule.check("please buy\nnow").is_some());
+        assert!(rule.check("buy nowhere").is_none());
+        assert!(rule.check("rebuy now").is_none());
+    }
+
+    #[test]
+    fn matches_links_and_subdomains() {
+        let rule = filter(FilterKind::Link, "example.com");
+        assert!(rule.check("see https://example.com/page").is_some());
+        assert!(rule.check("see www.Example.com").is_some());
+        assert!(rule.check("see http://cdn.example.com:8080").is_some());
+        assert!(rule.check("see https://notexample.com").is_none());
+        assert!(rule.check("example.com without a scheme").is_none());
+
+        let all = filter(FilterKind::Link, "*");
+        assert!(all.check("http://anything.org").is_some());
+        assert!(all.check("no links here").is_none());
+    }
+
+    #[test]
+    fn token_bucket_allows_bursts() {
+        let mut bucket = TokenBucket::new(&RateLimitConfig {
+            burst: 2,
+            per_minute: 0,
+        });
+        assert!(bucket.is_full());
+        assert!(bucket.take());
+        assert!(!bucket.is_full());
+        assert!(bucket.take());
+        assert!(bucket.is_empty());
+        assert!(!bucket.take());
+    }
+
+    #[tokio::test]
+    async fn stores_filters_once() {
+        let conn = crate::app::memory_db().await;
+        let filters = ChatFilters::new(conn);
+
+        let created = filters
+            .create(FilterKind::Word, "spam", "alice")
+            .await
+            .unwrap();
+        let again = filters
+            .create(FilterKind::Word, "spam", "bob")
+            .await
+            .unwrap();
+        assert_eq!(created.id, again.id);
+        assert_eq!(again.created_by.as_deref(), Some("alice"));
+        filters
+            .create(FilterKind::Link, "spam", "alice")
+            .await
+            .unwrap();
+
+        let all = filters.all().await.unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[1].kind, FilterKind::Link);
+
+        assert!(filters.delete(created.id).await.unwrap());
+        assert!(!filters.delete(created.id).await.unwrap());
+    }
+}
@@ -1,29 +1,123 @@
-use crate::utils::RingBuffer;
+use crate::{config::ChatConfig, ssg::safe_md_to_html, utils::RingBuffer};
 use dashmap::DashMap;
-use std::{collections::HashSet, sync::atomic::AtomicU64};
+use eyre::{bail, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
 
-use super::{ChatMessage, ChatRequest, ChatResponse};
+use super::{
+    commands,
+    direct::DirectMessages,
+    history::ChatHistory,
+    moderation::{Moderation, Sanction},
+    rooms::{ChatRooms, RoomInfo},
+    spam::{self, ChatFilters, FilterKind, FilterRule, TokenBucket},
+    ChatMessage, ChatRequest, ChatResponse, DirectMessage,
+};
+
+// every connection joins this room, it always exists
+pub const DEFAULT_ROOM: &str = "general";
+
+// the most messages a single history request can return
+const MAX_HISTORY_LIMIT: usize = 200;
+const MAX_TOPIC_LENGTH: usize = 200;
+// different emoji on one message
+const MAX_REACTIONS: usize = 20;
+const MAX_REACTION_LENGTH: usize = 32;
+const MAX_SLOW_MODE: Duration = Duration::from_secs(60 * 60);
+// typing is sent to every other connection in the room, so it's throttled
+const TYPING_INTERVAL: Duration = Duration::from_secs(2);
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 #[derive(Debug)]
 pub struct Room {
-    connected_users: HashSet<String>,
+    // connection id to username, a user can be connected more than once
+    connections: HashMap<u64, String>,
+    // the most recent messages, older ones are loaded from the database
     message_history: RingBuffer<ChatMessage>,
+    topic: Option<String>,
+    private: bool,
+    created_by: Option<String>,
+    // users who joined or were invited, only they can join private rooms
+    members: HashSet<String>,
+    slow_mode: u64,
+    // when each user last sent a message, for slow mode
+    last_sent: HashMap<String, u64>,
 }
 
-impl Default for Room {
-    fn default() -> Self {
+impl Room {
+    fn new(info: RoomInfo, history_window: usize) -> Self {
         Self {
-            connected_users: HashSet::new(),
-            message_history: RingBuffer::new(20),
+            connections: HashMap::new(),
+            message_history: RingBuffer::new(history_window),
+            topic: info.topic,
+            private: info.private,
+            created_by: info.created_by,
+            members: HashSet::new(),
+            slow_mode: info.slow_mode,
+            last_sent: HashMap::new(),
         }
     }
+
+    fn response(&self, name: &str) -> ChatResponse {
+        ChatResponse::Room {
+            room: name.to_string(),
+            topic: self.topic.clone(),
+            private: self.private,
+            slow_mode: self.slow_mode,
+            users: self.users(),
+        }
+    }
+
+    fn users(&self) -> Vec<String> {
+        let mut users = self.connections.values().cloned().collect::<Vec<_>>();
+        users.sort();
+        users.dedup();
+        users
+    }
+
+    fn has_user(&self, username: &str) -> bool {
+        self.connections.values().any(|user| user == username)
+    }
+
+    // returns whether the user wasn't in the room before
+    fn add(&mut self, connection: &Connection) -> bool {
+        let joined = !self.has_user(&connection.username);
+        self.connections
+            .insert(connection.id, connection.username.clone());
+        joined
+    }
+
+    // returns the username if this was their last connection in the room
+    fn remove(&mut self, connection_id: u64) -> Option<String> {
+        let username = self.connections.remove(&connection_id)?;
+        (!self.has_user(&username)).then_some(username)
+    }
+
+    fn can_access(&self, username: &str) -> bool {
+        !self.private || self.members.contains(username)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Connection {
+    pub id: u64,
     pub username: String,
+    // not logged in, guests can't create rooms and aren't remembered as members
+    pub guest: bool,
     pub(super) channel: broadcast::Sender<ChatResponse>,
+    // cancelled when the connection should be closed, e.g. after a ban
+    pub(super) closed: CancellationToken,
+    rate_limit: Arc<Mutex<TokenBucket>>,
+    // when typing was last sent to each room
+    last_typing: Arc<Mutex<HashMap<String, Instant>>>,
 }
 
 impl Connection {
@@ -34,96 +128,997 @@ impl Connection {
         });
     }
 
-    pub fn send_info(&self, default_room: &str, public_rooms: Vec<String>) {
+    pub fn send_info(
+        &self,
+        default_room: &str,
+        public_rooms: Vec<String>,
+        private_rooms: Option<Vec<String>>,
+    ) {
         let _ = self.channel.send(ChatResponse::Info {
             default_room: default_room.to_string(),
             public_rooms,
-            private_rooms: None,
+            private_rooms,
+        });
+    }
+
+    pub fn send_error(&self, message: &str) {
+        let _ = self.channel.send(ChatResponse::Error {
+            message: message.to_string(),
         });
     }
 
+    // false if the connection sent too many messages recently
+    fn take_token(&self) -> bool {
+        self.rate_limit.lock().unwrap().take()
+    }
+
+    // false if typing was sent to the room less than `TYPING_INTERVAL` ago
+    fn take_typing(&self, room: &str) -> bool {
+        let mut last_typing = self.last_typing.lock().unwrap();
+        let now = Instant::now();
+        match last_typing.get(room) {
+            Some(last) if now.duration_since(*last) < TYPING_INTERVAL => false,
+            _ => {
+                last_typing.insert(room.to_string(), now);
+                true
+            }
+        }
+    }
+
     // send a message only to this connection
     pub fn send_msg(&self, username: &str, room: &str, message: &str) {
         let _ = self.channel.send(ChatResponse::Message(ChatMessage {
+            id: 0,
             username: username.to_string(),
             message: message.to_string(),
+            html: safe_md_to_html(message),
             room: room.to_string(),
-            time: time::OffsetDateTime::now_utc().unix_timestamp() as u64,
+            time: now(),
+            action: false,
+            reply_to: None,
+            edited: None,
+            reactions: Default::default(),
         }));
     }
 }
 
-#[derive(Debug)]
 pub struct ChatState {
-    pub connections: DashMap<String, Connection>,
+    pub connections: DashMap<u64, Connection>,
+    // the ids of every open connection by username, users are online while they have any
+    pub users: DashMap<String, HashSet<u64>>,
     pub rooms: DashMap<String, Room>,
     pub guest_id_counter: AtomicU64,
+    pub connection_id_counter: AtomicU64,
+    history: ChatHistory,
+    saved_rooms: ChatRooms,
+    direct_messages: DirectMessages,
+    moderation: Moderation,
+    // mutes and bans by username
+    sanctions: DashMap<String, Sanction>,
+    filters: ChatFilters,
+    filter_rules: DashMap<u64, FilterRule>,
+    config: ChatConfig,
 }
 
 impl ChatState {
-    pub fn new() -> Self {
-        Self {
+    // loads the rooms and their recent messages
+    pub async fn load(conn: libsql::Connection, config: ChatConfig) -> Result<Self> {
+        let history = ChatHistory::new(conn.clone());
+        let saved_rooms = ChatRooms::new(conn.clone());
+        let direct_messages = DirectMessages::new(conn.clone());
+        let moderation = Moderation::new(conn.clone());
+        let sanctions = DashMap::from_iter(moderation.all().await?);
+        let filters = ChatFilters::new(conn);
+        let filter_rules = filters
+            .all()
+            .await?
+            .into_iter()
+            .map(|rule| (rule.id, rule))
+            .collect();
+
+        let rooms = DashMap::new();
+        for info in saved_rooms.all().await? {
+            rooms.insert(info.name.clone(), Room::new(info, config.history_window));
+        }
+        for (room, username) in saved_rooms.members().await? {
+            if let Some(mut room) = rooms.get_mut(&room) {
+                room.members.insert(username);
+            }
+        }
+
+        let state = Self {
             guest_id_counter: AtomicU64::new(0),
-            rooms: DashMap::from_iter(vec![("general".to_string(), Room::default())]),
+            connection_id_counter: AtomicU64::new(0),
+            rooms,
             connections: DashMap::new(),
+            users: DashMap::new(),
+            history,
+            saved_rooms,
+            direct_messages,
+            moderation,
+            sanctions,
+            filters,
+            filter_rules,
+            config,
+        };
+
+        let rooms = state
+            .rooms
+            .iter()
+            .map(|r| r.key().clone())
+            .collect::<Vec<_>>();
+        for room in rooms {
+            state.load_recent(&room).await?;
+        }
+
+        if !state.rooms.contains_key(DEFAULT_ROOM) {
+            bail!("chat room {DEFAULT_ROOM} is missing");
+        }
+
+        Ok(state)
+    }
+
+    async fn load_recent(&self, room_name: &str) -> Result<()> {
+        let messages = self
+            .history
+            .messages(room_name, None, self.config.history_window)
+            .await?;
+
+        if let Some(mut room) = self.rooms.get_mut(room_name) {
+            room.message_history = RingBuffer::new(self.config.history_window);
+            for message in messages {
+                room.message_history.push(message);
+            }
+        }
+        Ok(())
+    }
+
+    // changes a message that new connections receive, if it's still recent
+    fn update_recent(&self, room_name: &str, id: u64, change: impl FnOnce(&mut ChatMessage)) {
+        if let Some(mut room) = self.rooms.get_mut(room_name) {
+            if let Some(message) = room.message_history.find_mut(|message| message.id == id) {
+                change(message);
+            }
         }
     }
 
-    // only if the room is exists
-    pub fn join_room(&self, room: &str, username: &str) {
-        if let Some(mut room) = self.rooms.get_mut(room) {
-            room.connected_users.insert(username.to_string());
+    // deletes old messages according to the retention settings every hour
+    pub async fn run_retention(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.prune_history().await {
+                log::error!("failed to prune chat history: {err}");
+            }
         }
     }
 
+    async fn prune_history(&self) -> Result<()> {
+        for room in self.history.rooms().await? {
+            let deleted = self
+                .history
+                .prune(&room, self.config.retention(&room))
+                .await?;
+
+            if deleted > 0 {
+                log::info!("deleted {deleted} old chat messages in {room}");
+                // deleted messages shouldn't be sent to new connections either
+                self.load_recent(&room).await?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn connect(&self, username: Option<String>) -> Connection {
+        let guest = username.is_none();
         let username = username.unwrap_or_else(|| format!("guest-{}", self.new_guest_id()));
 
         let connection = Connection {
+            id: self.connection_id_counter.fetch_add(1, Ordering::SeqCst),
             username: username.clone(),
+            guest,
             channel: broadcast::channel(100).0,
+            closed: CancellationToken::new(),
+            rate_limit: Arc::new(Mutex::new(TokenBucket::new(&self.config.rate_limit))),
+            last_typing: Arc::default(),
         };
 
-        self.connections
-            .insert(username.clone(), connection.clone());
+        self.connections.insert(connection.id, connection.clone());
+        self.users
+            .entry(username)
+            .or_default()
+            .insert(connection.id);
 
         connection
     }
 
-    pub fn disconnect(&self, username: &str) {
+    // joins the default room and every room the user is a member of,
+    // returns the names of the joined rooms
+    pub fn join_rooms(&self, connection: &Connection) -> Vec<String> {
+        let mut joined = vec![DEFAULT_ROOM.to_string()];
+        if !connection.guest {
+            let mut member_of = self
+                .rooms
+                .iter()
+                .filter(|room| room.key() != DEFAULT_ROOM)
+                .filter(|room| room.members.contains(&connection.username))
+                .map(|room| room.key().clone())
+                .collect::<Vec<_>>();
+            member_of.sort();
+            joined.extend(member_of);
+        }
+
+        for room_name in &joined {
+            if let Some(mut room) = self.rooms.get_mut(room_name) {
+                if room.add(connection) {
+                    self.broadcast(&room, self.presence(room_name, &connection.username, true));
+                }
+            }
+        }
+
+        // other tabs of the user are already online
+        if self.connections_of(&connection.username).len() == 1 {
+            self.send_status(&joined, &connection.username, true);
+        }
+
+        joined
+    }
+
+    // closes the connection, the user stays online while they have others
+    pub fn disconnect(&self, connection: &Connection) {
+        if self.connections.remove(&connection.id).is_none() {
+            return;
+        }
+
+        let offline = self
+            .users
+            .remove_if_mut(&connection.username, |_, ids| {
+                ids.remove(&connection.id);
+                ids.is_empty()
+            })
+            .is_some();
+
+        let mut left = Vec::new();
         for mut room in self.rooms.iter_mut() {
-            room.connected_users.remove(username);
+            if !room.connections.contains_key(&connection.id) {
+                continue;
+            }
+            left.push(room.key().clone());
+            if let Some(username) = room.remove(connection.id) {
+                let response = self.presence(room.key(), &username, false);
+                self.broadcast(&room, response);
+            }
         }
+
+        if offline {
+            self.send_status(&left, &connection.username, false);
+        }
+        connection.closed.cancel();
     }
 
-    pub fn send_message(&self, room_name: &str, username: &str, message: String) {
-        let room = {
+    fn presence(&self, room: &str, username: &str, joined: bool) -> ChatResponse {
+        let (room, username) = (room.to_string(), username.to_string());
+        let time = now();
+        match joined {
+            true => ChatResponse::Join {
+                username,
+                room,
+                time,
+            },
+            false => ChatResponse::Leave {
+                username,
+                room,
+                time,
+            },
+        }
+    }
+
+    // tells everyone in the rooms that the user came online or went offline,
+    // once per connection even if they share multiple rooms
+    fn send_status(&self, rooms: &[String], username: &str, online: bool) {
+        let mut recipients = HashSet::new();
+        for room_name in rooms {
+            if let Some(room) = self.rooms.get(room_name) {
+                recipients.extend(
+                    room.connections
+                        .iter()
+                        .filter(|(_, user)| *user != username)
+                        .map(|(id, _)| *id),
+                );
+            }
+        }
+
+        let response = ChatResponse::Presence {
+            username: username.to_string(),
+            online,
+            time: now(),
+        };
+        for id in recipients {
+            if let Some(connection) = self.connections.get(&id) {
+                let _ = connection.channel.send(response.clone());
+            }
+        }
+    }
+
+    // sends a response to everyone in the room
+    fn broadcast(&self, room: &Room, response: ChatResponse) {
+        for id in room.connections.keys() {
+            if let Some(connection) = self.connections.get(id) {
+                let _ = connection.channel.send(response.clone());
+            }
+        }
+    }
+
+    pub fn public_rooms(&self) -> Vec<String> {
+        let mut rooms = self
+            .rooms
+            .iter()
+            .filter(|room| !room.private)
+            .map(|room| room.key().clone())
+            .collect::<Vec<_>>();
+        rooms.sort();
+        rooms
+    }
+
+    // private rooms the user was invited to, guests can't have any
+    pub fn private_rooms(&self, connection: &Connection) -> Option<Vec<String>> {
+        if connection.guest {
+            return None;
+        }
+
+        let mut rooms = self
+            .rooms
+            .iter()
+            .filter(|room| room.private && room.members.contains(&connection.username))
+            .map(|room| room.key().clone())
+            .collect::<Vec<_>>();
+        rooms.sort();
+        Some(rooms)
+    }
+
+    pub fn send_info(&self, connection: &Connection) {
+        connection.send_info(
+            DEFAULT_ROOM,
+            self.public_rooms(),
+            self.private_rooms(connection),
+        );
+    }
+
+    pub fn send_room(&self, room_name: &str, connection: &Connection) {
+        if let Some(room) = self.rooms.get(room_name) {
+            let _ = connection.channel.send(room.response(room_name));
+        }
+    }
+
+    pub async fn join(&self, room_name: &str, connection: &Connection) -> Result<()> {
+        match self.rooms.get(room_name) {
+            None => bail!("room {room_name} does not exist"),
+            Some(room) if !room.can_access(&connection.username) => {
+                bail!("room {room_name} is invite-only")
+            }
+            Some(_) => {}
+        }
+
+        if !connection.guest {
+            self.saved_rooms
+                .add_member(room_name, &connection.username)
+                .await?;
+        }
+
+        // every open tab of the user joins, not just this one
+        let connections = self.connections_of(&connection.username);
+        {
             let Some(mut room) = self.rooms.get_mut(room_name) else {
-                log::error!("room {} does not exist", room_name);
-                return;
+                bail!("room {room_name} does not exist");
             };
+            if !connection.guest {
+                room.members.insert(connection.username.clone());
+            }
+            let mut joined = false;
+            for connection in &connections {
+                joined |= room.add(connection);
+            }
+            if joined {
+                self.broadcast(&room, self.presence(room_name, &connection.username, true));
+            }
+        }
+
+        let history = self.room_history(room_name);
+        for connection in &connections {
+            self.send_room(room_name, connection);
+            connection.send_room_history(room_name, history.clone());
+        }
+        Ok(())
+    }
+
+    pub async fn leave(&self, room_name: &str, connection: &Connection) -> Result<()> {
+        if !self.rooms.contains_key(room_name) {
+            bail!("room {room_name} does not exist");
+        }
+
+        if !connection.guest {
+            self.saved_rooms
+                .remove_member(room_name, &connection.username)
+                .await?;
+        }
+
+        let connections = self.connections_of(&connection.username);
+        if let Some(mut room) = self.rooms.get_mut(room_name) {
+            room.members.remove(&connection.username);
+            let mut left = false;
+            for connection in &connections {
+                left |= room.remove(connection.id).is_some();
+            }
+            if left {
+                let response = self.presence(room_name, &connection.username, false);
+                self.broadcast(&room, response.clone());
+                // the user isn't in the room anymore, but should still see that they left
+                for connection in &connections {
+                    let _ = connection.channel.send(response.clone());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn create_room(
+        &self,
+        room_name: &str,
+        private: bool,
+        topic: Option<String>,
+        connection: &Connection,
+    ) -> Result<()> {
+        if connection.guest {
+            bail!("guests can't create rooms");
+        }
+        if !is_valid_room_name(room_name) {
+            bail!("invalid room name, use up to 32 lowercase letters, numbers, - and _");
+        }
+        let topic = validate_topic(topic)?;
+        self.check_topic(topic.as_deref(), connection)?;
+        if self.rooms.contains_key(room_name) {
+            bail!("room {room_name} already exists");
+        }
+
+        let info = RoomInfo {
+            name: room_name.to_string(),
+            topic,
+            private,
+            created_by: Some(connection.username.clone()),
+            slow_mode: 0,
+        };
+        self.saved_rooms.create(&info).await?;
+
+        let mut room = Room::new(info, self.config.history_window);
+        room.members.insert(connection.username.clone());
+        self.rooms.insert(room_name.to_string(), room);
+        log::info!("{} created chat room {room_name}", connection.username);
+
+        // everyone should see the new room in their list
+        if !private {
+            for other in self.connections.iter() {
+                self.send_info(&other);
+            }
+        } else {
+            self.send_info(connection);
+        }
+
+        self.join(room_name, connection).await
+    }
+
+    // adds a user to a private room, members of the room can invite others
+    pub async fn invite(
+        &self,
+        room_name: &str,
+        username: &str,
+        connection: &Connection,
+    ) -> Result<()> {
+        match self.rooms.get(room_name) {
+            None => bail!("room {room_name} does not exist"),
+            Some(room) if !room.private => bail!("room {room_name} is public, anyone can join"),
+            Some(room) if !room.members.contains(&connection.username) => {
+                bail!("only members of {room_name} can invite others")
+            }
+            Some(_) => {}
+        }
+
+        if !self.saved_rooms.user_exists(username).await? {
+            bail!("user {username} does not exist");
+        }
+        self.saved_rooms.add_member(room_name, username).await?;
+
+        if let Some(mut room) = self.rooms.get_mut(room_name) {
+            room.members.insert(username.to_string());
+        }
+
+        for invited in self.connections_of(username) {
+            self.send_info(&invited);
+            invited.send_msg(
+                "system",
+                room_name,
+                &format!("{} invited you to {room_name}", connection.username),
+            );
+        }
+        connection.send_msg(
+            "system",
+            room_name,
+            &format!("invited {username} to {room_name}"),
+        );
+        Ok(())
+    }
+
+    // only the creator of a room and moderators can change its topic
+    pub async fn set_topic(
+        &self,
+        room_name: &str,
+        topic: Option<String>,
+        connection: &Connection,
+    ) -> Result<()> {
+        let topic = validate_topic(topic)?;
+        self.check_topic(topic.as_deref(), connection)?;
+        let is_creator = match self.rooms.get(room_name) {
+            None => bail!("room {room_name} does not exist"),
+            Some(room) => room.created_by.as_ref() == Some(&connection.username),
+        };
+        if !is_creator && !self.is_moderator(connection).await? {
+            bail!("only the creator of {room_name} can change its topic");
+        }
+
+        self.saved_rooms
+            .set_topic(room_name, topic.as_deref())
+            .await?;
+
+        if let Some(mut room) = self.rooms.get_mut(room_name) {
+            room.topic = topic;
+            self.broadcast(&room, room.response(room_name));
+        }
+        Ok(())
+    }
+
+    // every open connection of the user
+    fn connections_of(&self, username: &str) -> Vec<Connection> {
+        let Some(ids) = self.users.get(username) else {
+            return Vec::new();
+        };
+        ids.iter()
+            .filter_map(|id| {
+                self.connections
+                    .get(id)
+                    .map(|connection| connection.clone())
+            })
+            .collect()
+    }
+
+    pub async fn send_direct_message(
+        &self,
+        username: &str,
+        message: String,
+        connection: &Connection,
+    ) -> Result<()> {
+        if connection.guest {
+            bail!("guests can't send direct messages");
+        }
+        if username == connection.username {
+            bail!("you can't send direct messages to yourself");
+        }
+        self.check_can_send(connection)?;
+        self.check_message(&message)?;
+        // guests aren't users, so they can't receive them either
+        if !self.saved_rooms.user_exists(username).await? {
+            bail!("user {username} does not exist");
+        }
+
+        let mut direct_message = DirectMessage {
+            id: 0,
+            from: connection.username.clone(),
+            to: username.to_string(),
+            message,
+            time: now(),
+        };
+        direct_message.id = self.direct_messages.insert(&direct_message).await?;
+
+        // the sender's other connections should see it too
+        let recipients = self
+            .connections_of(username)
+            .into_iter()
+            .chain(self.connections_of(&connection.username));
+        for recipient in recipients {
+            let _ = recipient
+                .channel
+                .send(ChatResponse::DirectMessage(direct_message.clone()));
+        }
+        Ok(())
+    }
+
+    pub async fn send_direct_history(
+        &self,
+        username: &str,
+        before: Option<u64>,
+        limit: Option<usize>,
+        connection: &Connection,
+    ) -> Result<()> {
+        if connection.guest {
+            bail!("guests can't use direct messages");
+        }
+
+        let limit = limit
+            .unwrap_or(self.config.history_window)
+            .clamp(1, MAX_HISTORY_LIMIT);
+        let history = self
+            .direct_messages
+            .conversation(&connection.username, username, before, limit)
+            .await?;
+
+        let _ = connection.channel.send(ChatResponse::DirectHistory {
+            username: username.to_string(),
+            history,
+        });
+        Ok(())
+    }
+
+    pub async fn mark_read(&self, username: &str, connection: &Connection) -> Result<()> {
+        if connection.guest {
+            bail!("guests can't use direct messages");
+        }
+
+        self.direct_messages
+            .mark_read(&connection.username, username)
+            .await?;
+
+        // keeps the counts in sync across all of the user's connections
+        for connection in self.connections_of(&connection.username) {
+            self.send_unread(&connection).await;
+        }
+        Ok(())
+    }
+
+    pub async fn send_unread(&self, connection: &Connection) {
+        if connection.guest {
+            return;
+        }
+
+        match self.direct_messages.unread(&connection.username).await {
+            Ok(counts) => {
+                let _ = connection.channel.send(ChatResponse::Unread { counts });
+            }
+            Err(err) => log::error!("failed to load unread direct messages: {err}"),
+        }
+    }
+
+    pub async fn is_moderator(&self, connection: &Connection) -> Result<bool> {
+        if connection.guest {
+            return Ok(false);
+        }
+        self.moderation.is_moderator(&connection.username).await
+    }
+
+    pub fn is_banned(&self, username: &str) -> bool {
+        self.sanctions
+            .get(username)
+            .is_some_and(|sanction| sanction.banned)
+    }
+
+    // banned or muted users can't send messages, and neither can guests if they are read-only
+    fn check_can_send(&self, connection: &Connection) -> Result<()> {
+        if connection.guest && self.config.guests_read_only {
+            bail!("guests can only read the chat, log in to send messages");
+        }
+
+        let Some(sanction) = self.sanctions.get(&connection.username) else {
+            return Ok(());
+        };
 
-            room.message_history.push(ChatMessage {
-                username: username.to_string(),
-                message: message.clone(),
-                room: room_name.to_string(),
-                time: time::OffsetDateTime::now_utc().unix_timestamp() as u64,
-            });
+        if sanction.banned {
+            bail!("you are banned from the chat");
+        }
+        if let Some(until) = sanction.muted_until.filter(|until| *until > now()) {
+            bail!(
+                "you are muted for another {}",
+                commands::format_duration(until - now())
+            );
+        }
+        Ok(())
+    }
 
-            // don't keep the mut locked for longer than necessary
-            room.downgrade()
+    fn check_message(&self, message: &str) -> Result<()> {
+        let max_length = self.config.max_message_length;
+        if message.chars().count() > max_length {
+            bail!("messages can be at most {max_length} characters long");
+        }
+
+        for rule in self.filter_rules.iter() {
+            if let Some(reason) = rule.check(message) {
+                bail!(reason);
+            }
+        }
+        Ok(())
+    }
+
+    // topics are shown to everyone in the room, so they are checked like messages
+    fn check_topic(&self, topic: Option<&str>, connection: &Connection) -> Result<()> {
+        let Some(topic) = topic else {
+            return Ok(());
+        };
+        self.check_can_send(connection)?;
+        self.check_message(topic)
+    }
+
+    // in slow mode, users have to wait between messages unless they are moderators
+    async fn check_slow_mode(&self, room_name: &str, connection: &Connection) -> Result<()> {
+        let slow_mode = match self.rooms.get(room_name) {
+            Some(room) => room.slow_mode,
+            None => bail!("room {room_name} does not exist"),
         };
+        if slow_mode == 0 || self.is_moderator(connection).await? {
+            return Ok(());
+        }
+
+        let Some(mut room) = self.rooms.get_mut(room_name) else {
+            bail!("room {room_name} does not exist");
+        };
+        let now = now();
+        if let Some(last_sent) = room.last_sent.get(&connection.username) {
+            let wait = (last_sent + slow_mode).saturating_sub(now);
+            if wait > 0 {
+                bail!(
+                    "{room_name} is in slow mode, wait another {}",
+                    commands::format_duration(wait)
+                );
+            }
+        }
+        room.last_sent.insert(connection.username.clone(), now);
+        Ok(())
+    }
+
+    // moderators can't moderate themselves or each other
+    async fn check_can_moderate(&self, username: &str, connection: &Connection) -> Result<()> {
+        if username == connection.username {
+            bail!("you can't moderate yourself");
+        }
+        if self.moderation.is_moderator(username).await? {
+            bail!("{username} is a moderator");
+        }
+        Ok(())
+    }
+
+    async fn update_sanction(
+        &self,
+        username: &str,
+        update: impl FnOnce(&mut Sanction),
+    ) -> Result<()> {
+        let mut sanction = self
+            .sanctions
+            .get(username)
+            .map(|sanction| sanction.clone())
+            .unwrap_or_default();
+        update(&mut sanction);
+
+        // guests are only sanctioned until they reconnect with a new name
+        if self.saved_rooms.user_exists(username).await? {
+            self.moderation.save(username, &sanction).await?;
+        } else if !self.users.contains_key(username) {
+            bail!("user {username} does not exist");
+        }
+
+        if sanction.muted_until.is_none() && !sanction.banned {
+            self.sanctions.remove(username);
+        } else {
+            self.sanctions.insert(username.to_string(), sanction);
+        }
+        Ok(())
+    }
+
+    // removes the user from the room, members can join again unless the room is private
+    pub async fn kick(
+        &self,
+        room_name: &str,
+        username: &str,
+        connection: &Connection,
+    ) -> Result<()> {
+        self.check_can_moderate(username, connection).await?;
+        if !self.is_in_room(room_name, username) {
+            bail!("{username} is not in {room_name}");
+        }
+
+        let kicked = self.connections_of(username);
+        if let Some(first) = kicked.first() {
+            self.leave(room_name, first).await?;
+        }
+        for kicked in kicked {
+            kicked.send_msg(
+                "system",
+                DEFAULT_ROOM,
+                &format!("{} kicked you from {room_name}", connection.username),
+            );
+        }
+        log::info!("{} kicked {username} from {room_name}", connection.username);
+        Ok(())
+    }
+
+    // `None` unmutes the user
+    pub async fn mute(
+        &self,
+        username: &str,
+        duration: Option<Duration>,
+        connection: &Connection,
+    ) -> Result<()> {
+        self.check_can_moderate(username, connection).await?;
+        let until = duration.map(|duration| now() + duration.as_secs());
+        self.update_sanction(username, |sanction| sanction.muted_until = until)
+            .await?;
+
+        let notice = match duration {
+            Some(duration) => format!(
+                "{} muted you for {}",
+                connection.username,
+                commands::format_duration(duration.as_secs())
+            ),
+            None => format!("{} unmuted you", connection.username),
+        };
+        for muted in self.connections_of(username) {
+            muted.send_msg("system", DEFAULT_ROOM, &notice);
+        }
+        log::info!("{} muted {username} until {until:?}", connection.username);
+        Ok(())
+    }
+
+    // banned users are removed from all rooms and can't use the chat anymore
+    pub async fn ban(&self, username: &str, banned: bool, connection: &Connection) -> Result<()> {
+        self.check_can_moderate(username, connection).await?;
+        self.update_sanction(username, |sanction| sanction.banned = banned)
+            .await?;
+
+        if banned {
+            for banned in self.connections_of(username) {
+                banned.send_error(&format!("{} banned you from the chat", connection.username));
+                self.disconnect(&banned);
+            }
+        }
+        log::info!("{} set ban of {username} to {banned}", connection.username);
+        Ok(())
+    }
+
+    // `None` turns slow mode off
+    pub async fn set_slow_mode(
+        &self,
+        room_name: &str,
+        duration: Option<Duration>,
+        connection: &Connection,
+    ) -> Result<()> {
+        if !self.rooms.contains_key(room_name) {
+            bail!("room {room_name} does not exist");
+        }
+        if duration.is_some_and(|duration| duration > MAX_SLOW_MODE) {
+            bail!(
+                "slow mode can be at most {}",
+                commands::format_duration(MAX_SLOW_MODE.as_secs())
+            );
+        }
+
+        let slow_mode = duration.map_or(0, |duration| duration.as_secs());
+        self.saved_rooms.set_slow_mode(room_name, slow_mode).await?;
 
-        for user in &room.connected_users {
-            if let Some(connection) = self.connections.get(user) {
-                let _ = connection.channel.send(ChatResponse::Message(ChatMessage {
-                    username: username.to_string(),
-                    message: message.clone(),
+        if let Some(mut room) = self.rooms.get_mut(room_name) {
+            room.slow_mode = slow_mode;
+            room.last_sent.clear();
+            self.broadcast(&room, room.response(room_name));
+        }
+        log::info!(
+            "{} set slow mode of {room_name} to {slow_mode}s",
+            connection.username
+        );
+        Ok(())
+    }
+
+    pub fn filters(&self) -> Vec<FilterRule> {
+        let mut rules = self
+            .filter_rules
+            .iter()
+            .map(|rule| rule.clone())
+            .collect::<Vec<_>>();
+        rules.sort_by_key(|rule| rule.id);
+        rules
+    }
+
+    pub async fn add_filter(
+        &self,
+        kind: FilterKind,
+        pattern: &str,
+        created_by: &str,
+    ) -> Result<FilterRule> {
+        let pattern = spam::normalize_pattern(kind, pattern)?;
+        let rule = self.filters.create(kind, &pattern, created_by).await?;
+        self.filter_rules.insert(rule.id, rule.clone());
+        log::info!("{created_by} added chat filter {pattern}");
+        Ok(rule)
+    }
+
+    // returns false if the filter didn't exist
+    pub async fn remove_filter(&self, id: u64) -> Result<bool> {
+        let deleted = self.filters.delete(id).await?;
+        self.filter_rules.remove(&id);
+        Ok(deleted)
+    }
+
+    pub async fn delete_message(&self, room_name: &str, id: u64) -> Result<()> {
+        if !self.history.delete(room_name, id).await? {
+            bail!("message {id} does not exist in {room_name}");
+        }
+
+        self.load_recent(room_name).await?;
+        if let Some(room) = self.rooms.get(room_name) {
+            self.broadcast(
+                &room,
+                ChatResponse::Delete {
                     room: room_name.to_string(),
-                    time: time::OffsetDateTime::now_utc().unix_timestamp() as u64,
-                }));
+                    id,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    // users in a room the user can access
+    pub fn room_users(&self, room_name: &str, username: &str) -> Option<Vec<String>> {
+        let room = self.rooms.get(room_name)?;
+        if !room.can_access(username) {
+            return None;
+        }
+
+        Some(room.users())
+    }
+
+    pub fn room_topic(&self, room_name: &str) -> Option<Option<String>> {
+        self.rooms.get(room_name).map(|room| room.topic.clone())
+    }
+
+    fn is_in_room(&self, room_name: &str, username: &str) -> bool {
+        self.rooms
+            .get(room_name)
+            .is_some_and(|room| room.has_user(username))
+    }
+
+    pub async fn send_message(
+        &self,
+        room_name: &str,
+        username: &str,
+        message: String,
+        action: bool,
+        reply_to: Option<u64>,
+    ) -> Result<()> {
+        if !self.rooms.contains_key(room_name) {
+            bail!("room {room_name} does not exist");
+        }
+        if let Some(id) = reply_to {
+            if self.history.message(room_name, id).await?.is_none() {
+                bail!("message {id} does not exist in {room_name}");
             }
         }
+
+        let mut chat_message = ChatMessage {
+            id: 0,
+            username: username.to_string(),
+            html: safe_md_to_html(&message),
+            message,
+            room: room_name.to_string(),
+            time: now(),
+            action,
+            reply_to,
+            edited: None,
+            reactions: Default::default(),
+        };
+        chat_message.id = self.history.insert(&chat_message).await?;
+
+        let Some(mut room) = self.rooms.get_mut(room_name) else {
+            return Ok(());
+        };
+        room.message_history.push(chat_message.clone());
+
+        // don't keep the mut locked for longer than necessary
+        let room = room.downgrade();
+        self.broadcast(&room, ChatResponse::Message(chat_message));
+        Ok(())
     }
 
     pub fn room_history(&self, room_name: &str) -> Vec<ChatMessage> {
@@ -134,46 +1129,537 @@ impl ChatState {
         }
     }
 
-    pub fn handle_req(&self, req: ChatRequest, connection: Connection) {
-        match req {
-            ChatRequest::Message { room, message } => {
-                if message.starts_with('/') {
-                    self.handle_command(&room, &message, connection);
+    // older messages than the ones in memory are loaded from the database
+    pub async fn load_history(
+        &self,
+        room_name: &str,
+        before: Option<u64>,
+        limit: Option<usize>,
+    ) -> Result<Option<Vec<ChatMessage>>> {
+        let window = self.config.history_window;
+        let limit = limit.unwrap_or(window).clamp(1, MAX_HISTORY_LIMIT);
+
+        let recent = match self.rooms.get(room_name) {
+            Some(room) => room.message_history.to_vec(),
+            None => return Ok(None),
+        };
+
+        if before.is_none() && limit <= window {
+            let skip = recent.len().saturating_sub(limit);
+            return Ok(Some(recent[skip..].to_vec()));
+        }
+
+        Ok(Some(self.history.messages(room_name, before, limit).await?))
+    }
+
+    pub async fn handle_req(&self, req: ChatRequest, connection: Connection) {
+        if self.is_banned(&connection.username) {
+            connection.send_error("you are banned from the chat");
+            return;
+        }
+
+        let limited = matches!(
+            req,
+            ChatRequest::Message { .. }
+                | ChatRequest::DirectMessage { .. }
+                | ChatRequest::Edit { .. }
+                | ChatRequest::React { .. }
+                | ChatRequest::Join { .. }
+                | ChatRequest::Leave { .. }
+                | ChatRequest::CreateRoom { .. }
+                | ChatRequest::Invite { .. }
+                | ChatRequest::Topic { .. }
+                | ChatRequest::History { .. }
+        );
+        if limited && !connection.take_token() {
+            connection.send_error("you are sending messages too quickly, slow down");
+            return;
+        }
+
+        let result = match req {
+            ChatRequest::Message {
+                room,
+                message,
+                reply_to,
+            } => {
+                self.handle_message(&room, message, reply_to, &connection)
+                    .await
+            }
+            ChatRequest::Edit { room, id, message } => {
+                self.edit_message(&room, id, message, &connection).await
+            }
+            ChatRequest::Delete { room, id } => {
+                self.delete_message_by(&room, id, &connection).await
+            }
+            ChatRequest::React { room, id, emoji } => {
+                self.react(&room, id, &emoji, true, &connection).await
+            }
+            ChatRequest::Unreact { room, id, emoji } => {
+                self.react(&room, id, &emoji, false, &connection).await
+            }
+            ChatRequest::History {
+                room: room_name,
+                before,
+                limit,
+            } => {
+                let accessible = self
+                    .rooms
+                    .get(&room_name)
+                    .is_some_and(|room| room.can_access(&connection.username));
+                if !accessible {
+                    connection.send_error(&format!("room {room_name} does not exist"));
                     return;
                 }
-                self.send_message(&room, &connection.username, message);
-            }
-            ChatRequest::History { room: room_name } => {
-                if let Some(room) = self.rooms.get(&room_name) {
-                    let history = room.message_history.to_vec();
-                    connection.send_room_history(&room_name, history);
+
+                match self.load_history(&room_name, before, limit).await {
+                    Ok(Some(history)) => connection.send_room_history(&room_name, history),
+                    Ok(None) => {}
+                    Err(err) => {
+                        log::error!("failed to load chat history: {err}");
+                        connection.send_error("failed to load history");
+                    }
                 }
+                Ok(())
             }
-            _ => {
-                let _ = connection.channel.send(ChatResponse::Error {
-                    message: "unimplemented".to_string(),
-                });
+            ChatRequest::Join { room } => self.join(&room, &connection).await,
+            ChatRequest::Leave { room } => self.leave(&room, &connection).await,
+            ChatRequest::CreateRoom {
+                room,
+                private,
+                topic,
+            } => self.create_room(&room, private, topic, &connection).await,
+            ChatRequest::Invite { room, username } => {
+                self.invite(&room, &username, &connection).await
+            }
+            ChatRequest::Topic { room, topic } => self.set_topic(&room, topic, &connection).await,
+            ChatRequest::DirectMessage { username, message } => {
+                self.send_direct_message(&username, message, &connection)
+                    .await
+            }
+            ChatRequest::DirectHistory {
+                username,
+                before,
+                limit,
+            } => {
+                self.send_direct_history(&username, before, limit, &connection)
+                    .await
+            }
+            ChatRequest::MarkRead { username } => self.mark_read(&username, &connection).await,
+            ChatRequest::Typing { room } => self.send_typing(&room, &connection),
+            ChatRequest::Info => {
+                self.send_info(&connection);
+                Ok(())
             }
         };
+
+        if let Err(err) = result {
+            connection.send_error(&err.to_string());
+        }
     }
 
-    pub fn handle_command(&self, room: &str, message: &str, connection: Connection) {
-        let mut parts = message.split_whitespace();
-        let command = parts.next().unwrap_or("");
-        let _args = parts.collect::<Vec<_>>();
+    async fn handle_message(
+        &self,
+        room: &str,
+        message: String,
+        reply_to: Option<u64>,
+        connection: &Connection,
+    ) -> Result<()> {
+        if !self.is_in_room(room, &connection.username) {
+            bail!("join {room} to send messages");
+        }
+        // commands too, so filtered words can't be sent with `/me` or `/msg`
+        self.check_message(&message)?;
 
-        match command {
-            "/help" => {
-                connection.send_msg("system", room, "no commands available");
+        if message.starts_with('/') {
+            return commands::run(self, room, &message, connection).await;
+        }
+
+        self.check_can_send(connection)?;
+        self.check_slow_mode(room, connection).await?;
+        self.send_message(room, &connection.username, message, false, reply_to)
+            .await
+    }
+
+    // only the author can edit a message, the new text is checked like a new message
+    async fn edit_message(
+        &self,
+        room_name: &str,
+        id: u64,
+        message: String,
+        connection: &Connection,
+    ) -> Result<()> {
+        if !self.is_in_room(room_name, &connection.username) {
+            bail!("join {room_name} to send messages");
+        }
+        let Some(original) = self.history.message(room_name, id).await? else {
+            bail!("message {id} does not exist in {room_name}");
+        };
+        // guest names are reused after a restart
+        if connection.guest || original.username != connection.username {
+            bail!("you can only edit your own messages");
+        }
+        if message.trim().is_empty() {
+            bail!("messages can't be empty, delete it instead");
+        }
+        self.check_can_send(connection)?;
+        self.check_message(&message)?;
+
+        let edited = now();
+        self.history.edit(room_name, id, &message, edited).await?;
+        let html = safe_md_to_html(&message);
+        self.update_recent(room_name, id, |recent| {
+            recent.message = message.clone();
+            recent.html = html.clone();
+            recent.edited = Some(edited);
+        });
+        if let Some(room) = self.rooms.get(room_name) {
+            self.broadcast(
+                &room,
+                ChatResponse::Edit {
+                    room: room_name.to_string(),
+                    id,
+                    message,
+                    html,
+                    edited,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    // authors can delete their own messages, moderators any message
+    async fn delete_message_by(
+        &self,
+        room_name: &str,
+        id: u64,
+        connection: &Connection,
+    ) -> Result<()> {
+        if !self.is_in_room(room_name, &connection.username) {
+            bail!("join {room_name} to delete messages");
+        }
+        let Some(message) = self.history.message(room_name, id).await? else {
+            bail!("message {id} does not exist in {room_name}");
+        };
+
+        let own = !connection.guest && message.username == connection.username;
+        if !own && !self.is_moderator(connection).await? {
+            bail!("you can only delete your own messages");
+        }
+        self.delete_message(room_name, id).await
+    }
+
+    async fn react(
+        &self,
+        room_name: &str,
+        id: u64,
+        emoji: &str,
+        add: bool,
+        connection: &Connection,
+    ) -> Result<()> {
+        if !self.is_in_room(room_name, &connection.username) {
+            bail!("join {room_name} to react to messages");
+        }
+        let Some(message) = self.history.message(room_name, id).await? else {
+            bail!("message {id} does not exist in {room_name}");
+        };
+
+        let username = &connection.username;
+        let changed = if add {
+            self.check_can_send(connection)?;
+            validate_reaction(emoji)?;
+            if message.reactions.len() >= MAX_REACTIONS && !message.reactions.contains_key(emoji) {
+                bail!("messages can have at most {MAX_REACTIONS} different reactions");
             }
-            _ => {
-                connection.send_msg("system", room, &format!("unknown command: {}", command));
+            self.history
+                .add_reaction(id, username, emoji, now())
+                .await?
+        } else {
+            self.history.remove_reaction(id, username, emoji).await?
+        };
+        if !changed {
+            return Ok(());
+        }
+
+        self.update_recent(room_name, id, |recent| {
+            let users = recent.reactions.entry(emoji.to_string()).or_default();
+            users.retain(|user| user != username);
+            if add {
+                users.push(username.clone());
+            }
+            if users.is_empty() {
+                recent.reactions.remove(emoji);
+            }
+        });
+        let Some(room) = self.rooms.get(room_name) else {
+            return Ok(());
+        };
+        let (room_name, username, emoji) =
+            (room_name.to_string(), username.clone(), emoji.to_string());
+        let response = match add {
+            true => ChatResponse::React {
+                room: room_name,
+                id,
+                username,
+                emoji,
+            },
+            false => ChatResponse::Unreact {
+                room: room_name,
+                id,
+                username,
+                emoji,
+            },
+        };
+        self.broadcast(&room, response);
+        Ok(())
+    }
+
+    // sent to everyone else in the room, clients show it until the next message or a timeout
+    fn send_typing(&self, room_name: &str, connection: &Connection) -> Result<()> {
+        let Some(room) = self.rooms.get(room_name) else {
+            bail!("room {room_name} does not exist");
+        };
+        if !room.has_user(&connection.username) {
+            bail!("join {room_name} to send messages");
+        }
+        self.check_can_send(connection)?;
+        if !connection.take_typing(room_name) {
+            return Ok(());
+        }
+
+        let response = ChatResponse::Typing {
+            username: connection.username.clone(),
+            room: room_name.to_string(),
+        };
+        for (id, username) in &room.connections {
+            if *username == connection.username {
+                continue;
+            }
+            if let Some(other) = self.connections.get(id) {
+                let _ = other.channel.send(response.clone());
             }
         }
+        Ok(())
+    }
+
+    // `/me waves`
+    pub async fn send_action(
+        &self,
+        room: &str,
+        action: &str,
+        connection: &Connection,
+    ) -> Result<()> {
+        self.check_can_send(connection)?;
+        self.check_slow_mode(room, connection).await?;
+        self.send_message(room, &connection.username, action.to_string(), true, None)
+            .await
     }
 
     pub fn new_guest_id(&self) -> u64 {
-        self.guest_id_counter
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+        self.guest_id_counter.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+fn now() -> u64 {
+    time::OffsetDateTime::now_utc().unix_timestamp() as u64
+}
+
+// lowercase so rooms can't be impersonated with different casing
+fn is_valid_room_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+// a single emoji, possibly with modifiers, but no text
+fn validate_reaction(emoji: &str) -> Result<()> {
+    let is_text = |c: char| {
+        c.is_alphanumeric() || c.is_whitespace() || c.is_control() || c.is_ascii_punctuation()
+    };
+    if emoji.is_empty() || emoji.len() > MAX_REACTION_LENGTH || emoji.chars().any(is_text) {
+        bail!("reactions have to be an emoji");
+    }
+    Ok(())
+}
+
+// an empty topic removes it
+fn validate_topic(topic: Option<String>) -> Result<Option<String>> {
+    let topic = topic
+        .map(|topic| topic.trim().to_string())
+        .filter(|topic| !topic.is_empty());
+
+    if topic
+        .as_ref()
+        .is_some_and(|topic| topic.chars().count() > MAX_TOPIC_LENGTH)
+    {
+        bail!("topics can be at most {MAX_TOPIC_LENGTH} characters long");
+    }
+    Ok(topic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn state() -> ChatState {
+        let conn = crate::app::memory_db().await;
+        conn.execute_batch(
+            "INSERT INTO users (username, password_hash) VALUES ('alice', ''), ('bob', '')",
+        )
+        .await
+        .unwrap();
+        ChatState::load(conn, ChatConfig::default()).await.unwrap()
+    }
+
+    // the types and usernames of everything sent to a connection so far
+    fn received(rx: &mut broadcast::Receiver<ChatResponse>) -> Vec<(String, String)> {
+        let mut received = Vec::new();
+        while let Ok(response) = rx.try_recv() {
+            let response = serde_json::to_value(response).unwrap();
+            let username = response["username"].as_str().unwrap_or_default();
+            received.push((
+                response["type"].as_str().unwrap().to_string(),
+                username.to_string(),
+            ));
+        }
+        received
+    }
+
+    fn of_type<'a>(received: &'a [(String, String)], kind: &str) -> Vec<&'a str> {
+        received
+            .iter()
+            .filter(|(t, _)| t == kind)
+            .map(|(_, username)| username.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn validates_room_names() {
+        for name in ["general", "room-2", "a_b"] {
+            assert!(is_valid_room_name(name), "{name}");
+        }
+        for name in ["", "General", "#general", "a b", "ünïcode", &"a".repeat(33)] {
+            assert!(!is_valid_room_name(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn validates_reactions() {
+        for emoji in ["👍", "🎉", "👩‍💻", "❤️"] {
+            assert!(validate_reaction(emoji).is_ok(), "{emoji}");
+        }
+        for emoji in ["", "a", ":)", "👍 ", "👍\n", &"👍".repeat(9)] {
+            assert!(validate_reaction(emoji).is_err(), "{emoji:?}");
+        }
+    }
+
+    #[test]
+    fn validates_topics() {
+        assert_eq!(validate_topic(None).unwrap(), None);
+        assert_eq!(validate_topic(Some("  ".to_string())).unwrap(), None);
+        assert_eq!(
+            validate_topic(Some(" hi ".to_string())).unwrap().as_deref(),
+            Some("hi")
+        );
+        assert!(validate_topic(Some("ä".repeat(MAX_TOPIC_LENGTH))).is_ok());
+        assert!(validate_topic(Some("a".repeat(MAX_TOPIC_LENGTH + 1))).is_err());
+    }
+
+    #[tokio::test]
+    async fn users_stay_online_while_any_connection_is_open() {
+        let state = state().await;
+        let bob = state.connect(Some("bob".to_string()));
+        let mut bob_rx = bob.channel.subscribe();
+        state.join_rooms(&bob);
+
+        let first = state.connect(Some("alice".to_string()));
+        state.join_rooms(&first);
+        let second = state.connect(Some("alice".to_string()));
+        state.join_rooms(&second);
+
+        // only the first tab is announced
+        let joined = received(&mut bob_rx);
+        assert_eq!(of_type(&joined, "presence"), ["alice"]);
+        assert_eq!(
+            state.room_users(DEFAULT_ROOM, "bob").unwrap(),
+            ["alice", "bob"]
+        );
+
+        state.disconnect(&first);
+        assert!(first.closed.is_cancelled());
+        assert!(of_type(&received(&mut bob_rx), "presence").is_empty());
+        assert_eq!(state.connections_of("alice").len(), 1);
+
+        state.disconnect(&second);
+        assert_eq!(of_type(&received(&mut bob_rx), "presence"), ["alice"]);
+        assert!(!state.users.contains_key("alice"));
+        assert_eq!(state.room_users(DEFAULT_ROOM, "bob").unwrap(), ["bob"]);
+    }
+
+    #[tokio::test]
+    async fn typing_is_sent_to_everyone_else() {
+        let state = state().await;
+        let alice = state.connect(Some("alice".to_string()));
+        let other_tab = state.connect(Some("alice".to_string()));
+        let bob = state.connect(None);
+        for connection in [&alice, &other_tab, &bob] {
+            state.join_rooms(connection);
+        }
+        let mut other_tab_rx = other_tab.channel.subscribe();
+        let mut bob_rx = bob.channel.subscribe();
+
+        state.send_typing(DEFAULT_ROOM, &alice).unwrap();
+        assert_eq!(of_type(&received(&mut bob_rx), "typing"), ["alice"]);
+        assert!(of_type(&received(&mut other_tab_rx), "typing").is_empty());
+        assert!(state.send_typing("missing", &alice).is_err());
+
+        // throttled per connection and room
+        state.send_typing(DEFAULT_ROOM, &alice).unwrap();
+        assert!(of_type(&received(&mut bob_rx), "typing").is_empty());
+        state.send_typing(DEFAULT_ROOM, &other_tab).unwrap();
+        assert_eq!(of_type(&received(&mut bob_rx), "typing"), ["alice"]);
+    }
+
+    #[tokio::test]
+    async fn updates_recent_messages_in_place() {
+        let state = state().await;
+        let alice = state.connect(Some("alice".to_string()));
+        let bob = state.connect(Some("bob".to_string()));
+        state.join_rooms(&alice);
+        state.join_rooms(&bob);
+
+        state
+            .send_message(DEFAULT_ROOM, "alice", "hi".to_string(), false, None)
+            .await
+            .unwrap();
+        let id = state.room_history(DEFAULT_ROOM)[0].id;
+
+        state
+            .edit_message(DEFAULT_ROOM, id, "hello".to_string(), &alice)
+            .await
+            .unwrap();
+        state
+            .react(DEFAULT_ROOM, id, "👍", true, &alice)
+            .await
+            .unwrap();
+        state
+            .react(DEFAULT_ROOM, id, "👍", true, &bob)
+            .await
+            .unwrap();
+        state
+            .react(DEFAULT_ROOM, id, "👍", false, &alice)
+            .await
+            .unwrap();
+
+        let recent = &state.room_history(DEFAULT_ROOM)[0];
+        assert_eq!(recent.message, "hello");
+        assert!(recent.edited.is_some());
+        assert_eq!(recent.reactions["👍"], ["bob"]);
+
+        // the same as what's stored
+        let stored = state.history.messages(DEFAULT_ROOM, None, 1).await.unwrap();
+        assert_eq!(stored[0].message, recent.message);
+        assert_eq!(stored[0].reactions, recent.reactions);
     }
 }
@@ -0,0 +1,920 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+
+use super::{
+    state::{ChatState, Connection, DEFAULT_ROOM},
+    ChatMessage, ChatRequest, ChatResponse,
+};
+
+// rooms are listed on the left if the terminal is wide enough
+const SIDEBAR_WIDTH: usize = 18;
+const MIN_SIDEBAR_TERMINAL_WIDTH: usize = 60;
+// older lines are dropped, but can be loaded again by scrolling up
+const MAX_SCROLLBACK: usize = 2000;
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+const HINT: &str = "Tab: next room · PgUp/PgDn: scroll · /join /leave /create /help /quit";
+
+const ENTER_SCREEN: &str = "\x1b[?1049h\x1b[2J";
+const LEAVE_SCREEN: &str = "\x1b[?25h\x1b[?1049l";
+
+pub enum TerminalInput {
+    Data(Vec<u8>),
+    // columns and rows
+    Resize(u16, u16),
+}
+
+#[derive(Debug, PartialEq)]
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    ClearLine,
+    Tab,
+    BackTab,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Quit,
+}
+
+#[derive(Clone, Copy)]
+enum Style {
+    Message,
+    Action,
+    Notice,
+}
+
+// a line of scrollback, can contain newlines
+struct Entry {
+    // 0 for notices
+    id: u64,
+    time: u64,
+    text: String,
+    style: Style,
+    // kept to format the entry again after edits and reactions
+    message: Option<ChatMessage>,
+}
+
+impl Entry {
+    fn message(message: &ChatMessage) -> Self {
+        let (text, style) = match (message.id, message.action) {
+            (0, _) => (message.message.clone(), Style::Notice),
+            (_, true) => (
+                format!("* {} {}", message.username, message.message),
+                Style::Action,
+            ),
+            (_, false) => (
+                format!("<{}> {}", message.username, message.message),
+                Style::Message,
+            ),
+        };
+
+        let mut text = text;
+        if message.edited.is_some() {
+            text.push_str(" (edited)");
+        }
+        let reactions = message
+            .reactions
+            .iter()
+            .map(|(emoji, users)| format!("{emoji} {}", users.len()))
+            .collect::<Vec<_>>();
+        if !reactions.is_empty() {
+            text.push_str(&format!("  [{}]", reactions.join(" ")));
+        }
+
+        Self {
+            id: message.id,
+            time: message.time,
+            text,
+            style,
+            message: (message.id != 0).then(|| message.clone()),
+        }
+    }
+
+    fn notice(text: String) -> Self {
+        Self {
+            id: 0,
+            time: now(),
+            text,
+            style: Style::Notice,
+            message: None,
+        }
+    }
+
+    // changes the message and formats the entry again
+    fn update(&mut self, change: impl FnOnce(&mut ChatMessage)) {
+        if let Some(mut message) = self.message.take() {
+            change(&mut message);
+            *self = Entry::message(&message);
+        }
+    }
+}
+
+#[derive(Default)]
+struct RoomView {
+    entries: Vec<Entry>,
+    unread: bool,
+    // rendered lines scrolled up from the bottom
+    scroll: usize,
+    // older messages were requested and haven't arrived yet
+    loading: bool,
+    // there are no older messages to load
+    complete: bool,
+}
+
+impl RoomView {
+    fn push(&mut self, entry: Entry) {
+        self.entries.push(entry);
+        if self.entries.len() > MAX_SCROLLBACK {
+            self.entries.remove(0);
+            self.complete = false;
+        }
+    }
+
+    // adds messages that aren't shown yet, returns how many were new
+    fn merge(&mut self, history: &[ChatMessage]) -> usize {
+        let new = history
+            .iter()
+            .filter(|message| !self.entries.iter().any(|entry| entry.id == message.id))
+            .map(Entry::message)
+            .collect::<Vec<_>>();
+
+        let added = new.len();
+        self.entries.extend(new);
+        self.entries.sort_by_key(|entry| (entry.time, entry.id));
+        added
+    }
+
+    // the id of the oldest stored message, to load the ones before it
+    fn oldest(&self) -> Option<u64> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.id != 0)
+            .map(|entry| entry.id)
+            .min()
+    }
+}
+
+#[derive(Default)]
+struct KeyParser {
+    // the start of a character split across packets
+    pending: Vec<u8>,
+}
+
+impl KeyParser {
+    fn parse(&mut self, data: &[u8]) -> Vec<Key> {
+        let mut bytes = std::mem::take(&mut self.pending);
+        bytes.extend_from_slice(data);
+
+        let mut keys = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let rest = &bytes[i..];
+            let (key, len) = match rest[0] {
+                b'\r' if rest.get(1) == Some(&b'\n') => (Some(Key::Enter), 2),
+                b'\r' | b'\n' => (Some(Key::Enter), 1),
+                0x7f | 0x08 => (Some(Key::Backspace), 1),
+                b'\t' => (Some(Key::Tab), 1),
+                // ctrl-c and ctrl-d
+                0x03 | 0x04 => (Some(Key::Quit), 1),
+                // ctrl-u
+                0x15 => (Some(Key::ClearLine), 1),
+                0x1b => escape_sequence(rest),
+                byte if byte < 0x20 => (None, 1),
+                byte => {
+                    let len = match byte {
+                        0xc0..=0xdf => 2,
+                        0xe0..=0xef => 3,
+                        0xf0..=0xf7 => 4,
+                        0x80..=0xff => {
+                            i += 1;
+                            continue;
+                        }
+                        _ => 1,
+                    };
+                    if rest.len() < len {
+                        self.pending = rest.to_vec();
+                        break;
+                    }
+
+                    let key = std::str::from_utf8(&rest[..len])
+                        .ok()
+                        .and_then(|c| c.chars().next())
+                        .map(Key::Char);
+                    (key, len)
+                }
+            };
+
+            keys.extend(key);
+            i += len;
+        }
+        keys
+    }
+}
+
+// unknown sequences, e.g. function keys, are skipped
+fn escape_sequence(bytes: &[u8]) -> (Option<Key>, usize) {
+    let keys = [
+        (&b"\x1b[5~"[..], Key::PageUp),
+        (b"\x1b[6~", Key::PageDown),
+        (b"\x1b[A", Key::Up),
+        (b"\x1b[B", Key::Down),
+        (b"\x1bOA", Key::Up),
+        (b"\x1bOB", Key::Down),
+        (b"\x1b[Z", Key::BackTab),
+    ];
+    if let Some((sequence, key)) = keys
+        .into_iter()
+        .find(|(sequence, _)| bytes.starts_with(sequence))
+    {
+        return (Some(key), sequence.len());
+    }
+
+    match bytes.get(1) {
+        Some(b'[') | Some(b'O') => {
+            let end = bytes[2..]
+                .iter()
+                .position(|byte| (0x40..=0x7e).contains(byte))
+                .map_or(bytes.len(), |end| end + 3);
+            (None, end)
+        }
+        _ => (None, 1),
+    }
+}
+
+struct Terminal {
+    chat: Arc<ChatState>,
+    connection: Connection,
+    output: mpsc::Sender<Vec<u8>>,
+    // changed since the last frame was sent
+    dirty: bool,
+    width: usize,
+    height: usize,
+    // joined rooms in the order they are listed
+    rooms: Vec<String>,
+    views: HashMap<String, RoomView>,
+    current: usize,
+    input: String,
+    keys: KeyParser,
+    // the last error, shown instead of the hint
+    status: Option<String>,
+    last_typing: Option<Instant>,
+}
+
+// a chat client for terminals, e.g. over ssh, returns the exit status.
+// `output` should be bounded, frames are only rendered when there is room for them,
+// so clients that stop reading only ever miss frames
+pub async fn run(
+    chat: Arc<ChatState>,
+    username: String,
+    (width, height): (u16, u16),
+    mut input: mpsc::UnboundedReceiver<TerminalInput>,
+    output: mpsc::Sender<Vec<u8>>,
+) -> u32 {
+    if chat.is_banned(&username) {
+        let _ = output
+            .send(b"you are banned from the chat\r\n".to_vec())
+            .await;
+        return 1;
+    }
+
+    let connection = chat.connect(Some(username));
+    let mut rx = connection.channel.subscribe();
+
+    let mut terminal = Terminal {
+        chat: chat.clone(),
+        connection: connection.clone(),
+        output: output.clone(),
+        dirty: true,
+        width: width as usize,
+        height: height as usize,
+        rooms: Vec::new(),
+        views: HashMap::new(),
+        current: 0,
+        input: String::new(),
+        keys: KeyParser::default(),
+        status: None,
+        last_typing: None,
+    };
+    for room in chat.join_rooms(&connection) {
+        terminal.open(&room);
+    }
+    terminal.current = 0;
+
+    terminal.write(ENTER_SCREEN).await;
+
+    let mut status = 0;
+    loop {
+        tokio::select! {
+            permit = output.reserve(), if terminal.dirty => match permit {
+                Ok(permit) => {
+                    let frame = terminal.frame();
+                    if !frame.is_empty() {
+                        permit.send(frame.into_bytes());
+                    }
+                    terminal.dirty = false;
+                    continue;
+                }
+                Err(_) => break,
+            },
+            data = input.recv() => match data {
+                Some(TerminalInput::Data(data)) => {
+                    if !terminal.handle_input(&data).await {
+                        break;
+                    }
+                }
+                Some(TerminalInput::Resize(width, height)) => {
+                    terminal.width = width as usize;
+                    terminal.height = height as usize;
+                }
+                None => break,
+            },
+            response = rx.recv() => match response {
+                Ok(response) => terminal.deliver(response),
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            },
+            _ = connection.closed.cancelled() => {
+                // e.g. why the connection was closed
+                while let Ok(response) = rx.try_recv() {
+                    terminal.deliver(response);
+                }
+                status = 1;
+                break;
+            }
+        }
+        terminal.dirty = true;
+    }
+
+    chat.disconnect(&connection);
+    terminal.write(LEAVE_SCREEN).await;
+    if let Some(status) = terminal.status.take() {
+        terminal.write(&format!("{status}\r\n")).await;
+    }
+    status
+}
+
+impl Terminal {
+    async fn write(&self, data: &str) {
+        let _ = self.output.send(data.as_bytes().to_vec()).await;
+    }
+
+    fn room(&self) -> &str {
+        self.rooms
+            .get(self.current)
+            .map_or(DEFAULT_ROOM, String::as_str)
+    }
+
+    fn view(&mut self) -> &mut RoomView {
+        let room = self.room().to_string();
+        self.views.entry(room).or_default()
+    }
+
+    // adds a joined room to the list and switches to it
+    fn open(&mut self, room: &str) {
+        let index = match self.rooms.iter().position(|joined| joined == room) {
+            Some(index) => index,
+            None => {
+                self.rooms.push(room.to_string());
+                let mut view = RoomView::default();
+                view.merge(&self.chat.room_history(room));
+                self.views.insert(room.to_string(), view);
+                self.rooms.len() - 1
+            }
+        };
+        self.switch(index);
+    }
+
+    fn close(&mut self, room: &str) {
+        let Some(index) = self.rooms.iter().position(|joined| joined == room) else {
+            return;
+        };
+        self.rooms.remove(index);
+        self.views.remove(room);
+        if self.current >= index {
+            self.switch(self.current.saturating_sub(1));
+        }
+    }
+
+    fn switch(&mut self, index: usize) {
+        self.current = index.min(self.rooms.len().saturating_sub(1));
+        self.view().unread = false;
+    }
+
+    // returns false to quit
+    async fn handle_input(&mut self, data: &[u8]) -> bool {
+        let page = self.height.saturating_sub(4).max(1);
+
+        for key in self.keys.parse(data) {
+            match key {
+                Key::Char(c) => {
+                    self.input.push(c);
+                    self.typing().await;
+                }
+                Key::Backspace => {
+                    self.input.pop();
+                }
+                Key::ClearLine => self.input.clear(),
+                Key::Enter => {
+                    if !self.submit().await {
+                        return false;
+                    }
+                }
+                Key::Tab if !self.rooms.is_empty() => {
+                    self.switch((self.current + 1) % self.rooms.len())
+                }
+                Key::BackTab if !self.rooms.is_empty() => {
+                    self.switch((self.current + self.rooms.len() - 1) % self.rooms.len())
+                }
+                Key::Tab | Key::BackTab => {}
+                Key::Up => self.scroll_up(1),
+                Key::PageUp => self.scroll_up(page),
+                Key::Down => self.view().scroll = self.view().scroll.saturating_sub(1),
+                Key::PageDown => self.view().scroll = self.view().scroll.saturating_sub(page),
+                Key::Quit => return false,
+            }
+        }
+
+        // requested after scrolling to the top
+        let room = self.room().to_string();
+        let view = self.view();
+        if view.loading {
+            return true;
+        }
+        let lines = self.lines(&room, self.main_width()).len();
+        let rows = self.rows();
+        let view = self.view();
+        if !view.complete && view.scroll + rows >= lines {
+            view.loading = true;
+            let before = view.oldest();
+            let request = ChatRequest::History {
+                room,
+                before,
+                limit: None,
+            };
+            self.chat.handle_req(request, self.connection.clone()).await;
+        }
+        true
+    }
+
+    fn scroll_up(&mut self, lines: usize) {
+        let room = self.room().to_string();
+        let max = self
+            .lines(&room, self.main_width())
+            .len()
+            .saturating_sub(self.rows());
+        let view = self.view();
+        view.scroll = (view.scroll + lines).min(max);
+    }
+
+    // lets others in the room know, at most every few seconds
+    async fn typing(&mut self) {
+        if self.input.starts_with('/')
+            || self
+                .last_typing
+                .is_some_and(|last| last.elapsed() < TYPING_INTERVAL)
+        {
+            return;
+        }
+        self.last_typing = Some(Instant::now());
+
+        let room = self.room().to_string();
+        self.chat
+            .handle_req(ChatRequest::Typing { room }, self.connection.clone())
+            .await;
+    }
+
+    // returns false to quit
+    async fn submit(&mut self) -> bool {
+        let line = std::mem::take(&mut self.input);
+        let line = line.trim();
+        if line.is_empty() {
+            return true;
+        }
+        self.status = None;
+        self.last_typing = None;
+        self.view().scroll = 0;
+
+        let room = self.room().to_string();
+        let (command, args) = line
+            .split_once(char::is_whitespace)
+            .map(|(command, args)| (command, args.trim()))
+            .unwrap_or((line, ""));
+
+        // the rest are handled like in the web client, including commands like /me
+        let request = match command {
+            "/quit" | "/exit" => return false,
+            "/join" if !args.is_empty() => ChatRequest::Join {
+                room: args.trim_start_matches('#').to_string(),
+            },
+            "/leave" | "/part" => ChatRequest::Leave {
+                room: match args {
+                    "" => room,
+                    args => args.trim_start_matches('#').to_string(),
+                },
+            },
+            "/create" if !args.is_empty() => {
+                let (name, private) = match args.split_once(char::is_whitespace) {
+                    Some((name, "private")) => (name, true),
+                    _ => (args, false),
+                };
+                ChatRequest::CreateRoom {
+                    room: name.trim_start_matches('#').to_string(),
+                    private,
+                    topic: None,
+                }
+            }
+            "/join" | "/create" => {
+                self.status = Some(format!("usage: {command} <room>"));
+                return true;
+            }
+            _ => ChatRequest::Message {
+                room,
+                message: line.to_string(),
+                reply_to: None,
+            },
+        };
+
+        self.chat.handle_req(request, self.connection.clone()).await;
+        true
+    }
+
+    fn deliver(&mut self, response: ChatResponse) {
+        let me = self.connection.username.clone();
+
+        match response {
+            ChatResponse::Join { username, room, .. } if username == me => self.open(&room),
+            ChatResponse::Leave { username, room, .. } if username == me => self.close(&room),
+            ChatResponse::Join { username, room, .. } => {
+                self.push(&room, Entry::notice(format!("{username} joined")))
+            }
+            ChatResponse::Leave { username, room, .. } => {
+                self.push(&room, Entry::notice(format!("{username} left")))
+            }
+            ChatResponse::Message(message) => {
+                // notices can be about rooms that aren't joined
+                let room = match self.views.contains_key(&message.room) {
+                    true => message.room.clone(),
+                    false => self.room().to_string(),
+                };
+                self.push(&room, Entry::message(&message));
+            }
+            ChatResponse::RoomHistory { room, history } => {
+                if !self.views.contains_key(&room) {
+                    self.open(&room);
+                }
+                if let Some(view) = self.views.get_mut(&room) {
+                    let added = view.merge(&history);
+                    if view.loading && added == 0 {
+                        view.complete = true;
+                    }
+                    view.loading = false;
+                }
+            }
+            ChatResponse::Delete { room, id } => {
+                if let Some(view) = self.views.get_mut(&room) {
+                    view.entries.retain(|entry| entry.id != id);
+                }
+            }
+            ChatResponse::Edit {
+                room,
+                id,
+                message,
+                edited,
+                ..
+            } => self.update(&room, id, |original| {
+                original.message = message;
+                original.edited = Some(edited);
+            }),
+            ChatResponse::React {
+                room,
+                id,
+                username,
+                emoji,
+            } => self.update(&room, id, |message| {
+                message.reactions.entry(emoji).or_default().push(username);
+            }),
+            ChatResponse::Unreact {
+                room,
+                id,
+                username,
+                emoji,
+            } => self.update(&room, id, |message| {
+                if let Some(users) = message.reactions.get_mut(&emoji) {
+                    users.retain(|user| *user != username);
+                    if users.is_empty() {
+                        message.reactions.remove(&emoji);
+                    }
+                }
+            }),
+            ChatResponse::DirectMessage(direct_message) => {
+                let room = self.room().to_string();
+                let text = format!(
+                    "[{} → {}] {}",
+                    direct_message.from, direct_message.to, direct_message.message
+                );
+                self.push(&room, Entry::notice(text));
+            }
+            ChatResponse::Error { message } => {
+                // e.g. a rate limited history request, scrolling up retries it
+                for view in self.views.values_mut() {
+                    view.loading = false;
+                }
+                self.status = Some(message);
+            }
+            // shown from the chat state when rendering, or not shown at all
+            _ => {}
+        }
+    }
+
+    fn update(&mut self, room: &str, id: u64, change: impl FnOnce(&mut ChatMessage)) {
+        let entry = self
+            .views
+            .get_mut(room)
+            .and_then(|view| view.entries.iter_mut().find(|entry| entry.id == id));
+        if let Some(entry) = entry {
+            entry.update(change);
+        }
+    }
+
+    fn push(&mut self, room: &str, entry: Entry) {
+        let current = room == self.room();
+        if let Some(view) = self.views.get_mut(room) {
+            view.push(entry);
+            view.unread |= !current;
+        }
+    }
+
+    fn main_width(&self) -> usize {
+        match self.width >= MIN_SIDEBAR_TERMINAL_WIDTH {
+            true => self.width - SIDEBAR_WIDTH - 1,
+            false => self.width,
+        }
+    }
+
+    // rows for messages, between the header and the status and input lines
+    fn rows(&self) -> usize {
+        self.height.saturating_sub(3)
+    }
+
+    // the scrollback of a room wrapped to the width, with the style of each line
+    fn lines(&self, room: &str, width: usize) -> Vec<(String, Style)> {
+        let Some(view) = self.views.get(room) else {
+            return Vec::new();
+        };
+
+        let mut lines = Vec::new();
+        for entry in &view.entries {
+            let time = format_time(entry.time);
+
+            // the start of the message this one replies to, above it
+            let reply_to = entry.message.as_ref().and_then(|message| message.reply_to);
+            if let Some(id) = reply_to {
+                let original = view
+                    .entries
+                    .iter()
+                    .find(|entry| entry.id == id)
+                    .and_then(|entry| entry.text.lines().next())
+                    .unwrap_or("an older message");
+                let indent = " ".repeat(time.len() + 1);
+                let text = fit(&format!("{indent}↪ {}", sanitize(original)), width);
+                lines.push((text.trim_end().to_string(), Style::Notice));
+            }
+
+            for (i, line) in entry.text.lines().enumerate() {
+                let prefix = match i {
+                    0 => format!("{time} "),
+                    _ => " ".repeat(time.len() + 1),
+                };
+                let text = format!("{prefix}{}", sanitize(line));
+                for wrapped in wrap(&text, width, prefix.len()) {
+                    lines.push((wrapped, entry.style));
+                }
+            }
+        }
+        lines
+    }
+
+    fn frame(&self) -> String {
+        if self.width < 20 || self.height < 4 {
+            return String::new();
+        }
+        let (width, rows) = (self.width, self.rows());
+        let room = self.room().to_string();
+        let mut out = String::from("\x1b[?25l\x1b[H");
+
+        let users = self
+            .chat
+            .room_users(&room, &self.connection.username)
+            .map_or(0, |users| users.len());
+        let mut header = format!(" #{room} · {users} online");
+        if let Some(topic) = self.chat.room_topic(&room).flatten() {
+            header.push_str(&format!(" · {}", sanitize(&topic)));
+        }
+        out.push_str(&format!("\x1b[7m{}\x1b[0m\r\n", fit(&header, width)));
+
+        let sidebar = width >= MIN_SIDEBAR_TERMINAL_WIDTH;
+        let lines = self.lines(&room, self.main_width());
+        let scroll = self.views.get(&room).map_or(0, |view| view.scroll);
+        let end = lines.len().saturating_sub(scroll);
+        let visible = &lines[end.saturating_sub(rows)..end];
+        // messages start at the bottom
+        let offset = rows - visible.len();
+
+        for row in 0..rows {
+            if sidebar {
+                out.push_str(&self.sidebar_cell(row));
+                out.push_str("\x1b[0m│");
+            }
+            if let Some((line, style)) = row.checked_sub(offset).and_then(|i| visible.get(i)) {
+                let code = match style {
+                    Style::Message => "",
+                    Style::Action => "\x1b[3m",
+                    Style::Notice => "\x1b[2m",
+                };
+                out.push_str(&format!("{code}{line}\x1b[0m"));
+            }
+            out.push_str("\x1b[K\r\n");
+        }
+
+        let status = match (&self.status, scroll) {
+            (Some(error), _) => format!("\x1b[31m{}", fit(&sanitize(error), width)),
+            (None, 0) => format!("\x1b[2m{}", fit(HINT, width)),
+            (None, _) => format!("\x1b[2m{}", fit("scrolled up, PgDn to go back", width)),
+        };
+        out.push_str(&format!("{status}\x1b[0m\x1b[K\r\n"));
+
+        // the end of the input if it's too long
+        let input = self.input.chars().collect::<Vec<_>>();
+        let visible_input = input[input.len().saturating_sub(width - 3)..]
+            .iter()
+            .collect::<String>();
+        out.push_str(&format!("> {visible_input}\x1b[K\x1b[?25h"));
+        out
+    }
+
+    fn sidebar_cell(&self, row: usize) -> String {
+        let Some(room) = self.rooms.get(row) else {
+            return " ".repeat(SIDEBAR_WIDTH);
+        };
+        let unread = self.views.get(room).is_some_and(|view| view.unread);
+        let marker = if unread { "+" } else { " " };
+        let cell = fit(&format!("{marker}#{room}"), SIDEBAR_WIDTH);
+
+        match (row == self.current, unread) {
+            (true, _) => format!("\x1b[7m{cell}"),
+            (false, true) => format!("\x1b[1m{cell}"),
+            (false, false) => cell,
+        }
+    }
+}
+
+// other users could otherwise move the cursor or change colors
+fn sanitize(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+// cuts or pads the text to exactly `width` characters
+fn fit(text: &str, width: usize) -> String {
+    let mut fitted = text.chars().take(width).collect::<String>();
+    let len = fitted.chars().count();
+    fitted.push_str(&" ".repeat(width - len));
+    fitted
+}
+
+// continuation lines are indented by `indent`
+fn wrap(text: &str, width: usize, indent: usize) -> Vec<String> {
+    let chars = text.chars().collect::<Vec<_>>();
+    if width <= indent + 1 || chars.len() <= width {
+        return vec![text.to_string()];
+    }
+
+    let mut lines = vec![chars[..width].iter().collect::<String>()];
+    for chunk in chars[width..].chunks(width - indent) {
+        lines.push(format!(
+            "{}{}",
+            " ".repeat(indent),
+            chunk.iter().collect::<String>()
+        ));
+    }
+    lines
+}
+
+// `HH:MM` in UTC
+fn format_time(time: u64) -> String {
+    let time = time::OffsetDateTime::from_unix_timestamp(time as i64)
+        .unwrap_or(time::OffsetDateTime::UNIX_EPOCH);
+    format!("{:02}:{:02}", time.hour(), time.minute())
+}
+
+fn now() -> u64 {
+    time::OffsetDateTime::now_utc().unix_timestamp() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u64, time: u64, text: &str) -> ChatMessage {
+        ChatMessage {
+            id,
+            username: "alice".to_string(),
+            room: "general".to_string(),
+            message: text.to_string(),
+            html: String::new(),
+            time,
+            action: false,
+            reply_to: None,
+            edited: None,
+            reactions: Default::default(),
+        }
+    }
+
+    #[test]
+    fn parses_keys() {
+        let mut parser = KeyParser::default();
+        assert_eq!(
+            parser.parse(b"a\r\n\x7f\t\x03\x15\x01"),
+            [
+                Key::Char('a'),
+                Key::Enter,
+                Key::Backspace,
+                Key::Tab,
+                Key::Quit,
+                Key::ClearLine
+            ]
+        );
+        assert_eq!(
+            parser.parse(b"\x1b[5~\x1b[6~\x1bOA\x1b[B\x1b[Z"),
+            [Key::PageUp, Key::PageDown, Key::Up, Key::Down, Key::BackTab]
+        );
+    }
+
+    #[test]
+    fn skips_unknown_escape_sequences() {
+        let mut parser = KeyParser::default();
+        // F5, then a lone escape
+        assert_eq!(
+            parser.parse(b"\x1b[15~x\x1by"),
+            [Key::Char('x'), Key::Char('y')]
+        );
+        // an unfinished sequence is skipped as a whole
+        assert_eq!(escape_sequence(b"\x1b[1;5"), (None, 5));
+    }
+
+    #[test]
+    fn joins_characters_split_across_packets() {
+        let mut parser = KeyParser::default();
+        let bytes = "é🎉".as_bytes();
+        assert!(parser.parse(&bytes[..1]).is_empty());
+        assert_eq!(parser.parse(&bytes[1..4]), [Key::Char('é')]);
+        assert_eq!(parser.parse(&bytes[4..]), [Key::Char('🎉')]);
+        // stray continuation bytes are dropped
+        assert_eq!(parser.parse(b"\x80a"), [Key::Char('a')]);
+    }
+
+    #[test]
+    fn wraps_with_indent() {
+        assert_eq!(wrap("short", 10, 2), ["short"]);
+        assert_eq!(wrap("abcdefghij", 4, 2), ["abcd", "  ef", "  gh", "  ij"]);
+        // too narrow to indent
+        assert_eq!(wrap("abcdef", 2, 2), ["abcdef"]);
+    }
+
+    #[test]
+    fn fits_and_sanitizes_text() {
+        assert_eq!(fit("héllo", 3), "hél");
+        assert_eq!(fit("hé", 4), "hé  ");
+        assert_eq!(sanitize("a\x1b[2Jb\n"), "a [2Jb ");
+        assert_eq!(format_time(3600 + 5 * 60), "01:05");
+    }
+
+    #[test]
+    fn merges_older_messages() {
+        let mut view = RoomView::default();
+        view.push(Entry::message(&message(3, 30, "c")));
+        view.push(Entry::notice("joined".to_string()));
+
+        let history = [
+            message(1, 10, "a"),
+            message(2, 20, "b"),
+            message(3, 30, "c"),
+        ];
+        assert_eq!(view.merge(&history), 2);
+        let texts = view
+            .entries
+            .iter()
+            .map(|e| e.text.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(texts[..3], ["<alice> a", "<alice> b", "<alice> c"]);
+        assert_eq!(view.oldest(), Some(1));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::utils::{is_valid_project_path, is_valid_username};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 pub const DOCKER_IMAGE: &str = "ghcr.io/dawdlestudios/container";
 pub const DOCKER_TAG: &str = "latest";
@@ -11,6 +12,8 @@ pub struct Config {
     pub ssh: SSHConfig,
     pub web: WebConfig,
     pub minecraft: MinecraftConfig,
+    #[serde(default)]
+    pub chat: ChatConfig,
     pub create_admin_user: Option<(String, String)>,
 }
 
@@ -33,6 +36,127 @@ pub struct SSHConfig {
 pub struct WebConfig {
     pub port: u16,
     pub interface: String,
+
+    /// The platform's base domain, user sites are served from its subdomains
+    #[serde(default = "default_domain")]
+    pub domain: String,
+
+    /// What to serve on the base domain (next to the api)
+    #[serde(default)]
+    pub main_site: Option<SiteSource>,
+
+    /// Additional hostnames that serve the api and main site, e.g. www.dawdle.space
+    #[serde(default)]
+    pub api_hostnames: Vec<String>,
+
+    /// Ports allowed in the Host header, an empty list allows any port.
+    /// The web and tls ports are always allowed
+    #[serde(default = "default_public_ports")]
+    pub public_ports: Vec<u16>,
+
+    /// Sites served from user projects, keyed by subdomain or custom domain
+    #[serde(default)]
+    pub sites: HashMap<String, ProjectConfig>,
+
+    /// Serve HTTPS with certificates issued through ACME
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+impl WebConfig {
+    // hostnames are compared in lowercase, and the ports the server listens on are always allowed
+    fn normalize(&mut self) {
+        self.domain = self.domain.to_ascii_lowercase();
+        for hostname in self.api_hostnames.iter_mut() {
+            *hostname = hostname.to_ascii_lowercase();
+        }
+        self.sites = std::mem::take(&mut self.sites)
+            .into_iter()
+            .map(|(hostname, project)| (hostname.to_ascii_lowercase(), project))
+            .collect();
+
+        if self.public_ports.is_empty() {
+            return;
+        }
+        let listening = std::iter::once(self.port).chain(self.tls.as_ref().map(|tls| tls.port));
+        for port in listening {
+            if !self.public_ports.contains(&port) {
+                self.public_ports.push(port);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SiteSource {
+    /// A project inside a user's home directory
+    Project(ProjectConfig),
+    /// A directory on the server
+    Static { dir: String },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProjectConfig {
+    pub user: String,
+    pub project: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TlsConfig {
+    pub port: u16,
+
+    /// Domains to always request certificates for, the platform domain, api hostnames,
+    /// user subdomains and custom domains of user sites are added automatically
+    #[serde(default)]
+    pub domains: Vec<String>,
+
+    /// Redirect plain HTTP requests to HTTPS if a certificate for the host exists
+    #[serde(default = "default_true")]
+    pub redirect_http: bool,
+
+    /// Renew certificates this many days before they expire
+    #[serde(default = "default_renew_before_days")]
+    pub renew_before_days: u32,
+
+    pub acme: AcmeConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AcmeConfig {
+    /// The ACME directory, e.g. https://acme-v02.api.letsencrypt.org/directory
+    /// or https://localhost:14000/dir for a local Pebble instance
+    #[serde(default = "default_acme_directory")]
+    pub directory_url: String,
+
+    /// Contact URLs for the ACME account, e.g. mailto:admin@dawdle.space
+    #[serde(default)]
+    pub contact: Vec<String>,
+
+    /// Additional root certificate (PEM) to trust when talking to the ACME server,
+    /// needed for test servers like Pebble
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+}
+
+fn default_domain() -> String {
+    "dawdle.space".to_string()
+}
+
+fn default_public_ports() -> Vec<u16> {
+    vec![80, 443]
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_renew_before_days() -> u32 {
+    30
+}
+
+fn default_acme_directory() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_string()
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -41,6 +165,108 @@ pub struct MinecraftConfig {
     pub restadmin_token: String,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatConfig {
+    /// Messages per room kept in memory and sent to new connections
+    #[serde(default = "default_history_window")]
+    pub history_window: usize,
+
+    /// How long messages are stored, unless a room overrides it
+    #[serde(default)]
+    pub retention: RetentionConfig,
+
+    /// Retention for specific rooms, keyed by room name
+    #[serde(default)]
+    pub rooms: HashMap<String, RetentionConfig>,
+
+    /// Longest message in characters that can be sent
+    #[serde(default = "default_max_message_length")]
+    pub max_message_length: usize,
+
+    /// How many messages a connection can send in quick succession
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+
+    /// Guests can only read, not send messages
+    #[serde(default)]
+    pub guests_read_only: bool,
+
+    /// Lets members connect with IRC clients, disabled if unset
+    #[serde(default)]
+    pub irc: Option<IrcConfig>,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            history_window: default_history_window(),
+            retention: RetentionConfig::default(),
+            rooms: HashMap::new(),
+            max_message_length: default_max_message_length(),
+            rate_limit: RateLimitConfig::default(),
+            guests_read_only: false,
+            irc: None,
+        }
+    }
+}
+
+impl ChatConfig {
+    pub fn retention(&self, room: &str) -> &RetentionConfig {
+        self.rooms.get(room).unwrap_or(&self.retention)
+    }
+
+    // largest websocket message a client can send, a maximum length message with every
+    // character escaped in json (`\u0000`) and room for the rest of the request
+    pub fn max_request_size(&self) -> usize {
+        self.max_message_length * 6 + 1024
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RetentionConfig {
+    /// Delete messages older than this, keeps them forever if unset
+    pub max_age_days: Option<u32>,
+
+    /// Only keep the newest messages, keeps all of them if unset
+    pub max_messages: Option<u32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IrcConfig {
+    pub port: u16,
+    pub interface: String,
+
+    /// Also listen with TLS on this port, using the certificates from `web.tls`
+    #[serde(default)]
+    pub tls_port: Option<u16>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// Messages that can be sent at once before being limited
+    pub burst: u32,
+
+    /// Messages per minute that can be sent after the burst is used up
+    pub per_minute: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            burst: 5,
+            per_minute: 20,
+        }
+    }
+}
+
+fn default_history_window() -> usize {
+    50
+}
+
+fn default_max_message_length() -> usize {
+    2000
+}
+
 impl Config {
     pub fn load() -> eyre::Result<Self> {
         let config_path = std::env::var("DAWDLE_CONFIG").unwrap_or_else(|_| {
@@ -63,7 +289,11 @@ impl Config {
         });
 
         let config = std::fs::read_to_string(config_path.clone())?;
-        let config: Config = toml::from_str(&config)?;
+        let mut config: Config = toml::from_str(&config)?;
+        config.web.normalize();
+        if config.chat.history_window == 0 {
+            eyre::bail!("chat.history_window has to be at least 1");
+        }
 
         log::info!("loaded config from {}", config_path);
         Ok(config)
@@ -103,6 +333,31 @@ impl Config {
             .join("id_ed25519")
     }
 
+    pub fn themes_dir(&self) -> std::path::PathBuf {
+        resolve_path(&self.fs.data_dir).join("themes")
+    }
+
+    pub fn image_cache_dir(&self) -> std::path::PathBuf {
+        resolve_path(&self.fs.data_dir).join("image-cache")
+    }
+
+    pub fn main_site_path(&self) -> Option<std::path::PathBuf> {
+        match self.web.main_site.as_ref()? {
+            SiteSource::Project(project) => self.project_path(&project.user, &project.project),
+            SiteSource::Static { dir } => Some(resolve_path(dir)),
+        }
+    }
+
+    pub fn certs_dir(&self) -> std::path::PathBuf {
+        resolve_path(&self.fs.data_dir).join("certs")
+    }
+
+    pub fn acme_account_path(&self) -> std::path::PathBuf {
+        resolve_path(&self.fs.data_dir)
+            .join("acme")
+            .join("account.json")
+    }
+
     pub fn project_path(&self, username: &str, project_path: &str) -> Option<std::path::PathBuf> {
         if !is_valid_username(username) || !is_valid_project_path(project_path) {
             return None;
@@ -5,11 +5,13 @@ use bollard::{
 };
 use eyre::{eyre, Result};
 use futures::Stream;
-use std::pin::Pin;
+use std::{os::unix::fs::PermissionsExt, pin::Pin};
 use tokio::io::AsyncWrite;
 
 use crate::{config::Config, utils::is_valid_username};
 
+const BUILD_SCRIPT: &str = "#!/bin/sh\nexec /usr/local/dawdle/bin/dawdle-server build \"$@\"\n";
+
 #[derive(Clone)]
 pub struct Containers {
     docker: Docker,
@@ -44,6 +46,34 @@ impl Containers {
 
     pub async fn init(&self) -> Result<()> {
         let _ = self.docker.info().await?;
+        self.install_tools()?;
+        Ok(())
+    }
+
+    // makes `dawdle-build` available in containers, using this binary
+    fn install_tools(&self) -> Result<()> {
+        let bin_dir = self.config.user_bin_dir();
+        std::fs::create_dir_all(&bin_dir)?;
+
+        let exe = std::env::current_exe()?;
+        let target = bin_dir.join("dawdle-server");
+        let exe_meta = std::fs::metadata(&exe)?;
+        let outdated = match std::fs::metadata(&target) {
+            Ok(meta) => meta.len() != exe_meta.len() || meta.modified()? < exe_meta.modified()?,
+            Err(_) => true,
+        };
+
+        if outdated {
+            // copy to a temporary file first, running containers might be using the old one
+            let tmp = bin_dir.join(".dawdle-server.tmp");
+            std::fs::copy(&exe, &tmp)?;
+            std::fs::rename(&tmp, &target)?;
+        }
+
+        let script = bin_dir.join("dawdle-build");
+        std::fs::write(&script, BUILD_SCRIPT)?;
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))?;
+
         Ok(())
     }
 
@@ -108,7 +138,7 @@ impl Containers {
                         binds: Some(binds),
                         ..Default::default()
                     }),
-                    hostname: Some("dawdle.space"),
+                    hostname: Some(self.config.web.domain.as_str()),
                     image: Some(
                         format!(
                             "{}:{}",
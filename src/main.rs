@@ -19,7 +19,14 @@ use tokio::select;
 async fn main() -> eyre::Result<()> {
     env_logger::builder().filter_level(LevelFilter::Info).init();
 
+    // `dawdle-server build <dir> <out>` exports a site without starting the server
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).is_some_and(|arg| arg == "build") {
+        return ssg::build::command(&args[2..]);
+    }
+
     let config = config::Config::load()?;
+    ssg::themes::init(config.themes_dir());
     let app = app::App::new(config.clone()).await?;
 
     if let Some((username, password)) = config.clone().create_admin_user {
@@ -41,6 +48,16 @@ async fn main() -> eyre::Result<()> {
         app.config.ssh.port,
     );
 
+    // the irc server is optional, without it this never completes
+    let irc_config = app.config.chat.irc.clone();
+    let irc_app = app.clone();
+    let irc_server = async move {
+        match irc_config {
+            Some(config) => chat::irc::run(irc_app, config).await,
+            None => std::future::pending().await,
+        }
+    };
+
     let ssh_server = SshServer::new(containers, app);
     let ssh_server = ssh_server.run(ssh_addr);
 
@@ -49,6 +66,7 @@ async fn main() -> eyre::Result<()> {
 
     select! {
         r = ssh_server => r,
-        r = api_server => r
+        r = api_server => r,
+        r = irc_server => r,
     }
 }
@@ -0,0 +1,9 @@
+create table chat_messages (
+    id integer primary key autoincrement,
+    room text not null,
+    username text not null,
+    message text not null,
+    time integer not null
+);
+
+create index chat_messages_room on chat_messages (room, time);
@@ -0,0 +1,18 @@
+create table chat_rooms (
+    name text primary key not null,
+    topic text,
+    private boolean not null default false,
+    created_by text,
+    created_at integer not null default (strftime('%s', 'now'))
+);
+
+-- users who joined a room, or were invited to a private one
+create table chat_room_members (
+    room text not null,
+    username text not null,
+    primary key (room, username),
+    foreign key (room) references chat_rooms (name) on delete cascade,
+    foreign key (username) references users (username) on delete cascade
+);
+
+insert into chat_rooms (name) values ('general');
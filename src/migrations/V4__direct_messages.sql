@@ -0,0 +1,13 @@
+create table direct_messages (
+    id integer primary key autoincrement,
+    sender text not null,
+    recipient text not null,
+    message text not null,
+    time integer not null,
+    read boolean not null default false,
+    foreign key (sender) references users (username) on delete cascade,
+    foreign key (recipient) references users (username) on delete cascade
+);
+
+create index direct_messages_conversation on direct_messages (sender, recipient, time);
+create index direct_messages_unread on direct_messages (recipient, read);
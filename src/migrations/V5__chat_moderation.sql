@@ -0,0 +1,9 @@
+-- `/me` messages
+alter table chat_messages add column action boolean not null default false;
+
+create table chat_sanctions (
+    username text primary key not null,
+    muted_until integer,
+    banned boolean not null default false,
+    foreign key (username) references users (username) on delete cascade
+);
@@ -0,0 +1,13 @@
+-- minimum seconds between messages of a user in the room, 0 is off
+alter table chat_rooms add column slow_mode integer not null default 0;
+
+-- messages matching any of these are rejected
+create table chat_filters (
+    id integer primary key autoincrement,
+    -- `word` or `link`
+    kind text not null,
+    pattern text not null,
+    created_by text,
+    created_at integer not null default (strftime('%s', 'now')),
+    unique (kind, pattern)
+);
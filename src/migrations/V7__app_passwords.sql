@@ -0,0 +1,10 @@
+-- separate passwords for clients like IRC, so the main password isn't stored in them
+create table user_app_passwords (
+    username text not null,
+    name text not null,
+    password_hash text not null,
+    created_at integer not null default (strftime('%s', 'now')),
+    last_used integer,
+    primary key (username, name),
+    foreign key (username) references users (username) on delete cascade
+);
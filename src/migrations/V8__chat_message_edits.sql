@@ -0,0 +1,12 @@
+-- unix time of the last edit
+alter table chat_messages add column edited integer;
+-- the id of the message this one replies to
+alter table chat_messages add column reply_to integer;
+
+create table chat_reactions (
+    message_id integer not null,
+    username text not null,
+    emoji text not null,
+    time integer not null,
+    primary key (message_id, username, emoji)
+);
@@ -0,0 +1,242 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use eyre::{bail, Result};
+
+use super::{collections, feeds, site::Site, template::Files, themes};
+
+// only used while rendering, everything else is copied as-is
+const SKIPPED: &[&str] = &[
+    "_layouts",
+    "_includes",
+    "_themes",
+    "_site.toml",
+    "_dawdle.toml",
+];
+
+const USAGE: &str =
+    "usage: dawdle-server build <dir> <out> [--url <site url>] [--themes <themes dir>]";
+
+#[derive(Debug, Default)]
+struct Stats {
+    rendered: usize,
+    generated: usize,
+    copied: usize,
+    failed: usize,
+}
+
+// `dawdle-server build <dir> <out>`, also available as `dawdle-build` inside containers
+pub fn command(args: &[String]) -> Result<()> {
+    let mut paths = Vec::new();
+    let mut site_url = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--url" => match args.next() {
+                Some(url) => site_url = Some(url.clone()),
+                None => bail!(USAGE),
+            },
+            "--themes" => match args.next() {
+                Some(dir) => super::themes::init(PathBuf::from(dir)),
+                None => bail!(USAGE),
+            },
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+
+    let [dir, out] = paths.as_slice() else {
+        bail!(USAGE);
+    };
+
+    let stats = build(dir, out, site_url.as_deref())?;
+    println!(
+        "rendered {} pages, generated {} pages and copied {} files to {}",
+        stats.rendered,
+        stats.generated,
+        stats.copied,
+        out.display()
+    );
+
+    if stats.failed > 0 {
+        bail!("{} pages failed to render", stats.failed);
+    }
+
+    Ok(())
+}
+
+fn build(dir: &Path, out: &Path, site_url: Option<&str>) -> Result<Stats> {
+    if !dir.is_dir() {
+        bail!("{} is not a directory", dir.display());
+    }
+
+    std::fs::create_dir_all(out)?;
+    let dir = dir.canonicalize()?;
+    let out = out.canonicalize()?;
+    if dir == out {
+        bail!("the output directory has to be different from the site directory");
+    }
+
+    let mut stats = Stats::default();
+    export_dir(&dir, &out, Path::new(""), &mut stats)?;
+
+    // theme files are served from the same path on every site
+    let site = Site::load(&Files::new(&dir))?;
+    let mut theme_names = match std::fs::read_dir(dir.join("_themes")) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .collect(),
+        Err(_) => BTreeSet::new(),
+    };
+    theme_names.extend(site.variable("theme").map(String::from));
+
+    for name in theme_names {
+        let Some(theme_dir) = themes::theme_dir(&dir, &name) else {
+            continue;
+        };
+        let target = out
+            .join(themes::THEMES_PATH.trim_start_matches('/'))
+            .join(&name);
+        copy_theme(&theme_dir, &target, &mut stats)?;
+    }
+
+    // index and tag pages of collections, unless there is a page at the same path
+    let collections = collections::load(&Files::new(&dir), &site)?;
+    let mut generated = collections::generated_paths(&collections);
+    if dir.join(super::site::SITE_FILE).exists() {
+        generated.extend(["/feed.xml", "/rss.xml", "/sitemap.xml"].map(String::from));
+    }
+
+    for path in generated {
+        let relative = out.join(path.trim_start_matches('/'));
+        let target = match feeds::is_feed(&path) {
+            true => relative.clone(),
+            false => relative.join("index.html"),
+        };
+
+        // `/posts` could also be `posts.html`
+        if target.exists() || relative.with_extension("html").exists() {
+            continue;
+        }
+
+        match super::render_generated_page(&dir, &path, site_url) {
+            Ok(Some(rendered)) => {
+                write(&target, rendered.body.as_bytes())?;
+                stats.generated += 1;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                eprintln!("{path}: {err}");
+                stats.failed += 1;
+            }
+        }
+    }
+
+    Ok(stats)
+}
+
+fn export_dir(base: &Path, out: &Path, relative: &Path, stats: &mut Stats) -> Result<()> {
+    let mut entries = std::fs::read_dir(base.join(relative))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry.file_name();
+        let Some(name_str) = name.to_str() else {
+            continue;
+        };
+
+        if name_str.starts_with('.')
+            || (relative.as_os_str().is_empty() && SKIPPED.contains(&name_str))
+        {
+            continue;
+        }
+
+        let path = entry.path();
+        // the output directory might be inside of the site
+        if path == out {
+            continue;
+        }
+
+        let relative = relative.join(&name);
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            export_dir(base, out, &relative, stats)?;
+            continue;
+        }
+
+        if file_type.is_symlink() && path.is_dir() {
+            // could point to one of its parents
+            continue;
+        }
+
+        let target = out.join(&relative);
+        if path.extension().is_some_and(|ext| ext == "md") {
+            // static html files are served instead of rendered pages
+            let target = target.with_extension("html");
+            if path.with_extension("html").exists() {
+                continue;
+            }
+
+            match super::render_page(base, &path) {
+                Ok(Some(rendered)) => {
+                    write(&target, rendered.body.as_bytes())?;
+                    stats.rendered += 1;
+                }
+                // drafts
+                Ok(None) => {}
+                Err(err) => {
+                    eprintln!("{}: {err}", relative.display());
+                    stats.failed += 1;
+                }
+            }
+            continue;
+        }
+
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::copy(&path, &target)?;
+        stats.copied += 1;
+    }
+
+    Ok(())
+}
+
+// everything but layouts, includes and hidden files
+fn copy_theme(theme_dir: &Path, target: &Path, stats: &mut Stats) -> Result<()> {
+    for entry in std::fs::read_dir(theme_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name
+            .to_str()
+            .is_none_or(|name| name.starts_with(['.', '_']))
+        {
+            continue;
+        }
+
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_theme(&entry.path(), &target.join(&name), stats)?;
+        } else if file_type.is_file() {
+            std::fs::create_dir_all(target)?;
+            std::fs::copy(entry.path(), target.join(&name))?;
+            stats.copied += 1;
+        }
+    }
+
+    Ok(())
+}
+
+fn write(path: &Path, content: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, content)?;
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+    time::SystemTime,
+};
+
+use lru::LruCache;
+
+use super::Page;
+
+// total size of rendered pages kept in memory
+const CACHE_CAPACITY: usize = 32 * 1024 * 1024;
+
+// pages bigger than this aren't cached at all
+const MAX_PAGE_SIZE: usize = CACHE_CAPACITY / 8;
+
+// every file a page was rendered from (the markdown file, its layout, ...)
+// and its modification time at the time of rendering
+pub type Dependencies = Vec<(PathBuf, Option<SystemTime>)>;
+
+struct Entry {
+    dependencies: Dependencies,
+    page: Page,
+}
+
+struct RenderCache {
+    entries: LruCache<PathBuf, Entry>,
+    size: usize,
+}
+
+static CACHE: LazyLock<Mutex<RenderCache>> = LazyLock::new(|| {
+    Mutex::new(RenderCache {
+        entries: LruCache::unbounded(),
+        size: 0,
+    })
+});
+
+pub async fn modified(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+// the cached page, if none of the files it was rendered from changed since
+pub async fn get(path: &Path) -> Option<Page> {
+    let (dependencies, page) = {
+        let mut cache = CACHE.lock().unwrap();
+        let entry = cache.entries.get(path)?;
+        (entry.dependencies.clone(), entry.page.clone())
+    };
+
+    for (dependency, cached) in &dependencies {
+        if modified(dependency).await != *cached {
+            return None;
+        }
+    }
+
+    Some(page)
+}
+
+pub fn insert(path: PathBuf, dependencies: Dependencies, page: Page) {
+    let size = page.body.len();
+    if size > MAX_PAGE_SIZE {
+        return;
+    }
+
+    let mut cache = CACHE.lock().unwrap();
+    cache.size += size;
+    if let Some(previous) = cache.entries.put(path, Entry { dependencies, page }) {
+        cache.size -= previous.page.body.len();
+    }
+
+    while cache.size > CACHE_CAPACITY {
+        let Some((_, evicted)) = cache.entries.pop_lru() else {
+            break;
+        };
+        cache.size -= evicted.page.body.len();
+    }
+}
+
+pub fn last_modified(dependencies: &Dependencies) -> Option<SystemTime> {
+    dependencies
+        .iter()
+        .filter_map(|(_, modified)| *modified)
+        .max()
+}
@@ -0,0 +1,569 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use eyre::Result;
+use minijinja::Value;
+use serde::{Deserialize, Serialize};
+
+use super::{frontmatter::FrontMatter, site::Site, template::Files};
+use crate::utils::escape_html;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CollectionConfig {
+    /// Directory of the markdown files, relative to the site root (defaults to the name)
+    pub dir: Option<String>,
+
+    /// Items per generated index page
+    #[serde(default = "default_per_page")]
+    pub per_page: usize,
+
+    /// Layout for the generated index and tag pages
+    pub layout: Option<String>,
+
+    /// Generate `/<dir>/tags/<tag>` pages
+    #[serde(default = "default_tags")]
+    pub tags: bool,
+}
+
+fn default_per_page() -> usize {
+    10
+}
+
+fn default_tags() -> bool {
+    true
+}
+
+impl Default for CollectionConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            per_page: default_per_page(),
+            layout: None,
+            tags: default_tags(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Item {
+    pub url: String,
+    #[serde(skip)]
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub front_matter: FrontMatter,
+}
+
+pub struct Collection {
+    pub name: String,
+    pub dir: String,
+    pub config: CollectionConfig,
+    // newest first
+    pub items: Vec<Item>,
+}
+
+impl Collection {
+    pub fn url(&self) -> String {
+        format!("/{}", self.dir)
+    }
+
+    fn tags(&self) -> BTreeMap<String, Vec<&Item>> {
+        let mut tags: BTreeMap<String, Vec<&Item>> = BTreeMap::new();
+        for item in &self.items {
+            for tag in item.tags() {
+                tags.entry(tag_slug(tag)).or_default().push(item);
+            }
+        }
+        tags
+    }
+}
+
+impl Item {
+    fn tags(&self) -> Vec<&str> {
+        self.front_matter
+            .tags
+            .as_ref()
+            .map(|tags| tags.as_list())
+            .unwrap_or_default()
+    }
+}
+
+pub fn load(files: &Files, site: &Site) -> Result<Vec<Collection>> {
+    let mut collections = Vec::new();
+
+    for (name, config) in &site.collections {
+        let dir = config
+            .dir
+            .clone()
+            .unwrap_or_else(|| name.clone())
+            .trim_matches('/')
+            .to_string();
+
+        let items = match is_valid_dir(&dir) {
+            true => read_items(files, &dir, site.preview, true)?,
+            false => {
+                log::warn!("invalid directory for collection {name}: {dir}");
+                Vec::new()
+            }
+        };
+
+        collections.push(Collection {
+            name: name.clone(),
+            dir,
+            config: config.clone(),
+            items,
+        });
+    }
+
+    Ok(collections)
+}
+
+fn is_valid_dir(dir: &str) -> bool {
+    !dir.is_empty()
+        && Path::new(dir)
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+}
+
+// whether the page at `path` (relative to the site root) is part of a collection
+pub fn is_item(site: &Site, path: &Path) -> bool {
+    let (Some(parent), Some(stem)) = (path.parent(), path.file_stem().and_then(|s| s.to_str()))
+    else {
+        return false;
+    };
+
+    stem != "index"
+        && !stem.starts_with(['.', '_'])
+        && site.collections.iter().any(|(name, config)| {
+            let dir = config.dir.as_deref().unwrap_or(name).trim_matches('/');
+            parent == Path::new(dir)
+        })
+}
+
+// feeds use the `posts` directory if the site doesn't have any collections, e.g. without
+// a site file, its pages are served where the files are since it isn't a real collection
+pub fn default_posts(files: &Files, site: &Site) -> Result<Collection> {
+    Ok(Collection {
+        name: "posts".to_string(),
+        dir: "posts".to_string(),
+        config: CollectionConfig::default(),
+        items: read_items(files, "posts", site.preview, false)?,
+    })
+}
+
+fn read_items(files: &Files, dir: &str, preview: bool, in_collection: bool) -> Result<Vec<Item>> {
+    let entries = match files.read_dir(Path::new(dir)) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut items = Vec::new();
+    for path in entries {
+        let Some(stem) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+
+        // the index page lists the collection, it's not part of it
+        if path.extension().is_none_or(|ext| ext != "md")
+            || stem == "index"
+            || stem.starts_with(['.', '_'])
+        {
+            continue;
+        }
+
+        let Some(md) = files.read(&path)? else {
+            continue;
+        };
+
+        let mut front_matter = FrontMatter::from_md(&md);
+        if front_matter.draft && !preview {
+            continue;
+        }
+        front_matter.title = front_matter.title.or_else(|| Some(stem.to_string()));
+
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+
+        items.push(Item {
+            url: front_matter.url(&Path::new(dir).join(name), in_collection),
+            path: path.clone(),
+            front_matter,
+        });
+    }
+
+    // dates are expected as `YYYY-MM-DD`, so they sort as strings
+    items.sort_by(|a, b| b.front_matter.date.cmp(&a.front_matter.date));
+    Ok(items)
+}
+
+// lowercase, with everything but letters and digits replaced by dashes
+pub fn tag_slug(tag: &str) -> String {
+    tag.trim()
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+// available to templates as `collections.<name>.pages`, newest first
+pub fn variables(collections: &[Collection]) -> Value {
+    let collections = collections
+        .iter()
+        .map(|collection| {
+            let tags = collection
+                .tags()
+                .into_iter()
+                .map(|(slug, items)| (slug, items.len()))
+                .collect::<BTreeMap<_, _>>();
+
+            (
+                collection.name.clone(),
+                minijinja::context! {
+                    url => collection.url(),
+                    pages => Value::from_serialize(&collection.items),
+                    tags => tags,
+                },
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    Value::from_serialize(&collections)
+}
+
+// every generated page and item with a custom url, for exporting the site
+pub fn generated_paths(collections: &[Collection]) -> Vec<String> {
+    let pages = |base_url: String, items: usize, per_page: usize| {
+        let total_pages = items.div_ceil(per_page.max(1)).max(1);
+        (1..=total_pages)
+            .map(move |page| match page {
+                1 => base_url.clone(),
+                page => format!("{base_url}/page/{page}"),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut paths = Vec::new();
+    for collection in collections {
+        let per_page = collection.config.per_page;
+        paths.extend(pages(collection.url(), collection.items.len(), per_page));
+
+        // items are exported where their file is, unless they have their own url
+        let custom_urls = collection
+            .items
+            .iter()
+            .filter(|item| {
+                item.front_matter.slug.is_some() || item.front_matter.permalink.is_some()
+            })
+            .map(|item| item.url.clone());
+        paths.extend(custom_urls);
+
+        if collection.config.tags {
+            for (tag, items) in collection.tags() {
+                let base_url = format!("{}/tags/{tag}", collection.url());
+                paths.extend(pages(base_url, items.len(), per_page));
+            }
+        }
+    }
+    paths
+}
+
+// a generated page: `/<dir>`, `/<dir>/page/<n>`, `/<dir>/tags/<tag>` or `/<dir>/tags/<tag>/page/<n>`
+pub struct Route {
+    pub collection: String,
+    pub tag: Option<String>,
+    pub page: usize,
+}
+
+impl Route {
+    pub fn parse(site: &Site, path: &str) -> Option<Self> {
+        let path = path.trim_end_matches('/');
+
+        site.collections.iter().find_map(|(name, config)| {
+            let dir = config.dir.as_deref().unwrap_or(name).trim_matches('/');
+            let rest = path.strip_prefix('/')?.strip_prefix(dir)?;
+
+            // `/postsfoo` isn't part of `/posts`
+            if !rest.is_empty() && !rest.starts_with('/') {
+                return None;
+            }
+
+            let (rest, page) = match rest.rsplit_once("/page/") {
+                Some((rest, page)) => (rest, page.parse().ok().filter(|page| *page > 1)?),
+                None => (rest, 1),
+            };
+
+            let tag = match rest {
+                "" => None,
+                rest if config.tags => {
+                    let tag = rest.strip_prefix("/tags/")?;
+                    if tag.is_empty() || tag.contains('/') {
+                        return None;
+                    }
+                    Some(tag.to_string())
+                }
+                _ => return None,
+            };
+
+            Some(Self {
+                collection: name.clone(),
+                tag,
+                page,
+            })
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Paginator {
+    pub title: String,
+    pub page: usize,
+    pub total_pages: usize,
+    pub total_items: usize,
+    pub pages: Vec<Item>,
+    pub previous: Option<String>,
+    pub next: Option<String>,
+}
+
+impl Paginator {
+    // returns None if the page doesn't exist
+    pub fn new(collection: &Collection, route: &Route) -> Option<Self> {
+        let (title, base_url, items) = match &route.tag {
+            Some(tag) => {
+                let items = collection.tags().remove(tag)?;
+                let title = items
+                    .iter()
+                    .flat_map(|item| item.tags())
+                    .find(|t| tag_slug(t) == *tag)
+                    .unwrap_or(tag)
+                    .to_string();
+                let base_url = format!("{}/tags/{tag}", collection.url());
+                (title, base_url, items)
+            }
+            None => (
+                collection.name.clone(),
+                collection.url(),
+                collection.items.iter().collect(),
+            ),
+        };
+
+        let per_page = collection.config.per_page.max(1);
+        let total_pages = items.len().div_ceil(per_page).max(1);
+        if route.page > total_pages {
+            return None;
+        }
+
+        let page_url = |page: usize| match page {
+            1 => base_url.clone(),
+            page => format!("{base_url}/page/{page}"),
+        };
+
+        Some(Self {
+            title,
+            page: route.page,
+            total_pages,
+            total_items: items.len(),
+            pages: items
+                .into_iter()
+                .skip((route.page - 1) * per_page)
+                .take(per_page)
+                .cloned()
+                .collect(),
+            previous: (route.page > 1).then(|| page_url(route.page - 1)),
+            next: (route.page < total_pages).then(|| page_url(route.page + 1)),
+        })
+    }
+
+    // used as `content` of the generated page, layouts can build their own from `paginator`
+    pub fn to_html(&self) -> String {
+        let mut html = format!("<h1>{}</h1>\n<ul>\n", escape_html(&self.title));
+        for item in &self.pages {
+            let title = item.front_matter.title.as_deref().unwrap_or_default();
+            let date = item
+                .front_matter
+                .date
+                .as_ref()
+                .map(|date| format!(" <time>{}</time>", escape_html(date)))
+                .unwrap_or_default();
+
+            html.push_str(&format!(
+                "<li><a href=\"{}\">{}</a>{date}</li>\n",
+                escape_html(&item.url),
+                escape_html(title),
+            ));
+        }
+        html.push_str("</ul>\n");
+
+        if self.previous.is_some() || self.next.is_some() {
+            html.push_str("<nav>");
+            if let Some(previous) = &self.previous {
+                html.push_str(&format!("<a href=\"{}\">Newer</a> ", escape_html(previous)));
+            }
+            if let Some(next) = &self.next {
+                html.push_str(&format!("<a href=\"{}\">Older</a>", escape_html(next)));
+            }
+            html.push_str("</nav>\n");
+        }
+
+        html
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssg::frontmatter::ListOrSingle;
+
+    fn site() -> Site {
+        toml::from_str(
+            r#"
+            [collections.posts]
+            per_page = 2
+
+            [collections.notes]
+            dir = "/misc/notes/"
+            tags = false
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn route(path: &str) -> Option<(String, Option<String>, usize)> {
+        Route::parse(&site(), path).map(|route| (route.collection, route.tag, route.page))
+    }
+
+    fn collection(items: &[(&str, &[&str])]) -> Collection {
+        let items = items
+            .iter()
+            .map(|(title, tags)| Item {
+                url: format!("/posts/{title}"),
+                path: PathBuf::from(format!("posts/{title}.md")),
+                front_matter: FrontMatter {
+                    title: Some(title.to_string()),
+                    tags: Some(ListOrSingle::List(
+                        tags.iter().map(|t| t.to_string()).collect(),
+                    )),
+                    ..Default::default()
+                },
+            })
+            .collect();
+
+        Collection {
+            name: "posts".to_string(),
+            dir: "posts".to_string(),
+            config: site().collections.remove("posts").unwrap(),
+            items,
+        }
+    }
+
+    fn titles(paginator: &Paginator) -> Vec<&str> {
+        paginator
+            .pages
+            .iter()
+            .map(|item| item.front_matter.title.as_deref().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parses_routes() {
+        let posts =
+            |tag: Option<&str>, page| Some(("posts".to_string(), tag.map(String::from), page));
+        assert_eq!(route("/posts"), posts(None, 1));
+        assert_eq!(route("/posts/"), posts(None, 1));
+        assert_eq!(route("/posts/page/3"), posts(None, 3));
+        assert_eq!(route("/posts/tags/rust"), posts(Some("rust"), 1));
+        assert_eq!(route("/posts/tags/rust/page/2"), posts(Some("rust"), 2));
+        assert_eq!(
+            route("/misc/notes/page/2"),
+            Some(("notes".to_string(), None, 2))
+        );
+    }
+
+    #[test]
+    fn rejects_other_routes() {
+        assert_eq!(route("/postsfoo"), None);
+        assert_eq!(route("/posts/hello"), None);
+        // the first page is only served without the suffix
+        assert_eq!(route("/posts/page/1"), None);
+        assert_eq!(route("/posts/page/0"), None);
+        assert_eq!(route("/posts/page/x"), None);
+        assert_eq!(route("/posts/tags/"), None);
+        assert_eq!(route("/posts/tags/a/b"), None);
+        assert_eq!(route("/misc/notes/tags/rust"), None);
+        assert_eq!(route("/notes"), None);
+    }
+
+    #[test]
+    fn slugs_tags() {
+        assert_eq!(tag_slug(" Rust Lang "), "rust-lang");
+        assert_eq!(tag_slug("C++ / C#"), "c-c");
+        assert_eq!(tag_slug("über"), "über");
+    }
+
+    #[test]
+    fn paginates() {
+        let collection = collection(&[("a", &[]), ("b", &[]), ("c", &[]), ("d", &[]), ("e", &[])]);
+        let page = |page| {
+            Paginator::new(
+                &collection,
+                &Route {
+                    collection: "posts".to_string(),
+                    tag: None,
+                    page,
+                },
+            )
+        };
+
+        let first = page(1).unwrap();
+        assert_eq!(titles(&first), ["a", "b"]);
+        assert_eq!((first.total_pages, first.total_items), (3, 5));
+        assert_eq!(
+            (first.previous, first.next.as_deref()),
+            (None, Some("/posts/page/2"))
+        );
+
+        let second = page(2).unwrap();
+        assert_eq!(titles(&second), ["c", "d"]);
+        assert_eq!(second.previous.as_deref(), Some("/posts"));
+        assert_eq!(second.next.as_deref(), Some("/posts/page/3"));
+
+        let last = page(3).unwrap();
+        assert_eq!(titles(&last), ["e"]);
+        assert_eq!(last.next, None);
+
+        assert!(page(4).is_none());
+    }
+
+    #[test]
+    fn paginates_tags() {
+        let collection =
+            collection(&[("a", &["Rust Lang"]), ("b", &["go"]), ("c", &["rust lang"])]);
+        let tag = |tag: &str| Route {
+            collection: "posts".to_string(),
+            tag: Some(tag.to_string()),
+            page: 1,
+        };
+
+        let paginator = Paginator::new(&collection, &tag("rust-lang")).unwrap();
+        assert_eq!(paginator.title, "Rust Lang");
+        assert_eq!(titles(&paginator), ["a", "c"]);
+        assert_eq!(paginator.next, None);
+        assert!(Paginator::new(&collection, &tag("python")).is_none());
+    }
+
+    #[test]
+    fn renders_empty_collections() {
+        let collection = collection(&[]);
+        let route = Route {
+            collection: "posts".to_string(),
+            tag: None,
+            page: 1,
+        };
+        let paginator = Paginator::new(&collection, &route).unwrap();
+        assert_eq!(paginator.total_pages, 1);
+        assert_eq!(paginator.to_html(), "<h1>posts</h1>\n<ul>\n</ul>\n");
+    }
+}
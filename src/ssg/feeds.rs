@@ -0,0 +1,392 @@
+use std::{path::Path, time::SystemTime};
+
+use eyre::Result;
+use time::{
+    format_description::well_known::{Rfc2822, Rfc3339},
+    macros::format_description,
+    Date, OffsetDateTime,
+};
+
+use super::{
+    collections::{self, Collection, Item},
+    frontmatter::FrontMatter,
+    markdown,
+    site::Site,
+    template::Files,
+};
+use crate::utils::escape_html;
+
+// generated unless the site has files with these names
+const ATOM_PATH: &str = "/feed.xml";
+const RSS_PATH: &str = "/rss.xml";
+const SITEMAP_PATH: &str = "/sitemap.xml";
+
+const ATOM_CONTENT_TYPE: &str = "application/atom+xml; charset=utf-8";
+const RSS_CONTENT_TYPE: &str = "application/rss+xml; charset=utf-8";
+const SITEMAP_CONTENT_TYPE: &str = "application/xml; charset=utf-8";
+
+// the limit of the sitemap protocol
+const MAX_SITEMAP_URLS: usize = 50_000;
+const MAX_SITEMAP_DEPTH: usize = 16;
+
+pub fn is_feed(path: &str) -> bool {
+    [ATOM_PATH, RSS_PATH, SITEMAP_PATH].contains(&path)
+}
+
+// returns the document and its content type, or None if the site has nothing to put in it
+pub fn render(
+    files: &Files,
+    site: &Site,
+    collections: &[Collection],
+    path: &str,
+    site_url: Option<&str>,
+) -> Result<Option<(String, &'static str)>> {
+    // absolute links need a url, which the site can set if it has more than one hostname
+    let base_url = site
+        .variable("url")
+        .or(site_url)
+        .unwrap_or_default()
+        .trim_end_matches('/');
+
+    if path == SITEMAP_PATH {
+        let sitemap = sitemap(files, site, collections, base_url)?;
+        return Ok(Some((sitemap, SITEMAP_CONTENT_TYPE)));
+    }
+
+    // without any collections, e.g. without a site file, `posts` is used
+    let default_posts;
+    let collections = match collections.is_empty() {
+        true => {
+            let posts = collections::default_posts(files, site)?;
+            if posts.items.is_empty() {
+                return Ok(None);
+            }
+            default_posts = [posts];
+            &default_posts[..]
+        }
+        false => collections,
+    };
+
+    let collection = match &site.feed.collection {
+        Some(name) => collections.iter().find(|c| c.name == *name),
+        None => collections
+            .iter()
+            .find(|c| c.name == "posts")
+            .or(collections.first()),
+    };
+    let Some(collection) = collection else {
+        return Ok(None);
+    };
+
+    let mut entries = Vec::new();
+    for item in collection.items.iter().take(site.feed.limit) {
+        let updated = item
+            .front_matter
+            .date
+            .as_deref()
+            .and_then(parse_date)
+            .or_else(|| files.modified(&item.path).map(OffsetDateTime::from))
+            .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+
+        let content = match files.read(&item.path)? {
+            Some(md) => {
+                let options = item.front_matter.markdown_options(site);
+                markdown::render(&md, &options).html
+            }
+            None => continue,
+        };
+
+        entries.push(Entry {
+            item,
+            url: format!("{base_url}{}", item.url),
+            updated,
+            content,
+        });
+    }
+
+    let feed = Feed {
+        title: site.variable("title").unwrap_or(&collection.name),
+        description: site.variable("description").unwrap_or_default(),
+        author: site.variable("author").or(site.variable("title")),
+        base_url,
+        updated: entries
+            .iter()
+            .map(|entry| entry.updated)
+            .max()
+            .unwrap_or(OffsetDateTime::UNIX_EPOCH),
+        entries,
+    };
+
+    Ok(Some(match path {
+        ATOM_PATH => (feed.atom(), ATOM_CONTENT_TYPE),
+        _ => (feed.rss(), RSS_CONTENT_TYPE),
+    }))
+}
+
+// `2024-01-31`, or a full RFC 3339 timestamp
+fn parse_date(date: &str) -> Option<OffsetDateTime> {
+    OffsetDateTime::parse(date, &Rfc3339).ok().or_else(|| {
+        Date::parse(date, format_description!("[year]-[month]-[day]"))
+            .ok()
+            .map(|date| date.midnight().assume_utc())
+    })
+}
+
+struct Entry<'a> {
+    item: &'a Item,
+    url: String,
+    updated: OffsetDateTime,
+    content: String,
+}
+
+struct Feed<'a> {
+    title: &'a str,
+    description: &'a str,
+    author: Option<&'a str>,
+    base_url: &'a str,
+    updated: OffsetDateTime,
+    entries: Vec<Entry<'a>>,
+}
+
+impl Feed<'_> {
+    fn atom(&self) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+        xml.push_str(&format!("<title>{}</title>\n", escape_html(self.title)));
+        if !self.description.is_empty() {
+            xml.push_str(&format!(
+                "<subtitle>{}</subtitle>\n",
+                escape_html(self.description)
+            ));
+        }
+        xml.push_str(&format!(
+            "<link href=\"{0}{ATOM_PATH}\" rel=\"self\"/>\n<link href=\"{0}/\"/>\n<id>{0}/</id>\n",
+            escape_html(self.base_url)
+        ));
+        xml.push_str(&format!("<updated>{}</updated>\n", rfc3339(self.updated)));
+        if let Some(author) = self.author {
+            xml.push_str(&format!(
+                "<author><name>{}</name></author>\n",
+                escape_html(author)
+            ));
+        }
+
+        for entry in &self.entries {
+            let front_matter = &entry.item.front_matter;
+            xml.push_str("<entry>\n");
+            xml.push_str(&format!(
+                "<title>{}</title>\n<link href=\"{1}\"/>\n<id>{1}</id>\n<updated>{2}</updated>\n",
+                escape_html(front_matter.title.as_deref().unwrap_or_default()),
+                escape_html(&entry.url),
+                rfc3339(entry.updated),
+            ));
+            if let Some(description) = &front_matter.description {
+                xml.push_str(&format!(
+                    "<summary>{}</summary>\n",
+                    escape_html(description)
+                ));
+            }
+            xml.push_str(&format!(
+                "<content type=\"html\">{}</content>\n</entry>\n",
+                escape_html(&entry.content)
+            ));
+        }
+
+        xml.push_str("</feed>\n");
+        xml
+    }
+
+    fn rss(&self) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        xml.push_str(
+            "<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n<channel>\n",
+        );
+        xml.push_str(&format!(
+            "<title>{}</title>\n<link>{}/</link>\n<description>{}</description>\n",
+            escape_html(self.title),
+            escape_html(self.base_url),
+            escape_html(self.description),
+        ));
+        xml.push_str(&format!(
+            "<atom:link href=\"{}{RSS_PATH}\" rel=\"self\" type=\"application/rss+xml\"/>\n",
+            escape_html(self.base_url)
+        ));
+        xml.push_str(&format!(
+            "<lastBuildDate>{}</lastBuildDate>\n",
+            rfc2822(self.updated)
+        ));
+
+        for entry in &self.entries {
+            let front_matter = &entry.item.front_matter;
+            xml.push_str(&format!(
+                "<item>\n<title>{}</title>\n<link>{1}</link>\n<guid isPermaLink=\"true\">{1}</guid>\n<pubDate>{2}</pubDate>\n<description>{3}</description>\n</item>\n",
+                escape_html(front_matter.title.as_deref().unwrap_or_default()),
+                escape_html(&entry.url),
+                rfc2822(entry.updated),
+                escape_html(&entry.content),
+            ));
+        }
+
+        xml.push_str("</channel>\n</rss>\n");
+        xml
+    }
+}
+
+fn rfc3339(date: OffsetDateTime) -> String {
+    date.format(&Rfc3339).unwrap_or_default()
+}
+
+fn rfc2822(date: OffsetDateTime) -> String {
+    date.format(&Rfc2822).unwrap_or_default()
+}
+
+fn sitemap(
+    files: &Files,
+    site: &Site,
+    collections: &[Collection],
+    base_url: &str,
+) -> Result<String> {
+    let mut urls = Vec::new();
+    collect_pages(files, site, Path::new(""), 0, &mut urls)?;
+
+    // generated index pages of collections without an index file
+    for collection in collections {
+        let url = collection.url();
+        if !urls.iter().any(|(existing, _)| *existing == url) {
+            urls.push((url, None));
+        }
+
+        for item in &collection.items {
+            urls.push((item.url.clone(), files.modified(&item.path)));
+        }
+    }
+
+    // `page.html` and `page.md` are the same page
+    urls.sort();
+    urls.dedup_by(|a, b| a.0 == b.0);
+    urls.truncate(MAX_SITEMAP_URLS);
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+    for (url, modified) in urls {
+        xml.push_str(&format!(
+            "<url><loc>{}</loc>",
+            escape_html(&format!("{base_url}{url}"))
+        ));
+        if let Some(modified) = modified {
+            let date = OffsetDateTime::from(modified).date();
+            xml.push_str(&format!("<lastmod>{date}</lastmod>"));
+        }
+        xml.push_str("</url>\n");
+    }
+    xml.push_str("</urlset>\n");
+
+    Ok(xml)
+}
+
+// all `.html` and `.md` files, except for hidden ones, those starting with `_`, drafts
+// and collection items, which are listed with their own urls
+fn collect_pages(
+    files: &Files,
+    site: &Site,
+    dir: &Path,
+    depth: usize,
+    urls: &mut Vec<(String, Option<SystemTime>)>,
+) -> Result<()> {
+    for path in files.read_dir(dir)? {
+        if urls.len() >= MAX_SITEMAP_URLS {
+            break;
+        }
+
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if name.starts_with(['.', '_']) {
+            continue;
+        }
+
+        let Ok(meta) = std::fs::symlink_metadata(&path) else {
+            continue;
+        };
+
+        if meta.is_dir() && depth < MAX_SITEMAP_DEPTH {
+            collect_pages(files, site, &dir.join(name), depth + 1, urls)?;
+            continue;
+        }
+
+        let Some((stem, "html" | "md")) = name.rsplit_once('.') else {
+            continue;
+        };
+        if !meta.is_file() || stem == "404" {
+            continue;
+        }
+
+        let relative_path = dir.join(name);
+        if name.ends_with(".md") {
+            if collections::is_item(site, &relative_path) {
+                continue;
+            }
+
+            let front_matter = files.read(&path)?.map(|md| FrontMatter::from_md(&md));
+            if front_matter.is_some_and(|page| page.draft && !site.preview) {
+                continue;
+            }
+        }
+
+        let url = FrontMatter::default().url(&relative_path, false);
+        urls.push((url, files.modified(&path)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feeds_without_site_file_list_posts() {
+        let dir = std::env::temp_dir().join(format!("dawdle-feeds-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("posts")).unwrap();
+        std::fs::write(
+            dir.join("posts/hello.md"),
+            "---\nslug: hi\ndate: 2024-01-31\n---\n# Hello",
+        )
+        .unwrap();
+
+        let files = Files::new(&dir);
+        let site = Site::load(&files).unwrap();
+        let collections = collections::load(&files, &site).unwrap();
+        let feed = render(
+            &files,
+            &site,
+            &collections,
+            ATOM_PATH,
+            Some("https://a.example"),
+        );
+        let sitemap = render(&files, &site, &collections, SITEMAP_PATH, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let (feed, content_type) = feed.unwrap().unwrap();
+        assert_eq!(content_type, ATOM_CONTENT_TYPE);
+        // not a real collection, so the page is served where the file is
+        assert!(feed.contains("https://a.example/posts/hello"));
+        assert!(!feed.contains("/hi"));
+
+        let (sitemap, _) = sitemap.unwrap().unwrap();
+        assert!(sitemap.contains("/posts/hello"));
+    }
+
+    #[test]
+    fn no_feed_without_posts() {
+        let dir = std::env::temp_dir().join(format!("dawdle-no-feed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let files = Files::new(&dir);
+        let site = Site::load(&files).unwrap();
+        let feed = render(&files, &site, &[], RSS_PATH, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(feed.unwrap().is_none());
+    }
+}
@@ -1,4 +1,18 @@
-#[derive(Debug, serde::Deserialize, Default)]
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path},
+};
+
+use percent_encoding::utf8_percent_encode;
+
+use super::markdown::MarkdownOptions;
+use crate::utils::{escape_html, PATH_SEGMENT};
+
+// YAML front matter is delimited by `---`, TOML front matter by `+++`
+const YAML_DELIMITER: &str = "---";
+const TOML_DELIMITER: &str = "+++";
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Default, Clone)]
 pub struct FrontMatter {
     #[serde(default)]
     pub title: Option<String>,
@@ -20,9 +34,42 @@ pub struct FrontMatter {
 
     #[serde(default)]
     pub layout: Option<String>,
+
+    #[serde(default)]
+    pub tags: Option<ListOrSingle<String>>,
+
+    // drafts are only served if the site is previewed
+    #[serde(default)]
+    pub draft: bool,
+
+    // replaces the file name in the url of collection items
+    #[serde(default)]
+    pub slug: Option<String>,
+
+    // the full url of a collection item, e.g. `/2024/hello`
+    #[serde(default)]
+    pub permalink: Option<String>,
+
+    #[serde(default)]
+    pub author: Option<String>,
+
+    // used for link previews
+    #[serde(default)]
+    pub image: Option<String>,
+
+    #[serde(default)]
+    pub lang: Option<String>,
+
+    // overrides the markdown options of the site
+    #[serde(default)]
+    pub markdown: Option<MarkdownOptions>,
+
+    // everything else, available to templates as `page.<key>`
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_yml::Value>,
 }
 
-#[derive(Debug, serde::Deserialize, Clone)]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(untagged)]
 pub enum ListOrSingle<T> {
     List(Vec<T>),
@@ -67,20 +114,51 @@ fn merge_property(
 impl FrontMatter {
     pub fn from_md(input: &str) -> Self {
         let mut lines = input.lines();
+        let Some(delimiter) = lines
+            .next()
+            .map(str::trim_end)
+            .filter(|line| [YAML_DELIMITER, TOML_DELIMITER].contains(line))
+        else {
+            return Self::default();
+        };
+
         let mut front_matter = String::new();
+        for line in lines {
+            if line.trim_end() == delimiter {
+                break;
+            }
+            front_matter.push_str(line);
+            front_matter.push('\n');
+        }
 
-        if let Some(line) = lines.next() {
-            if line == "---" {
-                for line in lines {
-                    if line == "---" {
-                        break;
-                    }
-                    front_matter.push_str(line);
-                    front_matter.push('\n');
-                }
+        match delimiter {
+            TOML_DELIMITER => from_toml(&front_matter).unwrap_or_default(),
+            _ => serde_yml::from_str::<FrontMatter>(&front_matter).unwrap_or_default(),
+        }
+    }
+
+    // the input without its front matter, which is replaced by empty lines to keep line numbers intact
+    pub fn strip(input: &str) -> String {
+        let mut lines = input.split_inclusive('\n');
+        let Some(delimiter) = lines
+            .next()
+            .map(str::trim_end)
+            .filter(|line| [YAML_DELIMITER, TOML_DELIMITER].contains(line))
+        else {
+            return input.to_string();
+        };
+
+        let mut stripped = String::from("\n");
+        for line in lines.by_ref() {
+            stripped.push('\n');
+            if line.trim_end() == delimiter {
+                stripped.extend(lines);
+                return stripped;
             }
         }
-        serde_yml::from_str::<FrontMatter>(&front_matter).unwrap_or_default()
+
+        // no closing delimiter, so it's not front matter after all
+        input.to_string()
     }
 
     pub fn merge(&mut self, other: &FrontMatter) {
@@ -90,44 +168,293 @@ impl FrontMatter {
         self.description = self.description.clone().or(other.description.clone());
         self.date = self.date.clone().or(other.date.clone());
         self.theme = self.theme.clone().or(other.theme.clone());
+        self.author = self.author.clone().or(other.author.clone());
+        self.image = self.image.clone().or(other.image.clone());
+        self.lang = self.lang.clone().or(other.lang.clone());
     }
 
-    pub fn html_head(&self) -> String {
-        let mut head = String::new();
+    pub fn markdown_options(&self, site: &super::site::Site) -> MarkdownOptions {
+        match &self.markdown {
+            Some(options) => options.merge(&site.markdown),
+            None => site.markdown.clone(),
+        }
+    }
+
+    // the url of a page at `path` (relative to the site root), slugs and permalinks are
+    // only used by collections, everything else is served where the file is
+    pub fn url(&self, path: &Path, in_collection: bool) -> String {
+        let permalink = self
+            .permalink
+            .as_deref()
+            .map(|permalink| permalink.trim_matches('/'))
+            .filter(|permalink| is_plain_path(permalink));
+        if let Some(permalink) = permalink.filter(|_| in_collection) {
+            let mut url = String::new();
+            for segment in permalink.split('/').filter(|s| !s.is_empty()) {
+                url.push('/');
+                url.extend(utf8_percent_encode(segment, PATH_SEGMENT));
+            }
+            return url;
+        }
 
-        if let Some(theme) = &self.theme {
-            if let Some(url) = super::themes::THEMES.iter().find(|(name, _)| name == theme) {
-                head.push_str(&format!("<link rel=\"stylesheet\" href=\"{}\">\n", url.1));
+        let mut url = String::new();
+        if let Some(parent) = path.parent() {
+            for segment in parent.iter().filter_map(|s| s.to_str()) {
+                url.push('/');
+                url.extend(utf8_percent_encode(segment, PATH_SEGMENT));
             }
         }
 
+        let stem = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or_default();
+        let slug = self
+            .slug
+            .as_deref()
+            .map(|slug| slug.trim_matches('/'))
+            .filter(|slug| is_plain_path(slug));
+        let name = match slug {
+            Some(slug) if in_collection => slug,
+            _ => stem,
+        };
+        if name != "index" {
+            url.push('/');
+            url.extend(utf8_percent_encode(name, PATH_SEGMENT));
+        }
+
+        if url.is_empty() {
+            url.push('/');
+        }
+        url
+    }
+
+    // `base_url` is the absolute url of the site if known, `url` the path of the page,
+    // the stylesheet of the theme is added separately
+    pub fn html_head(&self, base_url: &str, url: &str) -> String {
+        let mut head = String::new();
+
         if let Some(css) = &self.css {
             for c in css.as_list() {
-                head.push_str(&format!("<link rel=\"stylesheet\" href=\"{}\">\n", c));
+                head.push_str(&format!(
+                    "<link rel=\"stylesheet\" href=\"{}\">\n",
+                    escape_html(c)
+                ));
             }
         }
 
+        // raw html on purpose
         if let Some(heads) = &self.head {
             for h in heads.as_list() {
                 head.push_str(h);
             }
         }
 
+        let mut meta = |attribute: &str, name: &str, content: &str| {
+            head.push_str(&format!(
+                "<meta {attribute}=\"{name}\" content=\"{}\">\n",
+                escape_html(content)
+            ));
+        };
+
+        let canonical = format!("{base_url}{url}");
+        let image = self
+            .image
+            .as_ref()
+            .map(|image| match image.starts_with('/') {
+                true => format!("{base_url}{image}"),
+                false => image.clone(),
+            });
+
+        if let Some(description) = &self.description {
+            meta("name", "description", description);
+        }
+        if let Some(author) = &self.author {
+            meta("name", "author", author);
+        }
+        if let Some(date) = &self.date {
+            meta("name", "date", date);
+        }
+
+        // link previews
         if let Some(title) = &self.title {
-            head.push_str(&format!("<title>{}</title>\n", title));
+            meta("property", "og:title", title);
+        }
+        if let Some(description) = &self.description {
+            meta("property", "og:description", description);
+        }
+        let kind = match self.date {
+            Some(_) => "article",
+            None => "website",
+        };
+        meta("property", "og:type", kind);
+        // has to be absolute
+        if !base_url.is_empty() {
+            meta("property", "og:url", &canonical);
+        }
+        if let Some(image) = &image {
+            meta("property", "og:image", image);
+        }
+        if let Some(lang) = &self.lang {
+            meta("property", "og:locale", &lang.replace('-', "_"));
+        }
+        if let Some(author) = &self.author {
+            meta("property", "article:author", author);
+        }
+        if let Some(tags) = &self.tags {
+            for tag in tags.as_list() {
+                meta("property", "article:tag", tag);
+            }
         }
 
+        let card = match image {
+            Some(_) => "summary_large_image",
+            None => "summary",
+        };
+        meta("name", "twitter:card", card);
+        if let Some(title) = &self.title {
+            meta("name", "twitter:title", title);
+        }
         if let Some(description) = &self.description {
-            head.push_str(&format!(
-                "<meta name=\"description\" content=\"{}\">\n",
-                description
-            ));
+            meta("name", "twitter:description", description);
+        }
+        if let Some(image) = &image {
+            meta("name", "twitter:image", image);
         }
 
-        if let Some(date) = &self.date {
-            head.push_str(&format!("<meta name=\"date\" content=\"{}\">\n", date));
+        head.push_str(&format!(
+            "<link rel=\"canonical\" href=\"{}\">\n",
+            escape_html(&canonical)
+        ));
+
+        if let Some(title) = &self.title {
+            head.push_str(&format!("<title>{}</title>\n", escape_html(title)));
         }
 
         head
     }
 }
+
+// toml dates like `date = 2024-01-31` are kept as strings, like in YAML
+fn from_toml(input: &str) -> Result<FrontMatter, toml::de::Error> {
+    fn dates_to_strings(value: &mut toml::Value) {
+        match value {
+            toml::Value::Datetime(date) => *value = toml::Value::String(date.to_string()),
+            toml::Value::Array(values) => values.iter_mut().for_each(dates_to_strings),
+            toml::Value::Table(table) => table
+                .iter_mut()
+                .for_each(|(_, value)| dates_to_strings(value)),
+            _ => {}
+        }
+    }
+
+    let mut value = toml::from_str::<toml::Value>(input)?;
+    dates_to_strings(&mut value);
+    value.try_into()
+}
+
+// slugs and permalinks are also used as paths when the site is exported,
+// so they can't contain `..` or anything else that could leave the output directory
+fn is_plain_path(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn front_matter(slug: Option<&str>, permalink: Option<&str>) -> FrontMatter {
+        FrontMatter {
+            slug: slug.map(String::from),
+            permalink: permalink.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn builds_urls_from_paths() {
+        let page = FrontMatter::default();
+        assert_eq!(page.url(Path::new("index.md"), false), "/");
+        assert_eq!(page.url(Path::new("about.md"), false), "/about");
+        assert_eq!(page.url(Path::new("docs/index.md"), false), "/docs");
+        assert_eq!(
+            page.url(Path::new("my docs/a b.md"), false),
+            "/my%20docs/a%20b"
+        );
+    }
+
+    #[test]
+    fn uses_slugs_and_permalinks_in_collections() {
+        let path = Path::new("posts/2024-01-01-hello.md");
+        let slug = front_matter(Some("/hello/"), None);
+        assert_eq!(slug.url(path, true), "/posts/hello");
+        assert_eq!(slug.url(path, false), "/posts/2024-01-01-hello");
+
+        let permalink = front_matter(Some("hello"), Some("/archive/hello/"));
+        assert_eq!(permalink.url(path, true), "/archive/hello");
+        assert_eq!(permalink.url(path, false), "/posts/2024-01-01-hello");
+    }
+
+    #[test]
+    fn ignores_slugs_and_permalinks_leaving_the_site() {
+        let path = Path::new("posts/hello.md");
+        assert_eq!(
+            front_matter(Some("../../x"), None).url(path, true),
+            "/posts/hello"
+        );
+        assert_eq!(
+            front_matter(Some("hi there"), None).url(path, true),
+            "/posts/hi%20there"
+        );
+        assert_eq!(
+            front_matter(None, Some("/../etc/")).url(path, true),
+            "/posts/hello"
+        );
+        assert_eq!(
+            front_matter(None, Some("a b/c")).url(path, true),
+            "/a%20b/c"
+        );
+    }
+
+    #[test]
+    fn parses_yaml_and_toml() {
+        let yaml =
+            FrontMatter::from_md("---\ntitle: Hi\ndraft: true\ntags: [a, b]\nmood: ok\n---\n# Hi");
+        assert_eq!(yaml.title.as_deref(), Some("Hi"));
+        assert!(yaml.draft);
+        assert_eq!(yaml.tags.unwrap().as_list(), ["a", "b"]);
+        assert!(yaml.extra.contains_key("mood"));
+
+        let toml = FrontMatter::from_md("+++\ntitle = \"Hi\"\ndate = 2024-01-31\n+++\n# Hi");
+        assert_eq!(toml.title.as_deref(), Some("Hi"));
+        assert_eq!(toml.date.as_deref(), Some("2024-01-31"));
+
+        assert!(FrontMatter::from_md("# no front matter").title.is_none());
+    }
+
+    #[test]
+    fn strips_front_matter_keeping_lines() {
+        assert_eq!(
+            FrontMatter::strip("---\ntitle: Hi\n---\n# Hi\n"),
+            "\n\n\n# Hi\n"
+        );
+        assert_eq!(FrontMatter::strip("# Hi\n"), "# Hi\n");
+        assert_eq!(FrontMatter::strip("---\nunclosed\n"), "---\nunclosed\n");
+    }
+
+    #[test]
+    fn adds_link_preview_tags() {
+        let page = FrontMatter {
+            title: Some("A \"quote\"".to_string()),
+            image: Some("/cover.png".to_string()),
+            ..Default::default()
+        };
+        let head = page.html_head("https://example.com", "/post");
+        assert!(head.contains("<link rel=\"canonical\" href=\"https://example.com/post\">"));
+        assert!(head.contains("content=\"https://example.com/cover.png\""));
+        assert!(head.contains("<title>A &quot;quote&quot;</title>"));
+    }
+}
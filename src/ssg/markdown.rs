@@ -1,26 +1,376 @@
+use std::sync::{Arc, LazyLock};
+
 use comrak::{
-    plugins::syntect::SyntectAdapterBuilder, ExtensionOptionsBuilder, ParseOptionsBuilder, Plugins,
-    RenderOptionsBuilder,
+    html::Anchorizer,
+    nodes::{AstNode, NodeValue},
+    plugins::syntect::{SyntectAdapter, SyntectAdapterBuilder},
+    Arena, ExtensionOptionsBuilder, ParseOptionsBuilder, Plugins, RenderOptionsBuilder,
 };
+use dashmap::DashMap;
+use latex2mathml::DisplayStyle;
+use serde::{Deserialize, Serialize};
+
+use super::{frontmatter::FrontMatter, shortcodes};
+use crate::utils::escape_html;
+
+const DEFAULT_HIGHLIGHT_THEME: &str = "base16-ocean.dark";
+
+// the themes bundled with syntect, `css` uses classes instead of inline styles
+const HIGHLIGHT_THEMES: &[&str] = &[
+    "base16-ocean.dark",
+    "base16-eighties.dark",
+    "base16-mocha.dark",
+    "base16-ocean.light",
+    "InspiredGitHub",
+    "Solarized (dark)",
+    "Solarized (light)",
+    "css",
+];
+
+// replaced by the table of contents
+const TOC_MARKER: &str = "<p>[[toc]]</p>";
+
+// marks where html is inserted after rendering, an object replacement character
+const PLACEHOLDER: char = '\u{FFFC}';
+
+// loading the syntaxes and themes is expensive, so this is only done once per theme
+static SYNTAX_HIGHLIGHTERS: LazyLock<DashMap<&'static str, Arc<SyntectAdapter>>> =
+    LazyLock::new(DashMap::new);
+
+// set in `_site.toml` under `[markdown]` or in front matter under `markdown:`,
+// where pages override the site
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MarkdownOptions {
+    /// GitHub-style tables
+    pub tables: Option<bool>,
+
+    /// `~~strikethrough~~`
+    pub strikethrough: Option<bool>,
+
+    /// `- [x] done`
+    pub tasklists: Option<bool>,
+
+    /// `[^1]` references and `[^1]: ...` footnotes
+    pub footnotes: Option<bool>,
+
+    /// Links for plain URLs and email addresses
+    pub autolinks: Option<bool>,
+
+    /// `e = mc^2^`
+    pub superscript: Option<bool>,
+
+    /// Term and details lists
+    pub description_lists: Option<bool>,
+
+    /// `id`s and `#` links for headings
+    pub anchors: Option<bool>,
+
+    /// Table of contents, placed at `[[toc]]` and available to layouts as `toc`
+    pub toc: Option<bool>,
+
+    /// Theme for code blocks, see `HIGHLIGHT_THEMES`
+    pub highlight_theme: Option<String>,
+
+    /// Strip raw HTML and unsafe links, e.g. for content written by visitors
+    pub safe: Option<bool>,
+
+    /// `$inline$` and `$$display$$` math, rendered to MathML
+    pub math: Option<bool>,
+
+    /// `mermaid` code blocks, kept as `<pre class="mermaid">` for the theme to render
+    /// in the browser, e.g. by loading mermaid.js
+    pub diagrams: Option<bool>,
+
+    /// `{{< youtube id >}}` and other shortcodes, see `shortcodes.rs` (on by default)
+    pub shortcodes: Option<bool>,
+}
+
+impl MarkdownOptions {
+    // options that aren't set are taken from `other`
+    pub fn merge(&self, other: &MarkdownOptions) -> MarkdownOptions {
+        MarkdownOptions {
+            tables: self.tables.or(other.tables),
+            strikethrough: self.strikethrough.or(other.strikethrough),
+            tasklists: self.tasklists.or(other.tasklists),
+            footnotes: self.footnotes.or(other.footnotes),
+            autolinks: self.autolinks.or(other.autolinks),
+            superscript: self.superscript.or(other.superscript),
+            description_lists: self.description_lists.or(other.description_lists),
+            anchors: self.anchors.or(other.anchors),
+            toc: self.toc.or(other.toc),
+            highlight_theme: self
+                .highlight_theme
+                .clone()
+                .or(other.highlight_theme.clone()),
+            safe: self.safe.or(other.safe),
+            math: self.math.or(other.math),
+            diagrams: self.diagrams.or(other.diagrams),
+            shortcodes: self.shortcodes.or(other.shortcodes),
+        }
+    }
+}
+
+pub struct Markdown {
+    pub html: String,
+    pub toc: Option<String>,
+}
 
 pub fn md_to_html(buf: &str) -> String {
-    let options = comrak::ComrakOptions {
+    render(buf, &MarkdownOptions::default()).html
+}
+
+// for content written by visitors, e.g. chat messages
+pub fn safe_md_to_html(buf: &str) -> String {
+    let options = MarkdownOptions {
+        strikethrough: Some(true),
+        autolinks: Some(true),
+        safe: Some(true),
+        shortcodes: Some(false),
+        ..Default::default()
+    };
+    render(buf, &options).html
+}
+
+pub fn render(buf: &str, options: &MarkdownOptions) -> Markdown {
+    let enabled = |option: Option<bool>| option.unwrap_or(false);
+    let toc = enabled(options.toc);
+    // shortcodes, math and diagrams are added as raw html, which safe mode has to keep out
+    let safe = enabled(options.safe);
+
+    let comrak_options = comrak::ComrakOptions {
         parse: ParseOptionsBuilder::default().build().unwrap(),
         render: RenderOptionsBuilder::default()
-            .unsafe_(true)
+            .unsafe_(!safe)
             .build()
             .unwrap(),
         extension: ExtensionOptionsBuilder::default()
-            .front_matter_delimiter(Some("---".to_string()))
+            .table(enabled(options.tables))
+            .strikethrough(enabled(options.strikethrough))
+            .tasklist(enabled(options.tasklists))
+            .footnotes(enabled(options.footnotes))
+            .autolink(enabled(options.autolinks))
+            .superscript(enabled(options.superscript))
+            .description_lists(enabled(options.description_lists))
+            .math_dollars(enabled(options.math))
+            // the table of contents links to the headings
+            .header_ids((enabled(options.anchors) || toc).then(String::new))
             .build()
             .unwrap(),
     };
 
+    let highlighter = syntax_highlighter(options.highlight_theme.as_deref());
     let mut plugins = Plugins::default();
-    let syntax_highlighter = SyntectAdapterBuilder::default()
-        .theme("base16-ocean.dark")
-        .build();
-    plugins.render.codefence_syntax_highlighter = Some(&syntax_highlighter);
+    plugins.render.codefence_syntax_highlighter = Some(&*highlighter);
+
+    // front matter can be YAML or TOML, which comrak can't both skip
+    let mut md = FrontMatter::strip(buf);
+    let mut replacements = Replacements::default();
+    if options.shortcodes.unwrap_or(true) && !safe {
+        md = shortcodes::expand(&md, |html| replacements.placeholder(html));
+    }
+
+    let arena = Arena::new();
+    let root = comrak::parse_document(&arena, &md, &comrak_options);
+    if !safe {
+        transform(&arena, root, options, &mut replacements);
+    }
+
+    // writing to a Vec can't fail
+    let mut html = Vec::new();
+    let _ = comrak::format_html_with_plugins(root, &comrak_options, &mut html, &plugins);
+    let mut html = replacements.apply(&String::from_utf8_lossy(&html));
+
+    let toc = toc.then(|| replacements.apply(&table_of_contents(root)));
+    if let Some(toc) = &toc {
+        html = html.replace(TOC_MARKER, toc);
+    }
+
+    Markdown { html, toc }
+}
+
+// html that can't be part of the markdown tree, so it's added after rendering in place
+// of a placeholder, which is never used in safe mode
+#[derive(Default)]
+struct Replacements {
+    html: Vec<String>,
+}
+
+impl Replacements {
+    fn placeholder(&mut self, html: String) -> String {
+        self.html.push(html);
+        format!("{PLACEHOLDER}{}{PLACEHOLDER}", self.html.len() - 1)
+    }
+
+    fn apply(&self, rendered: &str) -> String {
+        let mut rendered = rendered.to_string();
+        for (i, html) in self.html.iter().enumerate() {
+            let placeholder = format!("{PLACEHOLDER}{i}{PLACEHOLDER}");
+            // blocks shouldn't end up inside of a paragraph
+            rendered = rendered
+                .replace(&format!("<p>{placeholder}</p>"), html)
+                .replace(&placeholder, html);
+        }
+        rendered
+    }
+}
+
+// math and diagrams
+fn transform<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    root: &'a AstNode<'a>,
+    options: &MarkdownOptions,
+    replacements: &mut Replacements,
+) {
+    let diagrams = options.diagrams.unwrap_or(false);
+
+    // collected first, the tree can't be changed while it's traversed
+    let nodes = root.descendants().collect::<Vec<_>>();
+    for node in nodes {
+        let value = node.data.borrow().value.clone();
+        match value {
+            NodeValue::Math(math) => {
+                let style = match math.display_math {
+                    true => DisplayStyle::Block,
+                    false => DisplayStyle::Inline,
+                };
+                let html = match latex2mathml::latex_to_mathml(&math.literal, style) {
+                    Ok(mathml) => mathml,
+                    Err(err) => format!(
+                        "<span class=\"math-error\" style=\"color:red\">{}: {}</span>",
+                        escape_html(&err.to_string()),
+                        escape_html(&math.literal)
+                    ),
+                };
+                node.data.borrow_mut().value = NodeValue::Text(replacements.placeholder(html));
+            }
+            NodeValue::CodeBlock(code) if diagrams && code.info.trim() == "mermaid" => {
+                let html = format!(
+                    "<pre class=\"mermaid\">{}</pre>",
+                    escape_html(&code.literal)
+                );
+
+                // a paragraph with only the placeholder, which is replaced as a whole
+                let placeholder = NodeValue::Text(replacements.placeholder(html));
+                let placeholder = arena.alloc(placeholder.into());
+                node.data.borrow_mut().value = NodeValue::Paragraph;
+                node.append(placeholder);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn syntax_highlighter(theme: Option<&str>) -> Arc<SyntectAdapter> {
+    let theme = theme
+        .and_then(|theme| HIGHLIGHT_THEMES.iter().find(|t| **t == theme))
+        .copied()
+        .unwrap_or(DEFAULT_HIGHLIGHT_THEME);
+
+    SYNTAX_HIGHLIGHTERS
+        .entry(theme)
+        .or_insert_with(|| {
+            let builder = SyntectAdapterBuilder::default();
+            Arc::new(match theme {
+                "css" => builder.css().build(),
+                theme => builder.theme(theme).build(),
+            })
+        })
+        .clone()
+}
+
+// nested lists of links to the headings, using the same ids as comrak
+fn table_of_contents<'a>(root: &'a AstNode<'a>) -> String {
+    let mut anchorizer = Anchorizer::new();
+    let mut html = String::from("<nav class=\"toc\">\n");
+    let mut levels: Vec<u8> = Vec::new();
+
+    for node in root.descendants() {
+        let NodeValue::Heading(heading) = node.data.borrow().value else {
+            continue;
+        };
+
+        let mut text = String::new();
+        collect_text(node, &mut text);
+        let id = anchorizer.anchorize(text.clone());
+
+        // close deeper lists, then open one if this heading is deeper than the last
+        while levels.last().is_some_and(|level| *level > heading.level) {
+            levels.pop();
+            html.push_str("</li>\n</ul>\n");
+        }
+        match levels.last() {
+            Some(level) if *level == heading.level => html.push_str("</li>\n"),
+            _ => {
+                levels.push(heading.level);
+                html.push_str("<ul>\n");
+            }
+        }
+
+        html.push_str(&format!(
+            "<li><a href=\"#{}\">{}</a>",
+            escape_html(&id),
+            escape_html(&text)
+        ));
+    }
+
+    for _ in levels {
+        html.push_str("</li>\n</ul>\n");
+    }
+    html.push_str("</nav>\n");
+    html
+}
+
+fn collect_text<'a>(node: &'a AstNode<'a>, output: &mut String) {
+    match &node.data.borrow().value {
+        NodeValue::Text(literal) => output.push_str(literal),
+        NodeValue::Code(code) => output.push_str(&code.literal),
+        NodeValue::LineBreak | NodeValue::SoftBreak => output.push(' '),
+        _ => {
+            for child in node.children() {
+                collect_text(child, output);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_mode_drops_raw_html() {
+        let options = MarkdownOptions {
+            safe: Some(true),
+            math: Some(true),
+            diagrams: Some(true),
+            ..Default::default()
+        };
+        let md = "<script>alert(1)</script>\n\n{{< youtube abc >}}\n\n$x < y$\n\n```mermaid\ngraph TD\n```\n";
+        let html = render(md, &options).html;
+        assert!(!html.contains("<script>"));
+        assert!(!html.contains("<iframe"));
+        assert!(!html.contains("<math"));
+        assert!(!html.contains("class=\"mermaid\""));
+        assert!(!html.contains(PLACEHOLDER));
+    }
+
+    #[test]
+    fn renders_extensions_outside_of_safe_mode() {
+        let options = MarkdownOptions {
+            math: Some(true),
+            diagrams: Some(true),
+            ..Default::default()
+        };
+        let html = render("$x$\n\n```mermaid\ngraph TD\n```\n", &options).html;
+        assert!(html.contains("<math"));
+        assert!(html.contains("<pre class=\"mermaid\">graph TD"));
+    }
 
-    comrak::markdown_to_html_with_plugins(buf, &options, &plugins)
+    #[test]
+    fn safe_md_to_html_renders_markdown() {
+        let html = safe_md_to_html("**hi** <b>there</b>");
+        assert!(html.contains("<strong>hi</strong>"));
+        assert!(!html.contains("<b>"));
+    }
 }
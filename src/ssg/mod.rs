@@ -1,50 +1,281 @@
-use axum::{
-    body::Body,
-    http::Response,
-    response::{Html, IntoResponse},
-};
-use eyre::Result;
+use crate::utils::escape_html;
+use axum::body::Bytes;
+use eyre::{eyre, Result};
 use frontmatter::FrontMatter;
-use std::path::PathBuf;
-use tokio::io::AsyncReadExt;
+use minijinja::{context, Value};
+use site::Site;
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
+pub mod build;
+mod cache;
+mod collections;
+mod feeds;
 mod frontmatter;
 mod markdown;
-mod themes;
-
-const DEFAULT_HTML: &str = r#"<!DOCTYPE html><html><head><meta charset="utf-8">{{head}}</head><body>{{content}}</body></html>"#;
-
-pub async fn render(base_path: PathBuf, file: tokio::fs::File) -> Result<Response<Body>> {
-    let md = read_file(file).await?;
-    let mut front_matter = FrontMatter::from_md(&md);
-    let mut content = markdown::md_to_html(&md);
-
-    if let Some(layout) = front_matter.layout.clone() {
-        let layout = layout.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "");
-        let layout_path = base_path.join(format!("./_layouts/{}.md", layout));
-        if layout_path.exists() {
-            let layout_file = tokio::fs::File::open(layout_path).await?;
-            let layout_md = read_file(layout_file).await?;
-            let layout_front_matter = FrontMatter::from_md(&layout_md);
-            let layout_content = markdown::md_to_html(&layout_md);
-            if layout_content.contains("{{content}}") {
-                content = layout_content.replace("{{content}}", &content);
-            } else {
-                content = layout_content + &content;
-            }
-            front_matter.merge(&layout_front_matter);
-        }
+mod shortcodes;
+mod site;
+mod template;
+pub mod themes;
+
+pub use markdown::safe_md_to_html;
+pub use template::TemplateError;
+
+const HTML_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+
+#[derive(Clone)]
+pub struct Page {
+    pub body: Bytes,
+    pub content_type: &'static str,
+    // latest modification of any file the page was rendered from
+    pub modified: Option<SystemTime>,
+}
+
+struct Rendered {
+    body: String,
+    content_type: &'static str,
+    dependencies: cache::Dependencies,
+}
+
+// returns None for drafts, unless the site is previewed
+pub async fn render(base_path: PathBuf, path: PathBuf) -> Result<Option<Page>> {
+    let key = path.clone();
+    render_cached(key, move || render_page(&base_path, &path)).await
+}
+
+// markdown files are also served as they are, except for drafts unless the site is previewed
+pub async fn is_hidden_draft(base_path: PathBuf, path: PathBuf) -> Result<bool> {
+    tokio::task::spawn_blocking(move || {
+        let files = template::Files::new(&base_path);
+        let Some(md) = files.read(&path)? else {
+            return Ok(false);
+        };
+        Ok(FrontMatter::from_md(&md).draft && !Site::load(&files)?.preview)
+    })
+    .await?
+}
+
+// collection items with a slug or permalink, index and tag pages of collections, feeds and the sitemap,
+// returns None if `request_path` isn't one of them
+pub async fn render_generated(
+    base_path: PathBuf,
+    request_path: String,
+    site_url: Option<String>,
+) -> Result<Option<Page>> {
+    // feeds and the sitemap fall back to defaults, collections need the site file
+    let site_file = base_path.join(site::SITE_FILE);
+    if !feeds::is_feed(&request_path) && !tokio::fs::try_exists(&site_file).await.unwrap_or(false) {
+        return Ok(None);
     }
 
-    let html = DEFAULT_HTML
-        .replace("{{head}}", &front_matter.html_head())
-        .replace("{{content}}", &content);
+    // keyed below the site file, so it can't collide with the path of a markdown file
+    let mut key = site_file.join(request_path.trim_matches('/'));
+    if feeds::is_feed(&request_path) {
+        // feeds contain absolute urls, which depend on the hostname
+        key.as_mut_os_string()
+            .push(format!("@{}", site_url.as_deref().unwrap_or_default()));
+    }
 
-    Ok(Html::from(html).into_response())
+    render_cached(key, move || {
+        render_generated_page(&base_path, &request_path, site_url.as_deref())
+    })
+    .await
 }
 
-async fn read_file(mut file: tokio::fs::File) -> Result<String> {
-    let mut buf = String::new();
-    file.read_to_string(&mut buf).await?;
-    Ok(buf)
+async fn render_cached(
+    key: PathBuf,
+    render: impl FnOnce() -> Result<Option<Rendered>> + Send + 'static,
+) -> Result<Option<Page>> {
+    if let Some(page) = cache::get(&key).await {
+        return Ok(Some(page));
+    }
+
+    let Some(rendered) = tokio::task::spawn_blocking(render).await?? else {
+        return Ok(None);
+    };
+
+    let page = Page {
+        body: Bytes::from(rendered.body),
+        content_type: rendered.content_type,
+        modified: cache::last_modified(&rendered.dependencies),
+    };
+    cache::insert(key, rendered.dependencies, page.clone());
+
+    Ok(Some(page))
+}
+
+fn render_page(base_path: &Path, path: &Path) -> Result<Option<Rendered>> {
+    let files = template::Files::new(base_path);
+    let md = files
+        .read(path)?
+        .ok_or_else(|| eyre!("{} not found", path.display()))?;
+
+    let site = Site::load(&files)?;
+    let page = FrontMatter::from_md(&md);
+    if page.draft && !site.preview {
+        return Ok(None);
+    }
+
+    let relative_path = path.strip_prefix(base_path).unwrap_or(path);
+    let url = page.url(relative_path, collections::is_item(&site, relative_path));
+    let markdown = markdown::render(&md, &page.markdown_options(&site));
+    let extra = context! {
+        toc => markdown.toc.map(Value::from_safe_string),
+    };
+
+    let html = render_document(&files, &site, None, &page, &url, markdown.html, extra)?;
+    Ok(Some(Rendered {
+        body: html,
+        content_type: HTML_CONTENT_TYPE,
+        dependencies: files.dependencies(),
+    }))
+}
+
+fn render_generated_page(
+    base_path: &Path,
+    request_path: &str,
+    site_url: Option<&str>,
+) -> Result<Option<Rendered>> {
+    let files = template::Files::new(base_path);
+    let site = Site::load(&files)?;
+
+    if feeds::is_feed(request_path) {
+        let collections = collections::load(&files, &site)?;
+        let feed = feeds::render(&files, &site, &collections, request_path, site_url)?;
+        return Ok(feed.map(|(body, content_type)| Rendered {
+            body,
+            content_type,
+            dependencies: files.dependencies(),
+        }));
+    }
+
+    let collections = collections::load(&files, &site)?;
+    let url = match request_path.trim_end_matches('/') {
+        "" => "/",
+        url => url,
+    };
+
+    let item = collections
+        .iter()
+        .flat_map(|collection| &collection.items)
+        .find(|item| item.url == url);
+    if let Some(item) = item {
+        return render_page(base_path, &item.path);
+    }
+
+    let Some(route) = collections::Route::parse(&site, request_path) else {
+        return Ok(None);
+    };
+
+    let Some(collection) = collections.iter().find(|c| c.name == route.collection) else {
+        return Ok(None);
+    };
+
+    let Some(paginator) = collections::Paginator::new(collection, &route) else {
+        return Ok(None);
+    };
+
+    let page = FrontMatter {
+        title: Some(paginator.title.clone()),
+        layout: collection.config.layout.clone(),
+        ..Default::default()
+    };
+    let content = paginator.to_html();
+    let extra = context! {
+        paginator => Value::from_serialize(&paginator),
+        tag => route.tag.as_ref().map(|_| &paginator.title),
+    };
+
+    let html = render_document(
+        &files,
+        &site,
+        Some(&collections),
+        &page,
+        url,
+        content,
+        extra,
+    )?;
+    Ok(Some(Rendered {
+        body: html,
+        content_type: HTML_CONTENT_TYPE,
+        dependencies: files.dependencies(),
+    }))
+}
+
+// wraps the content in the layouts of the page, or the default document if there are none
+fn render_document(
+    files: &template::Files,
+    site: &Site,
+    collections: Option<&[collections::Collection]>,
+    page: &FrontMatter,
+    url: &str,
+    mut content: String,
+    extra: Value,
+) -> Result<String> {
+    // themes can provide a `default` layout
+    let theme = page.theme.as_deref().or(site.variable("theme"));
+    let theme_dir = theme.and_then(|theme| themes::theme_dir(files.base_path(), theme));
+    let layout = match (&page.layout, &theme_dir) {
+        (None, Some(_)) => Some("default".to_string()),
+        (layout, _) => layout.clone(),
+    };
+
+    let layouts = template::load_layouts(files, theme_dir.as_deref(), layout)?;
+    let mut front_matter = page.clone();
+    for layout in &layouts {
+        front_matter.merge(&layout.front_matter);
+    }
+
+    let mut head = match front_matter.theme.as_deref().or(theme) {
+        Some(name) if Some(name) == theme => themes::stylesheet(files, theme_dir.as_deref(), name),
+        Some(name) => {
+            let dir = themes::theme_dir(files.base_path(), name);
+            themes::stylesheet(files, dir.as_deref(), name)
+        }
+        None => String::new(),
+    };
+    head.push_str(&front_matter.html_head(site.base_url(), url));
+
+    if !layouts.is_empty() {
+        let collections = match collections {
+            Some(collections) => collections::variables(collections),
+            None => collections::variables(&collections::load(files, site)?),
+        };
+        let site = Value::from_serialize(&site.variables);
+        let env = template::environment(files.clone(), theme_dir.clone());
+
+        // every layout wraps the output of the previous one
+        for layout in &layouts {
+            let context = context! {
+                page => page,
+                layout => &layout.front_matter,
+                site => &site,
+                collections => &collections,
+                head => Value::from_safe_string(head.clone()),
+                content => Value::from_safe_string(content),
+                ..extra.clone()
+            };
+            content = template::render_layout(&env, layout, context)?;
+        }
+    }
+
+    // layouts can provide the whole document themselves
+    let trimmed = content.trim_start().to_ascii_lowercase();
+    if trimmed.starts_with("<!doctype") || trimmed.starts_with("<html") {
+        return Ok(content);
+    }
+
+    let lang = front_matter
+        .lang
+        .as_deref()
+        .or(site.variable("lang"))
+        .map(|lang| format!(" lang=\"{}\"", escape_html(lang)))
+        .unwrap_or_default();
+
+    // formatted in one pass, so text like `{{content}}` in the head is kept as it is
+    Ok(format!(
+        r#"<!DOCTYPE html><html{lang}><head><meta charset="utf-8">{head}</head><body>{content}</body></html>"#
+    ))
 }
@@ -0,0 +1,301 @@
+use std::collections::BTreeMap;
+
+use percent_encoding::utf8_percent_encode;
+
+use crate::utils::{escape_html, PATH_SEGMENT};
+
+// `{{< name positional key="value" >}}`
+const OPEN: &str = "{{<";
+const CLOSE: &str = ">}}";
+
+type Shortcode = fn(&Args) -> Result<String, String>;
+
+const SHORTCODES: &[(&str, Shortcode)] = &[("youtube", youtube), ("figure", figure)];
+
+#[derive(Debug, Default)]
+struct Args {
+    positional: Vec<String>,
+    named: BTreeMap<String, String>,
+}
+
+impl Args {
+    // `key="value"` or the positional argument at `index`
+    fn get(&self, key: &str, index: usize) -> Option<&str> {
+        self.named
+            .get(key)
+            .or(self.positional.get(index))
+            .map(String::as_str)
+    }
+}
+
+// replaces shortcodes outside of code with the placeholder for their html,
+// broken or unknown shortcodes are rendered as an error so they're noticed
+pub fn expand(md: &str, mut placeholder: impl FnMut(String) -> String) -> String {
+    if !md.contains(OPEN) {
+        return md.to_string();
+    }
+
+    let mut output = String::with_capacity(md.len());
+    let mut fence: Option<&str> = None;
+
+    for line in md.split_inclusive('\n') {
+        let trimmed = line.trim_start_matches(' ');
+        if let Some(delimiter) = fence {
+            if trimmed.starts_with(delimiter) {
+                fence = None;
+            }
+            output.push_str(line);
+            continue;
+        }
+
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            fence = Some(&trimmed[..3]);
+            output.push_str(line);
+            continue;
+        }
+
+        // every other part is inline code
+        for (i, part) in line.split('`').enumerate() {
+            if i > 0 {
+                output.push('`');
+            }
+            match i % 2 {
+                0 => expand_text(part, &mut output, &mut placeholder),
+                _ => output.push_str(part),
+            }
+        }
+    }
+
+    output
+}
+
+fn expand_text(text: &str, output: &mut String, placeholder: &mut impl FnMut(String) -> String) {
+    let mut rest = text;
+    while let Some(start) = rest.find(OPEN) {
+        let Some(end) = rest[start..].find(CLOSE) else {
+            break;
+        };
+
+        output.push_str(&rest[..start]);
+        let inner = &rest[start + OPEN.len()..start + end];
+        output.push_str(&placeholder(render(inner)));
+        rest = &rest[start + end + CLOSE.len()..];
+    }
+    output.push_str(rest);
+}
+
+fn render(inner: &str) -> String {
+    let (name, args) = match parse(inner) {
+        Ok(parsed) => parsed,
+        Err(err) => return error(&err),
+    };
+
+    let Some((_, shortcode)) = SHORTCODES.iter().find(|(n, _)| *n == name) else {
+        return error(&format!("unknown shortcode: {name}"));
+    };
+
+    shortcode(&args).unwrap_or_else(|err| error(&format!("{name}: {err}")))
+}
+
+fn error(message: &str) -> String {
+    format!(
+        "<span class=\"shortcode-error\" style=\"color:red\">{}</span>",
+        escape_html(message)
+    )
+}
+
+fn parse(inner: &str) -> Result<(String, Args), String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quoted = false;
+    let mut has_token = false;
+
+    for c in inner.trim().chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                has_token = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if has_token {
+                    tokens.push(std::mem::take(&mut token));
+                    has_token = false;
+                }
+            }
+            c => {
+                token.push(c);
+                has_token = true;
+            }
+        }
+    }
+    if quoted {
+        return Err("unclosed quote".to_string());
+    }
+    if has_token {
+        tokens.push(token);
+    }
+
+    let mut tokens = tokens.into_iter();
+    let name = tokens.next().ok_or("missing shortcode name")?;
+
+    let mut args = Args::default();
+    for token in tokens {
+        match token.split_once('=') {
+            Some((key, value)) if !key.is_empty() => {
+                args.named.insert(key.to_string(), value.to_string());
+            }
+            _ => args.positional.push(token),
+        }
+    }
+
+    Ok((name, args))
+}
+
+// {{< youtube dQw4w9WgXcQ >}}
+fn youtube(args: &Args) -> Result<String, String> {
+    let id = args.get("id", 0).ok_or("missing video id")?;
+    if !id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!("invalid video id: {id}"));
+    }
+
+    let title = args.get("title", 1).unwrap_or("YouTube video");
+    Ok(format!(
+        "<div class=\"video\"><iframe src=\"https://www.youtube-nocookie.com/embed/{}\" title=\"{}\" \
+        style=\"border:0;width:100%;aspect-ratio:16/9\" loading=\"lazy\" allowfullscreen \
+        allow=\"accelerometer; autoplay; clipboard-write; encrypted-media; gyroscope; picture-in-picture\"></iframe></div>",
+        utf8_percent_encode(id, PATH_SEGMENT),
+        escape_html(title),
+    ))
+}
+
+// {{< figure src="/cat.png" alt="A cat" caption="My cat" link="/cats" >}}
+fn figure(args: &Args) -> Result<String, String> {
+    let src = args.get("src", 0).ok_or("missing src")?;
+    let src = safe_url(src).ok_or("invalid src")?;
+
+    let caption = args.get("caption", 1);
+    let alt = args.get("alt", 2).or(caption).unwrap_or_default();
+
+    let mut img = format!(
+        "<img src=\"{}\" alt=\"{}\" loading=\"lazy\">",
+        escape_html(&src),
+        escape_html(alt)
+    );
+    if let Some(link) = args.named.get("link") {
+        let link = safe_url(link).ok_or("invalid link")?;
+        img = format!("<a href=\"{}\">{img}</a>", escape_html(&link));
+    }
+
+    let caption = caption
+        .map(|caption| format!("<figcaption>{}</figcaption>", escape_html(caption)))
+        .unwrap_or_default();
+
+    Ok(format!("<figure>{img}{caption}</figure>"))
+}
+
+// shortcodes are also used in safe mode, so they can't add scripts. browsers ignore
+// whitespace and control characters in urls, so they're removed before the check
+fn safe_url(url: &str) -> Option<String> {
+    let url = url
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>();
+
+    // relative urls can contain `:` after the first `/`, `?` or `#`
+    let scheme = url
+        .split_once(':')
+        .map(|(scheme, _)| scheme)
+        .filter(|scheme| !scheme.contains(['/', '?', '#']));
+    match scheme {
+        None => Some(url),
+        Some(scheme) if ["http", "https", "mailto"].contains(&&*scheme.to_ascii_lowercase()) => {
+            Some(url)
+        }
+        Some(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_args() {
+        let (name, args) = parse(r#" figure /cat.png caption="My  cat" link=/cats "" "#).unwrap();
+        assert_eq!(name, "figure");
+        assert_eq!(args.positional, ["/cat.png", ""]);
+        assert_eq!(args.get("caption", 1), Some("My  cat"));
+        assert_eq!(args.get("link", 9), Some("/cats"));
+        assert_eq!(args.get("alt", 0), Some("/cat.png"));
+        assert_eq!(args.get("alt", 5), None);
+    }
+
+    #[test]
+    fn keeps_equals_signs_in_values() {
+        let (_, args) = parse(r#"figure caption="a=b" =c"#).unwrap();
+        assert_eq!(args.get("caption", 9), Some("a=b"));
+        assert_eq!(args.positional, ["=c"]);
+    }
+
+    #[test]
+    fn rejects_broken_args() {
+        assert!(parse("").is_err());
+        assert!(parse("   ").is_err());
+        assert!(parse(r#"figure caption="unclosed"#).is_err());
+    }
+
+    #[test]
+    fn allows_relative_and_web_urls() {
+        for url in [
+            "/cat.png",
+            "cat.png",
+            "../cats/?page=1:2",
+            "#top",
+            "//example.com/cat.png",
+            "https://example.com",
+            "HTTP://example.com",
+            "mailto:me@example.com",
+        ] {
+            assert_eq!(safe_url(url).as_deref(), Some(url), "{url}");
+        }
+        assert_eq!(safe_url(" /cat .png\n").as_deref(), Some("/cat.png"));
+    }
+
+    #[test]
+    fn rejects_other_schemes() {
+        for url in [
+            "javascript:alert(1)",
+            "JavaScript:alert(1)",
+            "java\tscript:alert(1)",
+            " javascript:alert(1)",
+            "java\u{0}script:alert(1)",
+            "java\u{200b}script:alert(1)",
+            "data:text/html,<script>alert(1)</script>",
+            "vbscript:msgbox",
+        ] {
+            assert_eq!(safe_url(url), None, "{url:?}");
+        }
+    }
+
+    #[test]
+    fn expands_outside_of_code() {
+        let md = "{{< youtube abc >}}\n`{{< youtube abc >}}`\n```\n{{< youtube abc >}}\n```\n";
+        let expanded = expand(md, |_| "X".to_string());
+        assert_eq!(
+            expanded,
+            "X\n`{{< youtube abc >}}`\n```\n{{< youtube abc >}}\n```\n"
+        );
+    }
+
+    #[test]
+    fn renders_errors_for_broken_shortcodes() {
+        assert!(render("unknown").contains("shortcode-error"));
+        assert!(render("youtube").contains("missing video id"));
+        assert!(render("youtube \"a b\"").contains("invalid video id"));
+        assert!(render("figure javascript:alert(1)").contains("invalid src"));
+        assert!(!render("figure /cat.png alt=\"<b>\"").contains("<b>"));
+    }
+}
@@ -0,0 +1,86 @@
+use std::{collections::BTreeMap, path::Path};
+
+use eyre::Result;
+use serde::Deserialize;
+
+use super::{
+    collections::CollectionConfig,
+    markdown::MarkdownOptions,
+    template::{Files, TemplateError},
+};
+
+// site-wide settings and variables, available to templates as `site.<key>`
+pub const SITE_FILE: &str = "_site.toml";
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Site {
+    /// Directories of markdown files listed by date, e.g. `[collections.posts]`
+    #[serde(default)]
+    pub collections: BTreeMap<String, CollectionConfig>,
+
+    /// `/feed.xml` and `/rss.xml`
+    #[serde(default)]
+    pub feed: FeedConfig,
+
+    /// Markdown extensions, see `MarkdownOptions`
+    #[serde(default)]
+    pub markdown: MarkdownOptions,
+
+    /// Serve and list draft pages
+    #[serde(default)]
+    pub preview: bool,
+
+    #[serde(flatten)]
+    pub variables: toml::Table,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct FeedConfig {
+    /// Collection to publish (defaults to `posts`, or the first collection)
+    pub collection: Option<String>,
+
+    /// Maximum number of entries
+    pub limit: usize,
+}
+
+impl Default for FeedConfig {
+    fn default() -> Self {
+        Self {
+            collection: None,
+            limit: 20,
+        }
+    }
+}
+
+impl Site {
+    // a top-level string like `title` or `url`
+    pub fn variable(&self, key: &str) -> Option<&str> {
+        self.variables.get(key).and_then(|value| value.as_str())
+    }
+
+    // absolute urls need the `url` of the site, relative ones are used otherwise
+    pub fn base_url(&self) -> &str {
+        self.variable("url")
+            .unwrap_or_default()
+            .trim_end_matches('/')
+    }
+
+    pub fn load(files: &Files) -> Result<Self> {
+        let Some(source) = files.read(Path::new(SITE_FILE))? else {
+            return Ok(Self::default());
+        };
+
+        toml::from_str::<Site>(&source).map_err(|err| {
+            let line = err
+                .span()
+                .map(|span| source[..span.start].matches('\n').count() + 1);
+
+            TemplateError {
+                line,
+                ..TemplateError::new(SITE_FILE, err.message())
+            }
+            .into()
+        })
+    }
+}
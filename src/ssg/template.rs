@@ -0,0 +1,415 @@
+use std::{
+    fmt,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use eyre::Result;
+use minijinja::{AutoEscape, Environment, ErrorKind, Value};
+
+use super::{cache::Dependencies, frontmatter::FrontMatter, markdown};
+use crate::utils::escape_html;
+
+// layouts are `_layouts/<name>.html` templates, or markdown files for older sites
+const LAYOUTS_DIR: &str = "_layouts";
+// partials for `{% include "nav.html" %}`
+const INCLUDES_DIR: &str = "_includes";
+
+// more nested layouts than this are most likely a cycle
+const MAX_LAYOUT_DEPTH: usize = 8;
+
+pub struct Layout {
+    name: String,
+    source: String,
+    pub front_matter: FrontMatter,
+}
+
+// reads files relative to the site root, remembering them so the cache knows when to re-render
+#[derive(Clone)]
+pub struct Files {
+    base_path: PathBuf,
+    dependencies: Arc<Mutex<Dependencies>>,
+}
+
+impl Files {
+    pub fn new(base_path: &Path) -> Self {
+        Self {
+            base_path: base_path.to_path_buf(),
+            dependencies: Arc::default(),
+        }
+    }
+
+    // returns None if the file doesn't exist, which is also tracked
+    pub fn read(&self, path: &Path) -> std::io::Result<Option<String>> {
+        let path = self.base_path.join(path);
+
+        // read the modification time first, so changes while rendering invalidate the result
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+        self.dependencies
+            .lock()
+            .unwrap()
+            .push((path.clone(), modified));
+
+        match std::fs::read_to_string(&path) {
+            Ok(content) => Ok(Some(content)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    // the modification time of a file, which is tracked like the files that are read
+    pub fn modified(&self, path: &Path) -> Option<SystemTime> {
+        let path = self.base_path.join(path);
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+        self.dependencies.lock().unwrap().push((path, modified));
+        modified
+    }
+
+    // the entries of a directory, which is tracked so added or removed files are noticed
+    pub fn read_dir(&self, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+        let path = self.base_path.join(path);
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+        self.dependencies
+            .lock()
+            .unwrap()
+            .push((path.clone(), modified));
+
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&path)? {
+            entries.push(entry?.path());
+        }
+        entries.sort();
+        Ok(entries)
+    }
+
+    pub fn base_path(&self) -> &Path {
+        &self.base_path
+    }
+
+    pub fn dependencies(&self) -> Dependencies {
+        self.dependencies.lock().unwrap().clone()
+    }
+}
+
+#[derive(Debug)]
+pub struct TemplateError {
+    pub file: String,
+    pub line: Option<usize>,
+    pub message: String,
+    // the surrounding source, if available
+    pub context: Option<String>,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.file, line, self.message),
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl TemplateError {
+    pub fn new(file: &str, message: &str) -> Self {
+        Self {
+            file: file.to_string(),
+            line: None,
+            message: message.to_string(),
+            context: None,
+        }
+    }
+
+    fn from_minijinja(err: minijinja::Error) -> Self {
+        // includes are loaded by their name only
+        let file = match err.name() {
+            Some(name) if name.starts_with(LAYOUTS_DIR) => name.to_string(),
+            Some(name) => format!("{INCLUDES_DIR}/{name}"),
+            None => "unknown template".to_string(),
+        };
+
+        let message = match err.detail() {
+            Some(detail) => format!("{}: {}", err.kind(), detail),
+            None => err.kind().to_string(),
+        };
+
+        let context = err.display_debug_info().to_string();
+
+        Self {
+            file,
+            line: err.line(),
+            message,
+            context: (!context.trim().is_empty()).then_some(context),
+        }
+    }
+
+    // shown instead of the page, so site owners see what went wrong
+    pub fn to_html(&self) -> String {
+        let location = match self.line {
+            Some(line) => format!("{}, line {}", self.file, line),
+            None => self.file.clone(),
+        };
+
+        let context = self
+            .context
+            .as_ref()
+            .map(|context| format!("<pre>{}</pre>", escape_html(context)))
+            .unwrap_or_default();
+
+        format!(
+            r#"<!DOCTYPE html><html><head><meta charset="utf-8"><title>Template error</title>
+<style>body{{font-family:monospace;margin:2rem}}pre{{background:#eee;padding:1rem;overflow:auto}}</style></head>
+<body><h1>Template error</h1><p><b>{}</b></p><p>{}</p>{context}</body></html>"#,
+            escape_html(&location),
+            escape_html(&self.message),
+        )
+    }
+}
+
+// includes are looked up in the site first, then in its theme
+pub fn environment(files: Files, theme_dir: Option<PathBuf>) -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_auto_escape_callback(|_| AutoEscape::Html);
+    env.set_loader(move |name| {
+        let Some(path) = include_path(name) else {
+            return Err(minijinja::Error::new(
+                ErrorKind::TemplateNotFound,
+                format!("invalid include path: {name}"),
+            ));
+        };
+
+        let read = |path: &Path| {
+            files.read(path).map_err(|err| {
+                minijinja::Error::new(ErrorKind::InvalidOperation, "failed to read include")
+                    .with_source(err)
+            })
+        };
+
+        match (read(&path)?, &theme_dir) {
+            (Some(source), _) => Ok(Some(source)),
+            (None, Some(theme_dir)) => read(&theme_dir.join(path)),
+            (None, None) => Ok(None),
+        }
+    });
+    env
+}
+
+// only plain relative paths inside `_includes`
+fn include_path(name: &str) -> Option<PathBuf> {
+    let path = Path::new(name);
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_)))
+        .then(|| Path::new(INCLUDES_DIR).join(path))
+}
+
+// the layout of a page, followed by the layout of that layout and so on,
+// layouts of the site take precedence over those of its theme
+pub fn load_layouts(
+    files: &Files,
+    theme_dir: Option<&Path>,
+    mut next: Option<String>,
+) -> Result<Vec<Layout>> {
+    let mut layouts = Vec::new();
+
+    while let Some(name) = next.take() {
+        if layouts.len() >= MAX_LAYOUT_DEPTH {
+            return Err(TemplateError::new(
+                &format!("{LAYOUTS_DIR}/{name}"),
+                "layouts are nested too deeply, do they use each other?",
+            )
+            .into());
+        }
+
+        let name = name.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "");
+        let html_name = format!("{LAYOUTS_DIR}/{name}.html");
+        let md_name = format!("{LAYOUTS_DIR}/{name}.md");
+
+        let layout = if let Some(source) = files.read(Path::new(&html_name))? {
+            Layout {
+                name: html_name,
+                front_matter: FrontMatter::from_md(&source),
+                source: FrontMatter::strip(&source),
+            }
+        } else if let Some((theme, source)) = read_theme_layout(files, theme_dir, &html_name)? {
+            Layout {
+                name: format!("{html_name} (theme {theme})"),
+                front_matter: FrontMatter::from_md(&source),
+                source: FrontMatter::strip(&source),
+            }
+        } else if let Some(source) = files.read(Path::new(&md_name))? {
+            // markdown layouts used to be appended to if they had no `{{content}}`
+            let mut html = markdown::md_to_html(&source);
+            if !html.contains("{{content}}") && !html.contains("{{ content }}") {
+                html.push_str("{{ content }}");
+            }
+
+            Layout {
+                name: md_name,
+                front_matter: FrontMatter::from_md(&source),
+                source: html,
+            }
+        } else {
+            // missing layouts are ignored
+            break;
+        };
+
+        next = layout.front_matter.layout.clone();
+        layouts.push(layout);
+    }
+
+    Ok(layouts)
+}
+
+fn read_theme_layout(
+    files: &Files,
+    theme_dir: Option<&Path>,
+    name: &str,
+) -> Result<Option<(String, String)>> {
+    let Some(dir) = theme_dir else {
+        return Ok(None);
+    };
+
+    let theme = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .to_string();
+    Ok(files.read(&dir.join(name))?.map(|source| (theme, source)))
+}
+
+pub fn render_layout(env: &Environment, layout: &Layout, context: Value) -> Result<String> {
+    env.render_named_str(&layout.name, &layout.source, context)
+        .map_err(|err| TemplateError::from_minijinja(err).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use minijinja::context;
+
+    use super::*;
+    use crate::ssg::site::{Site, SITE_FILE};
+
+    // a new site directory with the given files
+    fn site(files: &[(&str, &str)]) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "dawdle-template-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        for (path, content) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn only_includes_plain_paths() {
+        assert_eq!(
+            include_path("nav.html"),
+            Some(PathBuf::from("_includes/nav.html"))
+        );
+        assert_eq!(
+            include_path("a/b.html"),
+            Some(PathBuf::from("_includes/a/b.html"))
+        );
+        assert_eq!(include_path("../_site.toml"), None);
+        assert_eq!(include_path("/etc/passwd"), None);
+        assert_eq!(include_path("./nav.html"), None);
+    }
+
+    #[test]
+    fn loads_nested_layouts() {
+        let dir = site(&[
+            (
+                "_layouts/post.html",
+                "---\nlayout: base\n---\n<article>{{ content }}</article>",
+            ),
+            ("_layouts/base.html", "<main>{{ content }}</main>"),
+        ]);
+        let files = Files::new(&dir);
+        let layouts = load_layouts(&files, None, Some("post".to_string())).unwrap();
+        let names = layouts.iter().map(|l| l.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["_layouts/post.html", "_layouts/base.html"]);
+        assert_eq!(layouts[0].source.trim(), "<article>{{ content }}</article>");
+
+        // missing layouts and names with path characters are ignored
+        assert!(load_layouts(&files, None, Some("missing".to_string()))
+            .unwrap()
+            .is_empty());
+        let layouts = load_layouts(&files, None, Some("../base".to_string())).unwrap();
+        assert_eq!(layouts[0].name, "_layouts/base.html");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stops_layout_cycles() {
+        let dir = site(&[
+            ("_layouts/a.html", "---\nlayout: b\n---\n{{ content }}"),
+            ("_layouts/b.html", "---\nlayout: a\n---\n{{ content }}"),
+        ]);
+        let err = load_layouts(&Files::new(&dir), None, Some("a".to_string()))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("nested too deeply"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn appends_content_to_markdown_layouts() {
+        let dir = site(&[("_layouts/old.md", "# Title\n")]);
+        let layouts = load_layouts(&Files::new(&dir), None, Some("old".to_string())).unwrap();
+        assert!(layouts[0].source.ends_with("{{ content }}"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn renders_includes_and_escapes_variables() {
+        let dir = site(&[
+            (
+                "_layouts/base.html",
+                "{% include \"nav.html\" %}{{ content }}{{ page.title }}",
+            ),
+            ("_includes/nav.html", "<nav>{{ site.name }}</nav>"),
+            ("_site.toml", "name = \"My <Site>\""),
+        ]);
+        let files = Files::new(&dir);
+        let layouts = load_layouts(&files, None, Some("base".to_string())).unwrap();
+        let env = environment(files.clone(), None);
+        let context = context! {
+            site => Value::from_serialize(&Site::load(&files).unwrap().variables),
+            page => context! { title => "<b>" },
+            content => Value::from_safe_string("<p>hi</p>".to_string()),
+        };
+
+        let html = render_layout(&env, &layouts[0], context).unwrap();
+        assert_eq!(html, "<nav>My &lt;Site&gt;</nav><p>hi</p>&lt;b&gt;");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reports_errors_with_their_file() {
+        let dir = site(&[
+            ("_layouts/base.html", "{% include \"../secret\" %}"),
+            ("_site.toml", "name = \n"),
+        ]);
+        let files = Files::new(&dir);
+
+        let err = Site::load(&files).unwrap_err();
+        let err = err.downcast_ref::<TemplateError>().unwrap();
+        assert_eq!((err.file.as_str(), err.line), (SITE_FILE, Some(1)));
+
+        let layouts = load_layouts(&files, None, Some("base".to_string())).unwrap();
+        let err = render_layout(&environment(files, None), &layouts[0], context! {}).unwrap_err();
+        let err = err.downcast_ref::<TemplateError>().unwrap();
+        assert_eq!(err.file, "_layouts/base.html");
+        assert!(err.to_html().contains("_layouts/base.html, line 1"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
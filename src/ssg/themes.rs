@@ -1,6 +1,223 @@
-pub const THEMES: &[(&str, &str)] = &[
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use eyre::{bail, Result};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+
+use super::template::Files;
+use crate::utils::escape_html;
+
+// theme files are served from here on every site, e.g. `/_dawdle/themes/water/theme.css`
+pub const THEMES_PATH: &str = "/_dawdle/themes/";
+
+// themes of a single site, which take precedence over installed ones
+const LOCAL_THEMES_DIR: &str = "_themes";
+
+// linked on every page using the theme
+const STYLESHEET: &str = "theme.css";
+// optional `description`, `author` and `version`
+const THEME_FILE: &str = "theme.toml";
+
+const MAX_THEME_SIZE: usize = 16 * 1024 * 1024;
+const MAX_THEME_FILES: usize = 256;
+// binary files are base64 encoded when installing, which makes them a third larger
+pub const MAX_INSTALL_REQUEST_SIZE: usize = MAX_THEME_SIZE / 3 * 4 + 1024 * 1024;
+
+// themes that used to be hosted on dawdle.space, used if no theme with that name is installed
+const LEGACY_THEMES: &[(&str, &str)] = &[
     ("retro", "https://dawdle.space/themes/retro.css"),
     ("water", "https://dawdle.space/themes/water.css"),
     ("water-dark", "https://dawdle.space/themes/water-dark.css"),
     ("water-light", "https://dawdle.space/themes/water-light.css"),
 ];
+
+// `data_dir/themes`, set once on startup
+static THEMES_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+// installs share the temporary directories next to the theme
+static INSTALL_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn init(themes_dir: PathBuf) {
+    let _ = THEMES_DIR.set(themes_dir);
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ThemeInfo {
+    #[serde(default)]
+    pub name: String,
+    pub description: Option<String>,
+    pub author: Option<String>,
+    pub version: Option<String>,
+}
+
+// a file of a theme to install, either text or base64 for images and fonts
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ThemeFile {
+    Text(String),
+    Base64 { base64: String },
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && !name.starts_with(['-', '_'])
+}
+
+// only plain relative paths, without hidden files
+fn is_valid_file_path(path: &Path) -> bool {
+    path.components().all(|c| match c {
+        Component::Normal(name) => name.to_str().is_some_and(|name| !name.starts_with('.')),
+        _ => false,
+    }) && path.components().next().is_some()
+}
+
+// the directory of a theme, looked up in the site first
+pub fn theme_dir(base_path: &Path, name: &str) -> Option<PathBuf> {
+    if !is_valid_name(name) {
+        return None;
+    }
+
+    let local = base_path.join(LOCAL_THEMES_DIR).join(name);
+    if local.is_dir() {
+        return Some(local);
+    }
+
+    THEMES_DIR
+        .get()
+        .map(|dir| dir.join(name))
+        .filter(|dir| dir.is_dir())
+}
+
+// the theme directory and the path of the file inside it for requests to `THEMES_PATH`,
+// layouts, includes and other files starting with `_` or `.` are not served
+pub fn asset_path(base_path: &Path, request_path: &str) -> Option<(PathBuf, String)> {
+    let rest = request_path.strip_prefix(THEMES_PATH)?;
+    let (name, file) = rest.split_once('/').unwrap_or((rest, ""));
+    let dir = theme_dir(base_path, name)?;
+
+    // checked like the file is opened later, e.g. `%5F` is `_`
+    let decoded = percent_decode_str(file).decode_utf8().ok()?;
+    if decoded
+        .split('/')
+        .any(|segment| segment.starts_with(['_', '.']))
+    {
+        return None;
+    }
+
+    Some((dir, format!("/{file}")))
+}
+
+// the `<link>` to the stylesheet of a theme, if it has one
+pub fn stylesheet(files: &Files, theme_dir: Option<&Path>, name: &str) -> String {
+    let href = match theme_dir {
+        Some(dir) if files.modified(&dir.join(STYLESHEET)).is_some() => {
+            format!("{THEMES_PATH}{name}/{STYLESHEET}")
+        }
+        Some(_) => return String::new(),
+        None => match LEGACY_THEMES.iter().find(|(legacy, _)| *legacy == name) {
+            Some((_, url)) => url.to_string(),
+            None => return String::new(),
+        },
+    };
+
+    format!(
+        "<link rel=\"stylesheet\" href=\"{}\">\n",
+        escape_html(&href)
+    )
+}
+
+// themes installed in `dir`, sorted by name
+pub fn list(dir: &Path) -> Result<Vec<ThemeInfo>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut themes = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if !is_valid_name(&name) || !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        let info = std::fs::read_to_string(entry.path().join(THEME_FILE))
+            .ok()
+            .and_then(|source| toml::from_str::<ThemeInfo>(&source).ok())
+            .unwrap_or_default();
+
+        themes.push(ThemeInfo { name, ..info });
+    }
+
+    themes.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(themes)
+}
+
+// replaces the theme `name` in `dir` with the given files
+pub fn install(dir: &Path, name: &str, files: BTreeMap<String, ThemeFile>) -> Result<()> {
+    if !is_valid_name(name) {
+        bail!("invalid theme name");
+    }
+    if files.is_empty() || files.len() > MAX_THEME_FILES {
+        bail!("a theme needs between 1 and {MAX_THEME_FILES} files");
+    }
+
+    let mut contents = Vec::new();
+    let mut size = 0;
+    for (path, file) in files {
+        let path = PathBuf::from(path);
+        if !is_valid_file_path(&path) {
+            bail!("invalid file path: {}", path.display());
+        }
+
+        let content = match file {
+            ThemeFile::Text(text) => text.into_bytes(),
+            ThemeFile::Base64 { base64 } => data_encoding::BASE64
+                .decode(base64.as_bytes())
+                .map_err(|_| eyre::eyre!("invalid base64 in {}", path.display()))?,
+        };
+
+        size += content.len();
+        if size > MAX_THEME_SIZE {
+            bail!(
+                "themes can't be larger than {} MiB",
+                MAX_THEME_SIZE / 1024 / 1024
+            );
+        }
+        contents.push((path, content));
+    }
+
+    // written next to the theme first, so sites never see a partially installed theme
+    let _lock = INSTALL_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let tmp = dir.join(format!(".{name}.tmp"));
+    let _ = std::fs::remove_dir_all(&tmp);
+    for (path, content) in contents {
+        let path = tmp.join(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, content)?;
+    }
+
+    let target = dir.join(name);
+    let old = dir.join(format!(".{name}.old"));
+    let _ = std::fs::remove_dir_all(&old);
+    if target.exists() {
+        std::fs::rename(&target, &old)?;
+    }
+    std::fs::rename(&tmp, &target)?;
+    let _ = std::fs::remove_dir_all(&old);
+
+    Ok(())
+}
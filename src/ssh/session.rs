@@ -5,9 +5,10 @@ use futures::TryStreamExt;
 use log::{debug, info};
 use russh_keys::key::parse_public_key;
 use tokio::io::AsyncWriteExt;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
 
 use crate::app::App;
+use crate::chat::terminal::{self, TerminalInput};
 use crate::containers::{AttachInput, Containers, Pty};
 use async_trait::async_trait;
 use russh::server::{Auth, Msg, Session};
@@ -45,8 +46,14 @@ pub struct SshChannel {
     pty: Option<Pty>,
     env: Option<Vec<(String, String)>>,
     shell: UserContainer,
+    // set instead of the shell while the chat is open
+    chat: Option<mpsc::UnboundedSender<TerminalInput>>,
 }
 
+// `ssh dawdle.space chat` opens the chat instead of running a command in the container
+const CHAT_COMMAND: &str = "chat";
+const CHAT_OUTPUT_BUFFER: usize = 2;
+
 #[derive(Debug)]
 struct SshUser {
     username: String,
@@ -83,6 +90,65 @@ impl SshSession {
             .ok_or_else(|| eyre::eyre!("channel not found"))
     }
 
+    fn start_chat(&mut self, channel_id: ChannelId, session: &mut Session) -> Result<()> {
+        let username = self.user()?.username.clone();
+        let size = self
+            .channel(channel_id)?
+            .pty
+            .as_ref()
+            .and_then(|pty| pty.pty_size);
+        let session_handle = session.handle();
+
+        let Some(size) = size else {
+            tokio::spawn(async move {
+                let message = "the chat needs a terminal, connect with `ssh -t` instead\r\n";
+                let _ = session_handle
+                    .extended_data(channel_id, 1, CryptoVec::from_slice(message.as_bytes()))
+                    .await;
+                let _ = session_handle.exit_status_request(channel_id, 1).await;
+                let _ = session_handle.close(channel_id).await;
+            });
+            session.request_success();
+            return Ok(());
+        };
+
+        let (input, input_rx) = mpsc::unbounded_channel();
+        // only a few frames are queued if the client stops reading
+        let (output, mut output_rx) = mpsc::channel::<Vec<u8>>(CHAT_OUTPUT_BUFFER);
+        self.channels.alter(&channel_id, |_, mut v| {
+            v.chat = Some(input);
+            v
+        });
+
+        let chat = self.state.chat.clone();
+        tokio::spawn(async move {
+            info!("chat terminal for {username} spawned");
+
+            let forward_output = async {
+                while let Some(data) = output_rx.recv().await {
+                    if session_handle
+                        .data(channel_id, CryptoVec::from_slice(&data))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            };
+            let (status, _) = tokio::join!(
+                terminal::run(chat, username, size, input_rx, output),
+                forward_output
+            );
+
+            let _ = session_handle.exit_status_request(channel_id, status).await;
+            let _ = session_handle.eof(channel_id).await;
+            let _ = session_handle.close(channel_id).await;
+        });
+
+        session.request_success();
+        Ok(())
+    }
+
     async fn get_user(&mut self, username: &str) -> Result<&SshUser> {
         let public_keys = match self.user {
             Some(ref user) => {
@@ -222,6 +288,9 @@ impl russh::server::Handler for SshSession {
     ) -> Result<(), Self::Error> {
         log::debug!("exec_request");
         let command = String::from_utf8(data.to_vec())?;
+        if command.trim() == CHAT_COMMAND {
+            return self.start_chat(channel_id, session);
+        }
 
         let username = self.user()?.username.clone();
         let attach = self
@@ -349,7 +418,13 @@ impl russh::server::Handler for SshSession {
         // SSH client sends data, pipe it to the corresponding PTY
         // info!("data packet: {:?}", String::from_utf8_lossy(data));
         {
-            match self.channel(channel_id)?.shell.write_all(data).await {
+            let mut channel = self.channel(channel_id)?;
+            if let Some(chat) = &channel.chat {
+                let _ = chat.send(TerminalInput::Data(data.to_vec()));
+                return Ok(());
+            }
+
+            match channel.shell.write_all(data).await {
                 Ok(_) => {}
                 Err(e) => log::error!("failed to write to pty: {}", e),
             }
@@ -373,8 +448,16 @@ impl russh::server::Handler for SshSession {
                 bail!("channel not found")
             };
 
+            if let Some(chat) = &channel.chat {
+                let resize = TerminalInput::Resize(col_width as u16, row_height as u16);
+                let _ = chat.send(resize);
+            }
+
             if let Some(pty) = channel.pty.as_mut() {
                 pty.pty_size = Some((col_width as u16, row_height as u16));
+                if channel.chat.is_some() {
+                    return Ok(());
+                }
                 self.containers
                     .resize(
                         channel.shell.exec_id()?,
@@ -395,8 +478,11 @@ impl russh::server::Handler for SshSession {
     ) -> Result<(), Self::Error> {
         log::debug!("channel_close");
         // Clean up
+        // dropping the channel also closes the chat, if it's open
         if let Some((_, channel)) = self.channels.remove(&channel_id) {
-            let _ = self.containers.detatch(channel.shell.exec_id()?).await;
+            if channel.chat.is_none() {
+                let _ = self.containers.detatch(channel.shell.exec_id()?).await;
+            }
         }
 
         Ok(())
@@ -408,8 +494,11 @@ impl russh::server::Handler for SshSession {
         _session: &mut Session,
     ) -> Result<(), Self::Error> {
         // Clean up
+        // dropping the channel also closes the chat, if it's open
         if let Some((_, channel)) = self.channels.remove(&channel_id) {
-            let _ = self.containers.detatch(channel.shell.exec_id()?).await;
+            if channel.chat.is_none() {
+                let _ = self.containers.detatch(channel.shell.exec_id()?).await;
+            }
         }
 
         Ok(())
@@ -1,5 +1,6 @@
 use argon2::PasswordHasher;
 use eyre::Result;
+use percent_encoding::{AsciiSet, CONTROLS};
 use std::fmt::{self, Debug, Formatter};
 
 pub fn to_time(timestamp: i64) -> Result<time::OffsetDateTime> {
@@ -31,6 +32,35 @@ pub fn is_valid_project_path(path: &str) -> bool {
         })
 }
 
+// characters that need to be escaped in a path segment
+pub const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+pub fn escape_html(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 pub struct RingBuffer<T> {
     buffer: Vec<T>,
     capacity: usize,
@@ -79,6 +109,11 @@ impl<T: Clone + Debug> RingBuffer<T> {
         }
     }
 
+    // the items aren't searched in order
+    pub fn find_mut(&mut self, predicate: impl Fn(&T) -> bool) -> Option<&mut T> {
+        self.buffer.iter_mut().find(|item| predicate(item))
+    }
+
     pub fn to_vec(&self) -> Vec<T> {
         let mut result = Vec::with_capacity(self.size);
         for i in 0..self.size {
@@ -0,0 +1,293 @@
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, Request, StatusCode},
+    response::Response,
+};
+use dashmap::DashMap;
+use eyre::{bail, eyre, Result};
+use http_body_util::{BodyExt, Full};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, BytesResponse, ChallengeType, HttpClient,
+    Identifier, NewAccount, NewOrder, OrderStatus,
+};
+use tokio::sync::OnceCell;
+
+use super::tls::Certificates;
+use crate::{app::App, config::TlsConfig};
+
+pub const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+// how often to check for missing or expiring certificates
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// don't retry a failed domain before this has passed, to stay clear of rate limits
+const RETRY_AFTER: Duration = Duration::from_secs(6 * 60 * 60);
+
+pub struct Acme {
+    state: App,
+    config: TlsConfig,
+    account: OnceCell<Account>,
+    certificates: Arc<Certificates>,
+
+    // token -> key authorization for pending HTTP-01 challenges
+    challenges: DashMap<String, String>,
+    failures: DashMap<String, std::time::Instant>,
+}
+
+impl Acme {
+    pub fn new(state: App, config: TlsConfig, certificates: Arc<Certificates>) -> Self {
+        Self {
+            state,
+            config,
+            certificates,
+            account: OnceCell::new(),
+            challenges: DashMap::new(),
+            failures: DashMap::new(),
+        }
+    }
+
+    // answer HTTP-01 challenges, returns None for all other requests
+    pub fn challenge_response<B>(&self, req: &Request<B>) -> Option<Response> {
+        let token = req.uri().path().strip_prefix(CHALLENGE_PREFIX)?;
+
+        Some(match self.challenges.get(token) {
+            Some(key_authorization) => Response::builder()
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .body(Body::from(key_authorization.clone()))
+                .unwrap(),
+            None => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap(),
+        })
+    }
+
+    // all domains that should have a certificate, HTTP-01 can't issue wildcard certificates,
+    // so every platform subdomain gets its own
+    fn domains(&self) -> Vec<String> {
+        let web = &self.state.config.web;
+        let mut domains = self.config.domains.clone();
+        domains.push(web.domain.clone());
+        domains.extend(web.api_hostnames.iter().cloned());
+
+        // custom domains are the only site keys that look like hostnames
+        for site in self.state.sites.iter() {
+            let key = site.key().to_ascii_lowercase();
+            match key.contains('.') {
+                true => domains.push(key),
+                false => domains.push(format!("{key}.{}", web.domain)),
+            }
+        }
+
+        domains.retain(|domain| addr::parse_domain_name(domain).is_ok());
+        domains.sort();
+        domains.dedup();
+        domains
+    }
+
+    fn needs_certificate(&self, domain: &str) -> bool {
+        if let Some(failed_at) = self.failures.get(domain) {
+            if failed_at.elapsed() < RETRY_AFTER {
+                return false;
+            }
+        }
+
+        let renew_before = time::Duration::days(self.config.renew_before_days.into());
+        match self.certificates.expires_at(domain) {
+            Some(expires_at) => expires_at - time::OffsetDateTime::now_utc() < renew_before,
+            None => true,
+        }
+    }
+
+    // periodically issue missing certificates and renew expiring ones
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        loop {
+            for domain in self.domains() {
+                if !self.needs_certificate(&domain) {
+                    continue;
+                }
+
+                log::info!("requesting certificate for {domain}");
+                match self.issue(&domain).await {
+                    Ok(()) => {
+                        self.failures.remove(&domain);
+                        log::info!("issued certificate for {domain}");
+                    }
+                    Err(err) => {
+                        self.failures
+                            .insert(domain.clone(), std::time::Instant::now());
+                        log::error!("failed to issue certificate for {domain}: {err}");
+                    }
+                }
+            }
+
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+
+    async fn account(&self) -> Result<&Account> {
+        self.account
+            .get_or_try_init(|| async {
+                let path = self.state.config.acme_account_path();
+
+                if path.exists() {
+                    let credentials: AccountCredentials =
+                        serde_json::from_slice(&std::fs::read(&path)?)?;
+                    let account =
+                        Account::from_credentials_and_http(credentials, self.http_client()?)
+                            .await?;
+                    return Ok(account);
+                }
+
+                let contact = self
+                    .config
+                    .acme
+                    .contact
+                    .iter()
+                    .map(|c| c.as_str())
+                    .collect::<Vec<_>>();
+
+                let (account, credentials) = Account::create_with_http(
+                    &NewAccount {
+                        contact: &contact,
+                        terms_of_service_agreed: true,
+                        only_return_existing: false,
+                    },
+                    &self.config.acme.directory_url,
+                    None,
+                    self.http_client()?,
+                )
+                .await?;
+
+                std::fs::create_dir_all(path.parent().unwrap())?;
+                std::fs::write(&path, serde_json::to_vec(&credentials)?)?;
+                log::info!("created acme account {}", account.id());
+
+                Ok(account)
+            })
+            .await
+    }
+
+    fn http_client(&self) -> Result<Box<dyn HttpClient>> {
+        let mut client = reqwest::Client::builder();
+        if let Some(ca_cert) = &self.config.acme.ca_cert {
+            let pem = std::fs::read(ca_cert)?;
+            client = client.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        Ok(Box::new(AcmeHttpClient(client.build()?)))
+    }
+
+    async fn issue(&self, domain: &str) -> Result<()> {
+        let account = self.account().await?;
+        let mut order = account
+            .new_order(&NewOrder {
+                identifiers: &[Identifier::Dns(domain.to_string())],
+            })
+            .await?;
+
+        let mut tokens = Vec::new();
+        let result = self.complete_order(domain, &mut order, &mut tokens).await;
+
+        for token in tokens {
+            self.challenges.remove(&token);
+        }
+
+        let (cert_pem, key_pem) = result?;
+        self.certificates.store(domain, &cert_pem, &key_pem)
+    }
+
+    async fn complete_order(
+        &self,
+        domain: &str,
+        order: &mut instant_acme::Order,
+        tokens: &mut Vec<String>,
+    ) -> Result<(String, String)> {
+        let mut challenge_urls = Vec::new();
+        for authz in order.authorizations().await? {
+            match authz.status {
+                AuthorizationStatus::Pending => {}
+                AuthorizationStatus::Valid => continue,
+                status => bail!("unexpected authorization status: {status:?}"),
+            }
+
+            let challenge = authz
+                .challenges
+                .iter()
+                .find(|c| c.r#type == ChallengeType::Http01)
+                .ok_or_else(|| eyre!("no http-01 challenge offered"))?;
+
+            let key_authorization = order.key_authorization(challenge);
+            self.challenges.insert(
+                challenge.token.clone(),
+                key_authorization.as_str().to_string(),
+            );
+            tokens.push(challenge.token.clone());
+            challenge_urls.push(challenge.url.clone());
+        }
+
+        for url in &challenge_urls {
+            order.set_challenge_ready(url).await?;
+        }
+
+        let mut delay = Duration::from_millis(250);
+        for _ in 0..10 {
+            tokio::time::sleep(delay).await;
+            let state = order.refresh().await?;
+            match state.status {
+                OrderStatus::Ready => break,
+                OrderStatus::Invalid => bail!("order is invalid"),
+                _ => delay = (delay * 2).min(Duration::from_secs(10)),
+            }
+        }
+
+        if order.state().status != OrderStatus::Ready {
+            bail!("order did not become ready: {:?}", order.state().status);
+        }
+
+        let mut params = rcgen::CertificateParams::new(vec![domain.to_string()])?;
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        let private_key = rcgen::KeyPair::generate()?;
+        let csr = params.serialize_request(&private_key)?;
+
+        order.finalize(csr.der()).await?;
+        let cert_pem = loop {
+            match order.certificate().await? {
+                Some(cert_pem) => break cert_pem,
+                None => tokio::time::sleep(Duration::from_secs(1)).await,
+            }
+        };
+
+        Ok((cert_pem, private_key.serialize_pem()))
+    }
+}
+
+// talks to the ACME server through reqwest, so additional root certificates can be trusted
+struct AcmeHttpClient(reqwest::Client);
+
+impl HttpClient for AcmeHttpClient {
+    fn request(
+        &self,
+        req: Request<Full<Bytes>>,
+    ) -> Pin<Box<dyn Future<Output = Result<BytesResponse, instant_acme::Error>> + Send>> {
+        let client = self.0.clone();
+        Box::pin(async move {
+            let (parts, body) = req.into_parts();
+            let body = body
+                .collect()
+                .await
+                .map_err(|err| instant_acme::Error::Other(Box::new(err)))?
+                .to_bytes();
+
+            let req = reqwest::Request::try_from(Request::from_parts(parts, body))
+                .map_err(|err| instant_acme::Error::Other(Box::new(err)))?;
+            let res = client
+                .execute(req)
+                .await
+                .map_err(|err| instant_acme::Error::Other(Box::new(err)))?;
+
+            Ok(BytesResponse::from(axum::http::Response::from(res)))
+        })
+    }
+}
@@ -1,5 +1,5 @@
 use crate::{
-    app::{App, Website},
+    app::{App, Website, MAX_APP_PASSWORDS},
     utils::valid_public_key,
 };
 use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
@@ -204,6 +204,87 @@ pub async fn remove_public_key(
     Ok((Json(json!({ "success": true }))).into_response())
 }
 
+pub async fn get_app_passwords(
+    session: RequiredSession,
+    State(state): State<App>,
+) -> APIResult<impl IntoResponse> {
+    let app_passwords = state
+        .users
+        .get_app_passwords(session.username())
+        .await
+        .api_internal_error()?;
+
+    Ok((Json(app_passwords)).into_response())
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct AppPasswordRequest {
+    name: String,
+}
+
+pub async fn create_app_password(
+    session: RequiredSession,
+    State(state): State<App>,
+    body: Json<AppPasswordRequest>,
+) -> APIResult<impl IntoResponse> {
+    let name = body.0.name.trim().to_string();
+    if name.is_empty() || name.len() > 64 {
+        return Err(APIError::new(
+            StatusCode::BAD_REQUEST,
+            "name must be between 1 and 64 characters long",
+        ));
+    }
+
+    let existing = state
+        .users
+        .get_app_passwords(session.username())
+        .await
+        .api_internal_error()?;
+    if existing.len() >= MAX_APP_PASSWORDS {
+        return Err(APIError::new(
+            StatusCode::BAD_REQUEST,
+            &format!("you can have at most {MAX_APP_PASSWORDS} app passwords"),
+        ));
+    }
+    if existing
+        .iter()
+        .any(|app_password| app_password.name == name)
+    {
+        return Err(APIError::new(
+            StatusCode::BAD_REQUEST,
+            "name is already in use",
+        ));
+    }
+
+    let password = state
+        .users
+        .create_app_password(session.username(), &name)
+        .await
+        .api_internal_error()?;
+
+    Ok((Json(json!({ "success": true, "password": password }))).into_response())
+}
+
+pub async fn remove_app_password(
+    session: RequiredSession,
+    State(state): State<App>,
+    body: Json<AppPasswordRequest>,
+) -> APIResult<impl IntoResponse> {
+    let removed = state
+        .users
+        .remove_app_password(session.username(), &body.0.name)
+        .await
+        .api_internal_error()?;
+
+    if !removed {
+        return Err(APIError::new(
+            StatusCode::BAD_REQUEST,
+            "app password does not exist",
+        ));
+    }
+    Ok((Json(json!({ "success": true }))).into_response())
+}
+
 #[derive(Debug, serde::Deserialize)]
 pub struct ApplicationRequest {
     pub username: String,
@@ -2,9 +2,15 @@ use super::{
     errors::{APIResult, ApiErrorExt},
     middleware,
 };
-use crate::app::App;
-use axum::{extract::State, response::IntoResponse, Json};
+use crate::{
+    app::App,
+    chat::{moderation::MODERATOR_ROLE, spam::FilterKind},
+    ssg::themes::{self, ThemeFile},
+    web::errors::APIError,
+};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
 use serde_json::json;
+use std::collections::BTreeMap;
 
 pub async fn is_admin(_user: middleware::Admin) -> impl IntoResponse {
     (Json(json!({ "success": true }))).into_response()
@@ -95,3 +101,136 @@ pub async fn delete_user(
     state.users.delete(&id).await.api_internal_error()?;
     Ok((Json(json!({ "success": true }))).into_response())
 }
+
+pub async fn get_themes(
+    _user: middleware::Admin,
+    State(state): State<App>,
+) -> APIResult<impl IntoResponse> {
+    let themes = themes::list(&state.config.themes_dir()).api_internal_error()?;
+    Ok((Json(themes)).into_response())
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct InstallThemeRequest {
+    name: String,
+    // paths relative to the theme directory, e.g. `theme.css` or `_layouts/default.html`
+    files: BTreeMap<String, ThemeFile>,
+}
+
+pub async fn install_theme(
+    _user: middleware::Admin,
+    State(state): State<App>,
+    body: Json<InstallThemeRequest>,
+) -> APIResult<impl IntoResponse> {
+    let InstallThemeRequest { name, files } = body.0;
+    if !themes::is_valid_name(&name) {
+        return Err(APIError::new(StatusCode::BAD_REQUEST, "invalid theme name"));
+    }
+
+    let dir = state.config.themes_dir();
+    tokio::fs::create_dir_all(&dir).await.api_internal_error()?;
+
+    let theme_name = name.clone();
+    tokio::task::spawn_blocking(move || themes::install(&dir, &theme_name, files))
+        .await
+        .api_internal_error()?
+        .map_err(|err| APIError::new(StatusCode::BAD_REQUEST, &err.to_string()))?;
+
+    log::info!("installed theme {name}");
+    Ok((Json(json!({ "success": true }))).into_response())
+}
+
+pub async fn get_chat_filters(
+    _user: middleware::Admin,
+    State(state): State<App>,
+) -> APIResult<impl IntoResponse> {
+    Ok((Json(state.chat.filters())).into_response())
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ChatFilterRequest {
+    kind: FilterKind,
+    pattern: String,
+}
+
+pub async fn add_chat_filter(
+    user: middleware::Admin,
+    State(state): State<App>,
+    body: Json<ChatFilterRequest>,
+) -> APIResult<impl IntoResponse> {
+    let ChatFilterRequest { kind, pattern } = body.0;
+    let rule = state
+        .chat
+        .add_filter(kind, &pattern, &user.0.username)
+        .await
+        .map_err(|err| APIError::new(StatusCode::BAD_REQUEST, &err.to_string()))?;
+    Ok((Json(rule)).into_response())
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ChatFilterIdRequest {
+    id: u64,
+}
+
+pub async fn delete_chat_filter(
+    _user: middleware::Admin,
+    State(state): State<App>,
+    body: Json<ChatFilterIdRequest>,
+) -> APIResult<impl IntoResponse> {
+    let deleted = state
+        .chat
+        .remove_filter(body.0.id)
+        .await
+        .api_internal_error()?;
+    if !deleted {
+        return Err(APIError::new(StatusCode::NOT_FOUND, "filter not found"));
+    }
+    Ok((Json(json!({ "success": true }))).into_response())
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ChatModeratorRequest {
+    username: String,
+    moderator: bool,
+}
+
+// grants or revokes the moderator role, admins can always moderate
+pub async fn set_chat_moderator(
+    user: middleware::Admin,
+    State(state): State<App>,
+    body: Json<ChatModeratorRequest>,
+) -> APIResult<impl IntoResponse> {
+    let ChatModeratorRequest {
+        username,
+        moderator,
+    } = body.0;
+    let target = state
+        .users
+        .get(&username)
+        .await
+        .api_internal_error()?
+        .ok_or_else(|| APIError::new(StatusCode::NOT_FOUND, "user not found"))?;
+
+    // other roles aren't replaced, e.g. admins would lose access to this api
+    if target
+        .role
+        .as_deref()
+        .is_some_and(|role| role != MODERATOR_ROLE)
+    {
+        return Err(APIError::new(
+            StatusCode::BAD_REQUEST,
+            "user already has a different role",
+        ));
+    }
+
+    state
+        .users
+        .update_role(&username, moderator.then_some(MODERATOR_ROLE))
+        .await
+        .api_internal_error()?;
+    log::info!(
+        "{} set chat moderator of {username} to {moderator}",
+        user.0.username
+    );
+    Ok((Json(json!({ "success": true }))).into_response())
+}
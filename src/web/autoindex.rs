@@ -0,0 +1,319 @@
+use std::path::Path;
+
+use axum::{
+    extract::Query,
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use eyre::Result;
+use percent_encoding::utf8_percent_encode;
+use serde::{Deserialize, Serialize};
+
+use super::{errors::APIError, site_config::SiteConfig};
+use crate::utils::{escape_html, PATH_SEGMENT};
+
+// a directory containing this file is listed even if the site config doesn't enable it
+const MARKER_FILE: &str = ".autoindex";
+
+// never listed, besides hidden files
+const EXCLUDED: &[&str] = &[
+    "_layouts",
+    "_includes",
+    "_themes",
+    "_site.toml",
+    "_dawdle.toml",
+    "_redirects",
+    "_headers",
+];
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: Option<u64>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    modified: Option<time::OffsetDateTime>,
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SortBy {
+    #[default]
+    Name,
+    Size,
+    Modified,
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ListingQuery {
+    #[serde(default)]
+    sort: SortBy,
+    #[serde(default)]
+    order: Order,
+}
+
+pub async fn is_enabled(config: &SiteConfig, dir: &Path, request_path: &str) -> bool {
+    config.autoindex_enabled(request_path)
+        || tokio::fs::try_exists(dir.join(MARKER_FILE))
+            .await
+            .unwrap_or(false)
+}
+
+pub async fn render(base_path: &Path, dir: &Path, uri: &Uri, headers: &HeaderMap) -> Response {
+    let query = Query::<ListingQuery>::try_from_uri(uri)
+        .map(|q| q.0)
+        .unwrap_or_default();
+
+    let mut entries = match read_entries(base_path, dir).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            return APIError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("failed to read directory: {err}"),
+            )
+            .into_response()
+        }
+    };
+
+    entries.sort_by(|a, b| {
+        let ordering = match query.sort {
+            SortBy::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            SortBy::Size => a.size.cmp(&b.size),
+            SortBy::Modified => a.modified.cmp(&b.modified),
+        };
+
+        let ordering = match query.order {
+            Order::Asc => ordering,
+            Order::Desc => ordering.reverse(),
+        };
+
+        // directories always come first
+        b.is_dir.cmp(&a.is_dir).then(ordering)
+    });
+
+    let wants_json = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/json"));
+
+    let mut res = if wants_json {
+        Json(entries).into_response()
+    } else {
+        Html(render_html(uri.path(), &entries, &query)).into_response()
+    };
+
+    res.headers_mut().insert(
+        header::CACHE_CONTROL,
+        header::HeaderValue::from_static("no-cache"),
+    );
+    res.headers_mut()
+        .insert(header::VARY, header::HeaderValue::from_static("accept"));
+    res
+}
+
+async fn read_entries(base_path: &Path, dir: &Path) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') || EXCLUDED.contains(&name.as_str()) {
+            continue;
+        }
+
+        let meta = entry.metadata().await?;
+        if meta.is_symlink() {
+            continue;
+        }
+        // drafts aren't served, so they aren't listed either
+        if name.ends_with(".md")
+            && crate::ssg::is_hidden_draft(base_path.to_path_buf(), entry.path()).await?
+        {
+            continue;
+        }
+
+        entries.push(Entry {
+            name,
+            is_dir: meta.is_dir(),
+            size: meta.is_file().then_some(meta.len()),
+            modified: meta.modified().ok().map(time::OffsetDateTime::from),
+        });
+    }
+
+    Ok(entries)
+}
+
+fn render_html(path: &str, entries: &[Entry], query: &ListingQuery) -> String {
+    let base = path.trim_end_matches('/');
+    let title = escape_html(if path.is_empty() { "/" } else { path });
+
+    // clicking the active column flips the order
+    let sort_link = |sort: SortBy, label: &str| {
+        let order = match query.sort == sort && query.order == Order::Asc {
+            true => "desc",
+            false => "asc",
+        };
+        let sort = match sort {
+            SortBy::Name => "name",
+            SortBy::Size => "size",
+            SortBy::Modified => "modified",
+        };
+        format!("<a href=\"?sort={sort}&amp;order={order}\">{label}</a>")
+    };
+
+    let mut rows = String::new();
+    if !base.is_empty() {
+        let parent = match base.rsplit_once('/') {
+            Some(("", _)) | None => "/",
+            Some((parent, _)) => parent,
+        };
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}\">../</a></td><td></td><td></td></tr>\n",
+            escape_html(parent)
+        ));
+    }
+
+    for entry in entries {
+        let href = format!("{base}/{}", utf8_percent_encode(&entry.name, PATH_SEGMENT));
+        let name = match entry.is_dir {
+            true => format!("{}/", entry.name),
+            false => entry.name.clone(),
+        };
+        let size = entry.size.map(format_size).unwrap_or_default();
+        let modified = entry
+            .modified
+            .and_then(|m| {
+                m.format(time::macros::format_description!(
+                    "[year]-[month]-[day] [hour]:[minute]"
+                ))
+                .ok()
+            })
+            .unwrap_or_default();
+
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            escape_html(&href),
+            escape_html(&name),
+            size,
+            modified
+        ));
+    }
+
+    format!(
+        r#"<!DOCTYPE html><html><head><meta charset="utf-8"><meta name="viewport" content="width=device-width, initial-scale=1"><title>Index of {title}</title>
+<style>body{{font-family:monospace;margin:2rem}}table{{border-collapse:collapse}}td,th{{padding:.2rem 1rem;text-align:left}}</style></head>
+<body><h1>Index of {title}</h1><table><thead><tr><th>{}</th><th>{}</th><th>{}</th></tr></thead><tbody>
+{rows}</tbody></table></body></html>"#,
+        sort_link(SortBy::Name, "Name"),
+        sort_link(SortBy::Size, "Size"),
+        sort_link(SortBy::Modified, "Modified"),
+    )
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} {}", size, UNITS[unit]),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, size: Option<u64>) -> Entry {
+        Entry {
+            name: name.to_string(),
+            is_dir: size.is_none(),
+            size,
+            modified: None,
+        }
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1024), "1.0 KiB");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MiB");
+        assert_eq!(format_size(u64::MAX), "16777216.0 TiB");
+    }
+
+    #[test]
+    fn links_and_escapes_entries() {
+        let entries = [entry("a b#1.txt", Some(10)), entry("<dir>", None)];
+        let html = render_html("/files/", &entries, &ListingQuery::default());
+
+        assert!(html.contains(r#"<a href="/files/a%20b%231.txt">a b#1.txt</a>"#));
+        assert!(html.contains(r#"<a href="/files/%3Cdir%3E">&lt;dir&gt;/</a>"#));
+        assert!(html.contains(r#"<a href="/">../</a>"#));
+        assert!(html.contains("<td>10 B</td>"));
+    }
+
+    #[test]
+    fn links_to_parent_directories() {
+        let html = render_html("/", &[], &ListingQuery::default());
+        assert!(!html.contains("../"));
+
+        let html = render_html("/a/b/", &[], &ListingQuery::default());
+        assert!(html.contains(r#"<a href="/a">../</a>"#));
+    }
+
+    #[test]
+    fn flips_the_order_of_the_active_column() {
+        let query = ListingQuery {
+            sort: SortBy::Size,
+            order: Order::Asc,
+        };
+        let html = render_html("/", &[], &query);
+        assert!(html.contains("?sort=size&amp;order=desc"));
+        assert!(html.contains("?sort=name&amp;order=asc"));
+    }
+
+    #[tokio::test]
+    async fn skips_hidden_and_config_files() {
+        let dir = std::env::temp_dir().join(format!("dawdle-autoindex-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        for file in ["index.txt", ".hidden", "_redirects", "_dawdle.toml"] {
+            std::fs::write(dir.join(file), "x").unwrap();
+        }
+        std::fs::write(dir.join("post.md"), "# post").unwrap();
+        std::fs::write(dir.join("draft.md"), "---\ndraft: true\n---\n# draft").unwrap();
+
+        let mut names = read_entries(&dir, &dir)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.name, entry.is_dir))
+            .collect::<Vec<_>>();
+        names.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            names,
+            [
+                ("index.txt".to_string(), false),
+                ("post.md".to_string(), false),
+                ("sub".to_string(), true)
+            ]
+        );
+    }
+}
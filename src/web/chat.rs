@@ -11,5 +11,8 @@ pub async fn handler(
     State(state): State<App>,
 ) -> impl IntoResponse {
     let username = session.username().map(|s| s.to_string());
-    ws.on_upgrade(move |socket| crate::chat::handle_chat_socket(socket, username, state))
+    let max_size = state.config.chat.max_request_size();
+    ws.max_message_size(max_size)
+        .max_frame_size(max_size)
+        .on_upgrade(move |socket| crate::chat::handle_chat_socket(socket, username, state))
 }
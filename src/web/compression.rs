@@ -0,0 +1,348 @@
+use std::{
+    ffi::OsString,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        LazyLock,
+    },
+    time::SystemTime,
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+};
+use dashmap::DashMap;
+use mime_guess::Mime;
+use tokio::io::AsyncReadExt;
+
+// smaller responses aren't worth the overhead
+const MIN_SIZE: u64 = 1024;
+
+// larger files are streamed as-is instead of being compressed in memory
+const MAX_SIZE: u64 = 8 * 1024 * 1024;
+
+// total size of compressed files kept in memory
+const CACHE_CAPACITY: usize = 64 * 1024 * 1024;
+
+const BROTLI_QUALITY: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut writer =
+                    brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, 22);
+                writer.write_all(data)?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+// encodings the client accepts, most preferred first
+pub fn accepted_encodings(headers: &HeaderMap) -> Vec<Encoding> {
+    let mut accepted = headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|part| {
+            let mut params = part.split(';').map(str::trim);
+            let encoding = match params.next()?.to_ascii_lowercase().as_str() {
+                "br" => Encoding::Brotli,
+                "gzip" | "x-gzip" => Encoding::Gzip,
+                _ => return None,
+            };
+
+            let quality = params
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            (quality > 0.0).then_some((encoding, quality))
+        })
+        .collect::<Vec<_>>();
+
+    // brotli wins ties as it's listed first
+    accepted.sort_by_key(|(encoding, _)| *encoding != Encoding::Brotli);
+    accepted.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    accepted.into_iter().map(|(encoding, _)| encoding).collect()
+}
+
+pub fn is_compressible(mime: &Mime) -> bool {
+    match (mime.type_().as_str(), mime.subtype().as_str()) {
+        ("text", _) => true,
+        ("image", "svg") => true,
+        ("application", "javascript" | "x-javascript" | "json" | "xml" | "wasm") => true,
+        // e.g. `application/ld+json`, the mime crate splits off the suffix
+        ("application", _) => matches!(mime.suffix().map(|s| s.as_str()), Some("json" | "xml")),
+        _ => false,
+    }
+}
+
+pub fn worth_compressing(size: u64) -> bool {
+    (MIN_SIZE..=MAX_SIZE).contains(&size)
+}
+
+// `index.html.br` or `index.html.gz` next to the requested file, in order of preference
+pub async fn open_precompressed(
+    path: &Path,
+    accepted: &[Encoding],
+) -> Option<(Encoding, tokio::fs::File)> {
+    for encoding in accepted {
+        let mut sibling = OsString::from(path.as_os_str());
+        sibling.push(".");
+        sibling.push(encoding.extension());
+
+        let Ok(file) = tokio::fs::File::open(&sibling).await else {
+            continue;
+        };
+
+        if file.metadata().await.is_ok_and(|meta| meta.is_file()) {
+            return Some((*encoding, file));
+        }
+    }
+
+    None
+}
+
+struct CachedFile {
+    modified: Option<SystemTime>,
+    len: u64,
+    data: Bytes,
+}
+
+static CACHE: LazyLock<DashMap<(PathBuf, Encoding), CachedFile>> = LazyLock::new(DashMap::new);
+static CACHE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+// compresses the file at `path`, reusing the last result as long as the file hasn't changed
+pub async fn compress_file(
+    path: &Path,
+    meta: &std::fs::Metadata,
+    encoding: Encoding,
+    file: &mut tokio::fs::File,
+) -> std::io::Result<Bytes> {
+    let key = (path.to_path_buf(), encoding);
+    let modified = meta.modified().ok();
+
+    if let Some(cached) = CACHE.get(&key) {
+        if cached.modified == modified && cached.len == meta.len() {
+            return Ok(cached.data.clone());
+        }
+    }
+
+    let mut data = Vec::with_capacity(meta.len() as usize);
+    file.read_to_end(&mut data).await?;
+    let data = compress(encoding, data).await?;
+
+    // dropping everything is crude, but keeps memory bounded without tracking usage
+    if CACHE_SIZE.fetch_add(data.len(), Ordering::Relaxed) + data.len() > CACHE_CAPACITY {
+        CACHE.clear();
+        CACHE_SIZE.store(data.len(), Ordering::Relaxed);
+    }
+
+    let previous = CACHE.insert(
+        key,
+        CachedFile {
+            modified,
+            len: meta.len(),
+            data: data.clone(),
+        },
+    );
+    if let Some(previous) = previous {
+        CACHE_SIZE.fetch_sub(previous.data.len(), Ordering::Relaxed);
+    }
+
+    Ok(data)
+}
+
+async fn compress(encoding: Encoding, data: Vec<u8>) -> std::io::Result<Bytes> {
+    tokio::task::spawn_blocking(move || encoding.compress(&data))
+        .await
+        .map_err(std::io::Error::other)?
+        .map(Bytes::from)
+}
+
+// compresses generated responses (rendered markdown, directory listings, error pages),
+// files are handled by `compress_file` so the result can be cached
+pub async fn compress_response(res: Response, accepted: &[Encoding]) -> Response {
+    let compressible = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<Mime>().ok())
+        .is_some_and(|mime| is_compressible(&mime));
+
+    // streamed bodies don't know their size, those are files
+    let size = res.body().size_hint().exact();
+
+    if !compressible
+        || res.headers().contains_key(header::CONTENT_ENCODING)
+        || !matches!(res.status(), StatusCode::OK | StatusCode::NOT_FOUND)
+        || !size.is_some_and(worth_compressing)
+    {
+        return res;
+    }
+
+    let (mut parts, body) = res.into_parts();
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+
+    let Some(encoding) = accepted.first().copied() else {
+        return Response::from_parts(parts, body);
+    };
+
+    let data = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(data) => data,
+        Err(err) => {
+            log::error!("failed to read response body: {err}");
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+
+    match compress(encoding, data.to_vec()).await {
+        Ok(compressed) => {
+            parts.headers.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.name()),
+            );
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(compressed))
+        }
+        Err(err) => {
+            log::error!("failed to compress response: {err}");
+            Response::from_parts(parts, Body::from(data))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn accepted(values: &[&str]) -> Vec<Encoding> {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(header::ACCEPT_ENCODING, value.parse().unwrap());
+        }
+        accepted_encodings(&headers)
+    }
+
+    #[test]
+    fn orders_encodings_by_quality() {
+        assert_eq!(accepted(&[]), []);
+        assert_eq!(
+            accepted(&["gzip, deflate, br"]),
+            [Encoding::Brotli, Encoding::Gzip]
+        );
+        assert_eq!(
+            accepted(&["br;q=0.5, gzip;q=0.8"]),
+            [Encoding::Gzip, Encoding::Brotli]
+        );
+        assert_eq!(
+            accepted(&["GZIP", "br ; q=0.9"]),
+            [Encoding::Gzip, Encoding::Brotli]
+        );
+        assert_eq!(accepted(&["x-gzip"]), [Encoding::Gzip]);
+    }
+
+    #[test]
+    fn skips_refused_and_unknown_encodings() {
+        assert_eq!(accepted(&["br;q=0, gzip"]), [Encoding::Gzip]);
+        assert_eq!(accepted(&["identity, deflate, *"]), []);
+        // an invalid quality counts as the default
+        assert_eq!(accepted(&["br;q=high"]), [Encoding::Brotli]);
+    }
+
+    #[test]
+    fn detects_compressible_types() {
+        for mime in [
+            "text/html",
+            "image/svg+xml",
+            "application/json",
+            "application/ld+json",
+        ] {
+            assert!(is_compressible(&mime.parse().unwrap()), "{mime}");
+        }
+        for mime in ["image/png", "application/zip", "video/mp4"] {
+            assert!(!is_compressible(&mime.parse().unwrap()), "{mime}");
+        }
+    }
+
+    #[test]
+    fn only_compresses_medium_sizes() {
+        assert!(!worth_compressing(MIN_SIZE - 1));
+        assert!(worth_compressing(MIN_SIZE));
+        assert!(worth_compressing(MAX_SIZE));
+        assert!(!worth_compressing(MAX_SIZE + 1));
+    }
+
+    #[test]
+    fn gzip_round_trips() {
+        let data = "hello ".repeat(1000);
+        let compressed = Encoding::Gzip.compress(data.as_bytes()).unwrap();
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[tokio::test]
+    async fn compresses_generated_responses() {
+        let response = |body: String, content_type: &'static str| {
+            Response::builder()
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let res =
+            compress_response(response("a".repeat(2048), "text/html"), &[Encoding::Gzip]).await;
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(res.headers()[header::VARY], "accept-encoding");
+
+        let res = compress_response(response("a".repeat(10), "text/html"), &[Encoding::Gzip]).await;
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+
+        let res =
+            compress_response(response("a".repeat(2048), "image/png"), &[Encoding::Gzip]).await;
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+
+        let res = compress_response(response("a".repeat(2048), "text/html"), &[]).await;
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(res.headers()[header::VARY], "accept-encoding");
+    }
+}
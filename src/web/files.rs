@@ -3,11 +3,19 @@ use std::convert::Infallible;
 use std::io::SeekFrom;
 use std::ops::RangeInclusive;
 use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 
+use super::autoindex;
+use super::compression::{self, Encoding};
 use super::errors::APIError;
-use axum::http::{header, HeaderValue, Method, StatusCode, Uri};
-use axum::response::Response;
+use super::images;
+use super::site_config::{self, Redirect, SiteConfig};
+use crate::ssg::themes;
+use axum::body::{Bytes, HttpBody};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri};
+use axum::response::{Html, Response};
 use axum::{body::Body, extract::Request, response::IntoResponse};
+use futures::{future, stream, StreamExt, TryStreamExt};
 use http_range_header::RangeUnsatisfiableError;
 use httpdate::HttpDate;
 use mime_guess::Mime;
@@ -26,6 +34,14 @@ use percent_encoding::percent_decode;
 // - Added fallback response if fallback file doesn't exist
 // - Removed / redirects
 // - Serve .html files if no file extension is given as fallback
+// - Per-site redirects, headers and fallbacks (see site_config.rs)
+// - Directory listings (see autoindex.rs)
+// - Precompressed files and compression (see compression.rs)
+// - Theme files on every site (see ssg/themes.rs)
+
+// `https://example.com`, set for requests to user sites so absolute links can be generated
+#[derive(Debug, Clone)]
+pub struct SiteUrl(pub String);
 
 pub fn create_dir_service(
     path: PathBuf,
@@ -33,154 +49,414 @@ pub fn create_dir_service(
     fallback: impl IntoResponse + Clone + Send + Sync + 'static,
 ) -> impl Service<Request, Response = impl IntoResponse, Error = Infallible, Future = impl Send> + Clone
 {
-    service_fn(move |req: Request| {
+    service_fn(move |mut req: Request| {
         let base_path = path.clone();
         let fallback_file = fallback_file.clone();
         let fallback = fallback.clone();
 
         async move {
-            if req.method() != Method::GET && req.method() != Method::HEAD {
-                return Ok(
-                    APIError::new(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
-                        .into_response(),
-                );
-            }
-
-            // 301 redirect if the path ends with a slash
-            if let Some(new_uri) = normalize_trailing_slash(req.uri()) {
-                if new_uri.path() != req.uri().path() {
+            let site_config = site_config::load(&base_path).await;
+            let request_path = req.uri().path().to_string();
+
+            match site_config.redirect(req.uri()) {
+                Some(Redirect::Redirect(status, location)) => {
+                    // the location comes from the site's config and can contain anything
+                    let Ok(location) = HeaderValue::try_from(location) else {
+                        return Ok(APIError::new(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "invalid redirect location",
+                        )
+                        .into_response());
+                    };
                     return Ok(Response::builder()
-                        .status(StatusCode::MOVED_PERMANENTLY)
-                        .header(header::LOCATION, new_uri.to_string())
+                        .status(status)
+                        .header(header::LOCATION, location)
                         .body(Body::empty())
                         .unwrap());
                 }
+                Some(Redirect::Rewrite(uri)) => *req.uri_mut() = uri,
+                None => {}
             }
 
-            let path_to_file = match build_and_validate_path(&base_path, req.uri().path()) {
-                None => {
-                    return Ok(
-                        APIError::new(StatusCode::BAD_REQUEST, "invalid path").into_response()
-                    )
+            let fallback_file = site_config
+                .fallback_file(&base_path)
+                .unwrap_or(fallback_file);
+
+            // theme files are available on every site
+            let mut base_path = base_path;
+            if req.uri().path().starts_with(themes::THEMES_PATH) {
+                let asset = themes::asset_path(&base_path, req.uri().path());
+                let Some((theme_dir, uri)) = asset.and_then(|(dir, path)| {
+                    let path_and_query = match req.uri().query() {
+                        Some(query) => format!("{path}?{query}"),
+                        None => path,
+                    };
+                    Some((
+                        dir,
+                        Uri::builder().path_and_query(path_and_query).build().ok()?,
+                    ))
+                }) else {
+                    return Ok(serve_fallback(&fallback_file, fallback).await);
+                };
+
+                base_path = theme_dir;
+                *req.uri_mut() = uri;
+            }
+
+            let is_head = req.method() == Method::HEAD;
+            let accepted = compression::accepted_encodings(req.headers());
+            let res = serve_file(req, base_path, &site_config, fallback_file, fallback).await;
+            let mut res = compression::compress_response(res, &accepted).await;
+            site_config.apply_headers(&request_path, &mut res);
+
+            // rendered pages are generated like for GET, only the body is dropped
+            if is_head {
+                let (mut parts, body) = res.into_parts();
+                if let Some(length) = body.size_hint().exact() {
+                    parts
+                        .headers
+                        .entry(header::CONTENT_LENGTH)
+                        .or_insert(HeaderValue::from(length));
                 }
-                Some(path) => path,
-            };
+                res = Response::from_parts(parts, Body::empty());
+            }
 
-            let buf_chunk_size = 65536;
-            let range_header = req
-                .headers()
-                .get(header::RANGE)
-                .and_then(|value| value.to_str().ok())
-                .map(|s| s.to_owned());
+            Ok(res)
+        }
+    })
+}
 
-            let if_unmodified_since = req
-                .headers()
-                .get(header::IF_UNMODIFIED_SINCE)
-                .and_then(to_http_date);
+async fn serve_file(
+    req: Request,
+    base_path: PathBuf,
+    site_config: &SiteConfig,
+    fallback_file: PathBuf,
+    fallback: impl IntoResponse,
+) -> Response {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return APIError::new(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed").into_response();
+    }
 
-            let if_modified_since = req
-                .headers()
-                .get(header::IF_MODIFIED_SINCE)
-                .and_then(to_http_date);
+    // 301 redirect if the path ends with a slash
+    if let Some(new_uri) = normalize_trailing_slash(req.uri()) {
+        if new_uri.path() != req.uri().path() {
+            return Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(header::LOCATION, new_uri.to_string())
+                .body(Body::empty())
+                .unwrap();
+        }
+    }
 
-            if req.method() == Method::HEAD {
-                return Ok(
-                    APIError::new(StatusCode::INTERNAL_SERVER_ERROR, "not supported yet")
-                        .into_response(),
-                );
-            }
+    let path_to_file = match build_and_validate_path(&base_path, req.uri().path()) {
+        None => return APIError::new(StatusCode::BAD_REQUEST, "invalid path").into_response(),
+        Some(path) => path,
+    };
 
-            let path_to_file = if is_dir(&path_to_file).await {
-                path_to_file.join("index.html")
-            } else {
-                path_to_file
-            };
+    let relative_path = path_to_file
+        .strip_prefix(&base_path)
+        .unwrap_or(&path_to_file);
+    if site_config::is_config_file(relative_path) {
+        return serve_fallback(&fallback_file, fallback).await;
+    }
 
-            let (mut file, mime) = match open_file(&path_to_file).await {
-                Ok(Some(file)) => file,
-                Ok(None) => {
-                    match open_markdown(path_to_file).await {
-                        Ok(Some(file)) => {
-                            return match crate::ssg::render(base_path, file).await {
-                                Ok(res) => Ok(res),
-                                Err(err) => Ok(APIError::new(
-                                    StatusCode::INTERNAL_SERVER_ERROR,
-                                    &format!("failed to render markdown: {}", err),
-                                )
-                                .into_response()),
-                            }
-                        }
-                        Ok(None) => {}
-                        Err(err) => return Ok(err.into_response()),
-                    }
+    let buf_chunk_size = 65536;
+    let range_header = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map(|s| s.to_owned());
 
-                    let Ok(mut file) = tokio::fs::File::open(&fallback_file).await else {
-                        return Ok(fallback.into_response());
-                    };
+    let conditions = Conditions::from_headers(req.headers());
 
-                    let mut body = String::new();
-                    if file.read_to_string(&mut body).await.is_err() {
-                        body = "404 Not Found".to_string();
-                    };
+    // ranges are always served from the uncompressed file
+    let accepted = match range_header {
+        Some(_) => Vec::new(),
+        None => compression::accepted_encodings(req.headers()),
+    };
 
-                    let mut resp = Response::builder().body(body.into()).unwrap();
-                    if !fallback_file.ends_with("index.html") {
-                        *resp.status_mut() = StatusCode::NOT_FOUND;
-                    }
-                    return Ok(resp);
-                }
-                Err(err) => return Ok(err.into_response()),
-            };
+    let (path_to_file, dir) = if is_dir(&path_to_file).await {
+        (path_to_file.join("index.html"), Some(path_to_file))
+    } else {
+        (path_to_file, None)
+    };
 
-            let meta = match file.metadata().await {
-                Ok(meta) => meta,
-                Err(_) => {
-                    return Ok(
-                        APIError::new(StatusCode::BAD_REQUEST, "invalid file").into_response()
-                    )
+    let (file, mime, path_to_file) = match open_file(&path_to_file).await {
+        Ok(Some(file)) => file,
+        Ok(None) => {
+            match open_markdown(path_to_file).await {
+                Ok(Some(markdown)) => {
+                    match crate::ssg::render(base_path.clone(), markdown).await {
+                        Ok(Some(page)) => return build_page_response(page, &conditions),
+                        // drafts are served like missing pages
+                        Ok(None) => {}
+                        Err(err) => return render_error(err),
+                    }
                 }
-            };
-
-            if !meta.is_file() || meta.is_symlink() {
-                return Ok(fallback.into_response());
+                Ok(None) => {}
+                Err(err) => return err.into_response(),
             }
 
-            let last_modified: Option<HttpDate> = meta.modified().ok().map(|time| time.into());
-            if let Some(resp) =
-                check_modified_headers(last_modified, if_unmodified_since, if_modified_since)
+            // index and tag pages of collections, feeds and the sitemap
+            let request_path = percent_decode(req.uri().path().as_bytes()).decode_utf8_lossy();
+            let site_url = req.extensions().get::<SiteUrl>().map(|url| url.0.clone());
+            match crate::ssg::render_generated(
+                base_path.clone(),
+                request_path.to_string(),
+                site_url,
+            )
+            .await
             {
-                return Ok(resp);
+                Ok(Some(page)) => return build_page_response(page, &conditions),
+                Ok(None) => {}
+                Err(err) => return render_error(err),
             }
 
-            let maybe_range = try_parse_range(range_header.as_deref(), meta.len());
-            if let Some(Ok(ranges)) = maybe_range.as_ref() {
-                // if there is any other amount of ranges than 1 we'll return an
-                // unsatisfiable later as there isn't yet support for multipart ranges
-                if ranges.len() == 1
-                    && file
-                        .seek(SeekFrom::Start(*ranges[0].start()))
-                        .await
-                        .is_err()
-                {
-                    return Ok(
-                        APIError::new(StatusCode::BAD_REQUEST, "invalid range").into_response()
-                    );
+            if let Some(dir) = dir {
+                if autoindex::is_enabled(site_config, &dir, req.uri().path()).await {
+                    return autoindex::render(&base_path, &dir, req.uri(), req.headers()).await;
                 }
             }
 
-            // we can actually return the file now
-            Ok(build_response(FileOutput {
-                chunk_size: buf_chunk_size,
-                file: Some(file),
-                last_modified,
-                maybe_range,
-                metadata: meta,
-                mime,
-            }))
+            return serve_fallback(&fallback_file, fallback).await;
+        }
+        Err(err) => return err.into_response(),
+    };
+
+    if path_to_file.extension().is_some_and(|ext| ext == "md") {
+        match crate::ssg::is_hidden_draft(base_path, path_to_file.clone()).await {
+            Ok(false) => {}
+            Ok(true) => return serve_fallback(&fallback_file, fallback).await,
+            Err(err) => return render_error(err),
+        }
+    }
+
+    // resized, converted or stripped images are served from the image cache
+    let (mut file, mime, path_to_file) =
+        match images::transform(&path_to_file, mime.as_ref(), req.uri().query()).await {
+            Ok(Some((file, mime, path))) => (file, Some(mime), path),
+            Ok(None) => (file, mime, path_to_file),
+            Err(err) => return err.into_response(),
+        };
+
+    let compressible = mime.as_ref().is_some_and(compression::is_compressible);
+    let mut encoding = None;
+    let mut precompressed = false;
+    if let Some((found, sibling)) = compression::open_precompressed(&path_to_file, &accepted).await
+    {
+        file = sibling;
+        encoding = Some(found);
+        precompressed = true;
+    }
+
+    let meta = match file.metadata().await {
+        Ok(meta) => meta,
+        Err(_) => return APIError::new(StatusCode::BAD_REQUEST, "invalid file").into_response(),
+    };
+
+    if !meta.is_file() || meta.is_symlink() {
+        return fallback.into_response();
+    }
+
+    if encoding.is_none() && compressible && compression::worth_compressing(meta.len()) {
+        encoding = accepted.first().copied();
+    }
+
+    let last_modified: Option<HttpDate> = meta.modified().ok().map(|time| time.into());
+    let etag = file_etag(&meta, encoding);
+    if let Some(resp) = check_modified_headers(last_modified, &etag, &conditions) {
+        return resp;
+    }
+
+    // a range is only honored if the client's copy is still current (If-Range)
+    let range_header = range_header.filter(|_| conditions.range_applies(last_modified, &etag));
+    let maybe_range = try_parse_range(range_header.as_deref(), meta.len())
+        .filter(|ranges| !matches!(ranges, Ok(ranges) if ranges.len() > MAX_RANGES));
+
+    if let Some(Ok(ranges)) = maybe_range.as_ref() {
+        // multipart ranges seek for every part while streaming
+        if ranges.len() == 1
+            && file
+                .seek(SeekFrom::Start(*ranges[0].start()))
+                .await
+                .is_err()
+        {
+            return APIError::new(StatusCode::BAD_REQUEST, "invalid range").into_response();
+        }
+    }
+
+    let compressed = match encoding {
+        Some(encoding) if !precompressed => {
+            match compression::compress_file(&path_to_file, &meta, encoding, &mut file).await {
+                Ok(data) => Some(data),
+                Err(err) => {
+                    return APIError::new(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        &format!("failed to compress file: {}", err),
+                    )
+                    .into_response()
+                }
+            }
+        }
+        _ => None,
+    };
+
+    // we can actually return the file now
+    build_response(FileOutput {
+        chunk_size: buf_chunk_size,
+        file: (req.method() != Method::HEAD).then_some(file),
+        last_modified,
+        etag,
+        maybe_range,
+        metadata: meta,
+        mime,
+        encoding,
+        compressed,
+        vary: compressible || precompressed,
+    })
+}
+
+// more ranges than this are answered with the whole file
+const MAX_RANGES: usize = 32;
+
+#[derive(Default)]
+struct Conditions {
+    if_match: Option<String>,
+    if_none_match: Option<String>,
+    if_unmodified_since: Option<HttpDate>,
+    if_modified_since: Option<HttpDate>,
+    if_range: Option<String>,
+}
+
+impl Conditions {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let string = |name| {
+            headers
+                .get(name)
+                .and_then(|value: &HeaderValue| value.to_str().ok())
+                .map(|s| s.to_owned())
+        };
+
+        Self {
+            if_match: string(header::IF_MATCH),
+            if_none_match: string(header::IF_NONE_MATCH),
+            if_unmodified_since: headers
+                .get(header::IF_UNMODIFIED_SINCE)
+                .and_then(to_http_date),
+            if_modified_since: headers
+                .get(header::IF_MODIFIED_SINCE)
+                .and_then(to_http_date),
+            if_range: string(header::IF_RANGE),
+        }
+    }
+
+    fn range_applies(&self, modified: Option<HttpDate>, etag: &str) -> bool {
+        let Some(if_range) = &self.if_range else {
+            return true;
+        };
+
+        if if_range.starts_with('"') {
+            return if_range == etag;
+        }
+
+        match (httpdate::parse_http_date(if_range), modified) {
+            (Ok(date), Some(modified)) => HttpDate::from(date) == modified,
+            _ => false,
+        }
+    }
+}
+
+// size and modification time, changes whenever the file is written
+fn file_etag(meta: &std::fs::Metadata, encoding: Option<Encoding>) -> String {
+    let tag = etag_value(meta.len(), meta.modified().ok());
+
+    // every encoding is a different representation and needs its own tag
+    match encoding {
+        Some(encoding) => format!("\"{tag}-{}\"", encoding.name()),
+        None => format!("\"{tag}\""),
+    }
+}
+
+fn etag_value(len: u64, modified: Option<std::time::SystemTime>) -> String {
+    let modified = modified
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+
+    format!("{:x}-{:x}", len, modified)
+}
+
+fn render_error(err: eyre::Report) -> Response {
+    match err.downcast::<crate::ssg::TemplateError>() {
+        Ok(err) => (StatusCode::INTERNAL_SERVER_ERROR, Html(err.to_html())).into_response(),
+        Err(err) => APIError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("failed to render markdown: {}", err),
+        )
+        .into_response(),
+    }
+}
+
+// rendered pages are compressed later on, so their tag is weak
+fn build_page_response(page: crate::ssg::Page, conditions: &Conditions) -> Response {
+    let last_modified: Option<HttpDate> = page.modified.map(HttpDate::from);
+    let etag = format!(
+        "W/\"{}\"",
+        etag_value(page.body.len() as u64, page.modified)
+    );
+    if let Some(resp) = check_modified_headers(last_modified, &etag, conditions) {
+        return resp;
+    }
+
+    let mut builder = Response::builder()
+        .header(header::CONTENT_TYPE, page.content_type)
+        .header(header::CACHE_CONTROL, "no-cache")
+        .header(header::ETAG, etag);
+    if let Some(last_modified) = last_modified {
+        builder = builder.header(header::LAST_MODIFIED, last_modified.to_string());
+    }
+
+    builder.body(Body::from(page.body)).unwrap()
+}
+
+// checks a list of entity tags like `"a", W/"b"` against `etag`,
+// the strong comparison fails if either of them is weak
+fn etag_matches(list: &str, etag: &str, weak: bool) -> bool {
+    fn split(tag: &str) -> (bool, &str) {
+        match tag.strip_prefix("W/") {
+            Some(tag) => (true, tag),
+            None => (false, tag),
+        }
+    }
+    let (etag_weak, etag) = split(etag);
+
+    list.split(',').map(str::trim).any(|tag| {
+        if tag == "*" {
+            return true;
         }
+
+        let (tag_weak, tag) = split(tag);
+        tag == etag && (weak || !(tag_weak || etag_weak))
     })
 }
 
+async fn serve_fallback(fallback_file: &Path, fallback: impl IntoResponse) -> Response {
+    let Ok(mut file) = tokio::fs::File::open(fallback_file).await else {
+        return fallback.into_response();
+    };
+
+    let mut body = String::new();
+    if file.read_to_string(&mut body).await.is_err() {
+        body = "404 Not Found".to_string();
+    };
+
+    let mut resp = Response::builder().body(body.into()).unwrap();
+    if !fallback_file.ends_with("index.html") {
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+    }
+    resp
+}
+
 struct FileOutput {
     // not included on HEAD requests
     pub(super) file: Option<tokio::fs::File>,
@@ -190,18 +466,34 @@ struct FileOutput {
     pub(super) mime: Option<Mime>,
     pub(super) maybe_range: Option<Result<Vec<RangeInclusive<u64>>, RangeUnsatisfiableError>>,
     pub(super) last_modified: Option<HttpDate>,
+    pub(super) etag: String,
+
+    pub(super) encoding: Option<Encoding>,
+    // compressed in memory, replaces the file
+    pub(super) compressed: Option<Bytes>,
+    pub(super) vary: bool,
 }
 
 async fn is_dir(path: &PathBuf) -> bool {
     tokio::fs::metadata(path)
         .await
-        .map_or(false, |meta_data| meta_data.is_dir())
+        .is_ok_and(|meta_data| meta_data.is_dir())
 }
 
 fn build_response(output: FileOutput) -> Response<Body> {
-    let mut builder = Response::builder().header(header::ACCEPT_RANGES, "bytes");
+    let mut builder = Response::builder()
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, &output.etag);
+
+    if output.vary {
+        builder = builder.header(header::VARY, "accept-encoding");
+    }
 
-    if let Some(mime_val) = output.mime {
+    if let Some(encoding) = output.encoding {
+        builder = builder.header(header::CONTENT_ENCODING, encoding.name());
+    }
+
+    if let Some(mime_val) = &output.mime {
         let mime_header_value = HeaderValue::from_str(mime_val.essence_str()).unwrap();
         builder = builder.header(header::CONTENT_TYPE, mime_header_value);
 
@@ -253,11 +545,33 @@ fn build_response(output: FileOutput) -> Response<Body> {
             };
 
             if ranges.len() > 1 {
-                return APIError::new(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "multipart ranges not supported yet",
-                )
-                .into_response();
+                let content_type = output
+                    .mime
+                    .as_ref()
+                    .map(|mime| mime.essence_str().to_string())
+                    .unwrap_or_else(|| "application/octet-stream".to_string());
+
+                let multipart = Multipart::new(&ranges, &content_type, size);
+                let length = multipart.content_length(&ranges);
+                let boundary = multipart.boundary.clone();
+
+                let body = match output.file {
+                    Some(file) => multipart.into_body(file, ranges, output.chunk_size),
+                    None => Body::empty(),
+                };
+
+                // replace the content type of the file itself
+                let mut res = builder
+                    .header(header::CONTENT_LENGTH, length)
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .body(body)
+                    .unwrap();
+                res.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_str(&format!("multipart/byteranges; boundary={boundary}"))
+                        .unwrap(),
+                );
+                return res;
             }
 
             let body = if let Some(file) = output.file {
@@ -288,6 +602,19 @@ fn build_response(output: FileOutput) -> Response<Body> {
 
         // Not a range request
         None => {
+            if let Some(compressed) = output.compressed {
+                let len = compressed.len();
+                let body = match output.file {
+                    Some(_) => Body::from(compressed),
+                    None => Body::empty(),
+                };
+
+                return builder
+                    .header(header::CONTENT_LENGTH, len.to_string())
+                    .body(body)
+                    .unwrap();
+            }
+
             let body = if let Some(file) = output.file {
                 Body::from_stream(ReaderStream::with_capacity(file, output.chunk_size))
             } else {
@@ -302,11 +629,83 @@ fn build_response(output: FileOutput) -> Response<Body> {
     }
 }
 
+// a `multipart/byteranges` body, see RFC 9110 section 14.6
+struct Multipart {
+    boundary: String,
+    part_headers: Vec<String>,
+    end: String,
+}
+
+impl Multipart {
+    fn new(ranges: &[RangeInclusive<u64>], content_type: &str, size: u64) -> Self {
+        let boundary = cuid2::cuid();
+        let part_headers = ranges
+            .iter()
+            .map(|range| {
+                format!(
+                    "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: bytes {}-{}/{size}\r\n\r\n",
+                    range.start(),
+                    range.end()
+                )
+            })
+            .collect();
+        let end = format!("\r\n--{boundary}--\r\n");
+
+        Self {
+            boundary,
+            part_headers,
+            end,
+        }
+    }
+
+    fn content_length(&self, ranges: &[RangeInclusive<u64>]) -> u64 {
+        let headers: usize = self.part_headers.iter().map(String::len).sum();
+        let data: u64 = ranges.iter().map(|r| r.end() - r.start() + 1).sum();
+        headers as u64 + data + self.end.len() as u64
+    }
+
+    fn into_body(
+        self,
+        file: tokio::fs::File,
+        ranges: Vec<RangeInclusive<u64>>,
+        chunk_size: usize,
+    ) -> Body {
+        let file = Arc::new(file);
+        let parts = ranges.into_iter().zip(self.part_headers);
+
+        let stream = stream::iter(parts)
+            .then(move |(range, part_header)| {
+                let file = file.clone();
+                async move {
+                    // clones share the cursor, which is fine as parts are read one after another
+                    let mut file = file.try_clone().await?;
+                    file.seek(SeekFrom::Start(*range.start())).await?;
+                    let data = ReaderStream::with_capacity(
+                        file.take(range.end() - range.start() + 1),
+                        chunk_size,
+                    );
+
+                    std::io::Result::Ok(
+                        stream::once(future::ready(Ok(Bytes::from(part_header)))).chain(data),
+                    )
+                }
+            })
+            .try_flatten()
+            .chain(stream::once(future::ready(Ok(Bytes::from(self.end)))));
+
+        Body::from_stream(stream)
+    }
+}
+
 fn try_parse_range(
     maybe_range_ref: Option<&str>,
     file_size: u64,
 ) -> Option<Result<Vec<RangeInclusive<u64>>, RangeUnsatisfiableError>> {
     maybe_range_ref.map(|header_value| {
+        // no byte of an empty file can be requested, but the end would be clamped to 0
+        if file_size == 0 {
+            return Err(RangeUnsatisfiableError::FileSuffixOutOfBounds);
+        }
         http_range_header::parse_range_header(header_value)
             .and_then(|first_pass| first_pass.validate(file_size))
     })
@@ -314,38 +713,54 @@ fn try_parse_range(
 
 fn check_modified_headers(
     modified: Option<HttpDate>,
-    if_unmodified_since: Option<HttpDate>,
-    if_modified_since: Option<HttpDate>,
+    etag: &str,
+    conditions: &Conditions,
 ) -> Option<Response> {
-    if let Some(since) = if_unmodified_since {
+    let precondition_failed = || {
+        Response::builder()
+            .status(StatusCode::PRECONDITION_FAILED)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let not_modified = || {
+        let mut builder = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, etag);
+        if let Some(modified) = modified {
+            builder = builder.header(header::LAST_MODIFIED, modified.to_string());
+        }
+        builder.body(Body::empty()).unwrap()
+    };
+
+    // entity tags take precedence over dates
+    if let Some(if_match) = &conditions.if_match {
+        if !etag_matches(if_match, etag, false) {
+            return Some(precondition_failed());
+        }
+    } else if let Some(since) = conditions.if_unmodified_since {
         let precondition = modified
             .as_ref()
             .map(|time| since >= *time)
             .unwrap_or(false);
 
         if !precondition {
-            return Some(
-                Response::builder()
-                    .status(StatusCode::PRECONDITION_FAILED)
-                    .body(Body::empty())
-                    .unwrap(),
-            );
+            return Some(precondition_failed());
         }
     }
 
-    if let Some(since) = if_modified_since {
+    if let Some(if_none_match) = &conditions.if_none_match {
+        if etag_matches(if_none_match, etag, true) {
+            return Some(not_modified());
+        }
+    } else if let Some(since) = conditions.if_modified_since {
         let unmodified = modified
             .as_ref()
             .map(|time| since >= *time)
             // no last_modified means its always modified
             .unwrap_or(false);
         if unmodified {
-            return Some(
-                Response::builder()
-                    .status(StatusCode::NOT_MODIFIED)
-                    .body(Body::empty())
-                    .unwrap(),
-            );
+            return Some(not_modified());
         }
     }
 
@@ -355,9 +770,9 @@ fn check_modified_headers(
 // returns None if the fallback file doesn't exist
 async fn open_file(
     path_to_file: &PathBuf,
-) -> Result<Option<(tokio::fs::File, Option<Mime>)>, APIError> {
+) -> Result<Option<(tokio::fs::File, Option<Mime>, PathBuf)>, APIError> {
     match tokio::fs::File::open(&path_to_file).await {
-        Ok(file) => Ok(Some((file, guess_mime(path_to_file)))),
+        Ok(file) => Ok(Some((file, guess_mime(path_to_file), path_to_file.clone()))),
         Err(err) => {
             if err.kind() != std::io::ErrorKind::NotFound {
                 return Err(APIError::new(
@@ -368,8 +783,13 @@ async fn open_file(
 
             // try .html if it's not at the end of the file already
             if !path_to_file.ends_with(".html") {
-                if let Ok(file) = tokio::fs::File::open(path_to_file.with_extension("html")).await {
-                    return Ok(Some((file, Some("text/html".parse().unwrap()))));
+                let path_to_html = path_to_file.with_extension("html");
+                if let Ok(file) = tokio::fs::File::open(&path_to_html).await {
+                    return Ok(Some((
+                        file,
+                        Some("text/html".parse().unwrap()),
+                        path_to_html,
+                    )));
                 }
             }
 
@@ -378,10 +798,12 @@ async fn open_file(
     }
 }
 
-async fn open_markdown(path_to_file: PathBuf) -> Result<Option<tokio::fs::File>, APIError> {
-    tokio::fs::File::open(path_to_file.with_extension("md"))
+// returns the path of the markdown file if it exists
+async fn open_markdown(path_to_file: PathBuf) -> Result<Option<PathBuf>, APIError> {
+    let path_to_markdown = path_to_file.with_extension("md");
+    tokio::fs::metadata(&path_to_markdown)
         .await
-        .map(Some)
+        .map(|meta| meta.is_file().then_some(path_to_markdown))
         .or_else(|err| {
             if err.kind() == std::io::ErrorKind::NotFound {
                 Ok(None)
@@ -405,7 +827,10 @@ fn to_http_date(value: &HeaderValue) -> Option<HttpDate> {
         .map(|time| time.into())
 }
 
-fn build_and_validate_path(base_path: &std::path::Path, requested_path: &str) -> Option<PathBuf> {
+pub fn build_and_validate_path(
+    base_path: &std::path::Path,
+    requested_path: &str,
+) -> Option<PathBuf> {
     let path = requested_path.trim_start_matches('/');
     let path_decoded = percent_decode(path.as_ref()).decode_utf8().ok()?;
     let path_decoded = Path::new(&*path_decoded);
@@ -456,9 +881,71 @@ fn normalize_trailing_slash(uri: &Uri) -> Option<Uri> {
     };
 
     parts.path_and_query = new_path_and_query;
-    if let Ok(new_uri) = Uri::from_parts(parts) {
-        Some(new_uri)
-    } else {
-        None
+    Uri::from_parts(parts).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(
+        header: &str,
+        size: u64,
+    ) -> Result<Vec<RangeInclusive<u64>>, RangeUnsatisfiableError> {
+        try_parse_range(Some(header), size).unwrap()
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert!(try_parse_range(None, 100).is_none());
+        assert_eq!(ranges("bytes=0-9", 100), Ok(vec![0..=9]));
+        assert_eq!(ranges("bytes=90-", 100), Ok(vec![90..=99]));
+        assert_eq!(ranges("bytes=-10", 100), Ok(vec![90..=99]));
+        assert_eq!(ranges("bytes=0-1, 5-6", 100), Ok(vec![0..=1, 5..=6]));
+        // the end is clamped to the file size
+        assert_eq!(ranges("bytes=50-500", 100), Ok(vec![50..=99]));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        assert!(ranges("bytes=100-", 100).is_err());
+        assert!(ranges("bytes=9-0", 100).is_err());
+        assert!(ranges("bytes=", 100).is_err());
+        assert!(ranges("lines=0-1", 100).is_err());
+        assert!(ranges("bytes=0-1", 0).is_err());
+        assert!(ranges("bytes=-5", 0).is_err());
+    }
+
+    #[test]
+    fn rejects_overlapping_ranges() {
+        assert!(ranges("bytes=0-10, 5-15", 100).is_err());
+        assert!(ranges("bytes=0-10, 10-15", 100).is_err());
+        assert!(ranges("bytes=0-10, -95", 100).is_err());
+    }
+
+    #[test]
+    fn if_range_matches_etag_or_date() {
+        let modified = HttpDate::from(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000));
+        let conditions = |if_range: &str| Conditions {
+            if_range: Some(if_range.to_string()),
+            ..Default::default()
+        };
+
+        assert!(Conditions::default().range_applies(None, "\"a\""));
+        assert!(conditions("\"a\"").range_applies(Some(modified), "\"a\""));
+        assert!(!conditions("\"b\"").range_applies(Some(modified), "\"a\""));
+        assert!(conditions(&modified.to_string()).range_applies(Some(modified), "\"a\""));
+        assert!(!conditions(&modified.to_string()).range_applies(None, "\"a\""));
+    }
+
+    #[test]
+    fn multipart_length_includes_headers() {
+        let ranges = [0..=1, 5..=9];
+        let multipart = Multipart::new(&ranges, "text/plain", 100);
+        let headers = multipart.part_headers.concat();
+        assert!(headers.contains("Content-Range: bytes 5-9/100"));
+
+        let length = headers.len() + 2 + 5 + multipart.end.len();
+        assert_eq!(multipart.content_length(&ranges), length as u64);
     }
 }
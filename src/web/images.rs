@@ -0,0 +1,574 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::UNIX_EPOCH,
+};
+
+use axum::http::StatusCode;
+use eyre::{bail, Result};
+use image::{
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    metadata::Orientation,
+    DynamicImage, ImageDecoder, ImageReader, Limits,
+};
+use mime_guess::Mime;
+use tokio::sync::Semaphore;
+
+use super::errors::APIError;
+
+// `?w=800&h=600&fit=cover&format=webp&q=80` on any image, the result is cached on disk.
+// jpegs without parameters are served without their metadata (location, camera, ...)
+
+// larger images can't be requested
+const MAX_DIMENSION: u32 = 4096;
+// larger images aren't decoded
+const MAX_SOURCE_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_SOURCE_DIMENSION: u32 = 16384;
+const MAX_DECODE_ALLOC: u64 = 512 * 1024 * 1024;
+
+// different sizes, formats and qualities of the same version of an image
+const MAX_VARIANTS: usize = 32;
+// decoding and encoding is expensive, so only a few images are processed at once
+const MAX_CONCURRENT: usize = 4;
+
+const DEFAULT_QUALITY: u8 = 85;
+// quality for jpegs that have to be rotated when their metadata is removed
+const ROTATED_QUALITY: u8 = 92;
+
+// `data_dir/image-cache`, set once on startup
+static CACHE_DIR: OnceLock<PathBuf> = OnceLock::new();
+static PERMITS: Semaphore = Semaphore::const_new(MAX_CONCURRENT);
+
+// marks jpegs without metadata, so they aren't read again to find that out
+const ORIGINAL_MARKER: &str = "original";
+
+pub fn init(cache_dir: PathBuf) {
+    let _ = CACHE_DIR.set(cache_dir);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Format {
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+}
+
+impl Format {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "jpeg" | "jpg" => Format::Jpeg,
+            "png" => Format::Png,
+            "webp" => Format::Webp,
+            "avif" => Format::Avif,
+            _ => return None,
+        })
+    }
+
+    fn from_mime(mime: &Mime) -> Option<Self> {
+        match mime.essence_str() {
+            "image/jpeg" => Some(Format::Jpeg),
+            "image/png" => Some(Format::Png),
+            "image/webp" => Some(Format::Webp),
+            // the first frame of a gif is saved as png
+            "image/gif" => Some(Format::Png),
+            _ => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpg",
+            Format::Png => "png",
+            Format::Webp => "webp",
+            Format::Avif => "avif",
+        }
+    }
+
+    fn mime(&self) -> Mime {
+        match self {
+            Format::Jpeg => mime_guess::mime::IMAGE_JPEG,
+            Format::Png => mime_guess::mime::IMAGE_PNG,
+            Format::Webp => "image/webp".parse().unwrap(),
+            Format::Avif => "image/avif".parse().unwrap(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Fit {
+    // scaled down to fit into the box
+    Contain,
+    // scaled and cropped to fill the box
+    Cover,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Transform {
+    // only the metadata is removed
+    Strip,
+    Resize {
+        width: Option<u32>,
+        height: Option<u32>,
+        fit: Fit,
+        format: Format,
+        quality: u8,
+    },
+}
+
+impl Transform {
+    // returns None if the query has no image parameters
+    fn parse(query: Option<&str>, source: Format) -> Result<Option<Self>, APIError> {
+        let bad_request = |message: &str| APIError::new(StatusCode::BAD_REQUEST, message);
+        let dimension = |value: &str| {
+            value
+                .parse::<u32>()
+                .ok()
+                .filter(|value| (1..=MAX_DIMENSION).contains(value))
+                .ok_or_else(|| bad_request("invalid image size"))
+        };
+
+        let (mut width, mut height, mut fit, mut format, mut quality) =
+            (None, None, None, None, None);
+        for (key, value) in form_urlencoded(query.unwrap_or_default()) {
+            match key {
+                "w" | "width" => width = Some(dimension(value)?),
+                "h" | "height" => height = Some(dimension(value)?),
+                "fit" => {
+                    fit = Some(match value {
+                        "contain" => Fit::Contain,
+                        "cover" | "crop" => Fit::Cover,
+                        _ => return Err(bad_request("invalid image fit")),
+                    })
+                }
+                "format" | "fm" => {
+                    format = Some(
+                        Format::from_name(value)
+                            .ok_or_else(|| bad_request("invalid image format"))?,
+                    )
+                }
+                "q" | "quality" => {
+                    quality = Some(
+                        value
+                            .parse::<u8>()
+                            .ok()
+                            .filter(|q| (1..=100).contains(q))
+                            .ok_or_else(|| bad_request("invalid image quality"))?,
+                    )
+                }
+                // e.g. `?v=2` for cache busting
+                _ => {}
+            }
+        }
+
+        if (width, height, fit, format, quality) == (None, None, None, None, None) {
+            return Ok((source == Format::Jpeg).then_some(Transform::Strip));
+        }
+
+        let fit = fit.unwrap_or(Fit::Contain);
+        if fit == Fit::Cover && (width.is_none() || height.is_none()) {
+            return Err(bad_request("fit=cover needs a width and height"));
+        }
+
+        Ok(Some(Transform::Resize {
+            width,
+            height,
+            fit,
+            format: format.unwrap_or(source),
+            quality: quality.unwrap_or(DEFAULT_QUALITY),
+        }))
+    }
+
+    fn format(&self, source: Format) -> Format {
+        match self {
+            Transform::Strip => source,
+            Transform::Resize { format, .. } => *format,
+        }
+    }
+}
+
+fn form_urlencoded(query: &str) -> impl Iterator<Item = (&str, &str)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+}
+
+// returns the transformed image, or None if the original should be served
+pub async fn transform(
+    path: &Path,
+    mime: Option<&Mime>,
+    query: Option<&str>,
+) -> Result<Option<(tokio::fs::File, Mime, PathBuf)>, APIError> {
+    let Some(cache_dir) = CACHE_DIR.get() else {
+        return Ok(None);
+    };
+    let Some(source) = mime.and_then(Format::from_mime) else {
+        return Ok(None);
+    };
+    let Some(transform) = Transform::parse(query, source)? else {
+        return Ok(None);
+    };
+
+    let internal_error = |err: std::io::Error| {
+        APIError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("failed to transform image: {err}"),
+        )
+    };
+
+    let meta = tokio::fs::metadata(path).await.map_err(internal_error)?;
+    if meta.len() > MAX_SOURCE_SIZE {
+        return match transform {
+            Transform::Strip => Ok(None),
+            _ => Err(APIError::new(StatusCode::BAD_REQUEST, "image is too large")),
+        };
+    }
+
+    // every source has its own directory, variants are named after the version of the source
+    let source_key = hash(path);
+    let version = format!("{:016x}-", hash((modified(&meta), meta.len())));
+    let dir = cache_dir
+        .join(format!("{:02x}", source_key >> 56))
+        .join(format!("{source_key:016x}"));
+
+    let format = transform.format(source);
+    let name = format!("{version}{:016x}", hash(transform));
+    let cached = dir.join(format!("{name}.{}", format.extension()));
+    let marker = dir.join(format!("{name}.{ORIGINAL_MARKER}"));
+
+    if let Ok(file) = tokio::fs::File::open(&cached).await {
+        return Ok(Some((file, format.mime(), cached)));
+    }
+    if tokio::fs::try_exists(&marker).await.unwrap_or(false) {
+        return Ok(None);
+    }
+
+    if remove_old_variants(&dir, &version).await >= MAX_VARIANTS {
+        return Err(APIError::new(
+            StatusCode::BAD_REQUEST,
+            "too many variants of this image",
+        ));
+    }
+
+    let _permit = PERMITS.acquire().await.map_err(|_| APIError::default())?;
+    let (source_path, target) = (path.to_path_buf(), cached.clone());
+    let result = tokio::task::spawn_blocking(move || process(&source_path, &target, transform))
+        .await
+        .map_err(|_| APIError::default())?;
+
+    match result {
+        Ok(true) => {}
+        // nothing to remove, or files that only look like jpegs, which are served as they are
+        Ok(false) | Err(_) if transform == Transform::Strip => {
+            let written = match tokio::fs::create_dir_all(&dir).await {
+                Ok(()) => tokio::fs::write(&marker, b"").await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = written {
+                log::warn!("failed to write {}: {err}", marker.display());
+            }
+            return Ok(None);
+        }
+        Ok(false) => return Ok(None),
+        Err(err) => {
+            log::warn!("failed to transform image {}: {err}", path.display());
+            return Err(APIError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "failed to transform image",
+            ));
+        }
+    }
+
+    let file = tokio::fs::File::open(&cached)
+        .await
+        .map_err(internal_error)?;
+    Ok(Some((file, format.mime(), cached)))
+}
+
+fn hash(value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn modified(meta: &std::fs::Metadata) -> u128 {
+    meta.modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|time| time.as_nanos())
+        .unwrap_or_default()
+}
+
+// removes the variants of previous versions of the source,
+// returns how many variants of the current version there are
+async fn remove_old_variants(dir: &Path, version: &str) -> usize {
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return 0;
+    };
+
+    let mut current = 0;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with(version) {
+            current += 1;
+        } else if let Err(err) = tokio::fs::remove_file(entry.path()).await {
+            log::warn!("failed to remove {}: {err}", entry.path().display());
+        }
+    }
+    current
+}
+
+// writes the transformed image to `target`, returns false if the original can be used as is
+fn process(source: &Path, target: &Path, transform: Transform) -> Result<bool> {
+    let data = std::fs::read(source)?;
+
+    let output = match transform {
+        Transform::Strip => match strip_jpeg(&data)? {
+            Some(stripped) => stripped,
+            None => return Ok(false),
+        },
+        Transform::Resize {
+            width,
+            height,
+            fit,
+            format,
+            quality,
+        } => {
+            let image = decode(&data)?;
+            let (source_width, source_height) = (image.width(), image.height());
+
+            // images are never scaled up
+            let width = width.unwrap_or(MAX_DIMENSION).min(source_width);
+            let height = height.unwrap_or(MAX_DIMENSION).min(source_height);
+            let image = match fit {
+                Fit::Contain if width == source_width && height == source_height => image,
+                Fit::Contain => image.resize(width, height, FilterType::Lanczos3),
+                Fit::Cover => image.resize_to_fill(width, height, FilterType::Lanczos3),
+            };
+
+            encode(&image, format, quality)?
+        }
+    };
+
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // written next to the target first, so requests never see a partial image
+    let tmp = target.with_extension(format!("{}.tmp", cuid2::create_id()));
+    std::fs::write(&tmp, output)?;
+    std::fs::rename(&tmp, target)?;
+    Ok(true)
+}
+
+// decodes the image with its orientation applied, since the metadata is lost
+fn decode(data: &[u8]) -> Result<DynamicImage> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SOURCE_DIMENSION);
+    limits.max_image_height = Some(MAX_SOURCE_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+fn encode(image: &DynamicImage, format: Format, quality: u8) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    match format {
+        // jpegs have no alpha channel
+        Format::Jpeg => image
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut output, quality))?,
+        Format::Png => image.write_with_encoder(PngEncoder::new(&mut output))?,
+        // only lossless webp is supported, so the quality is ignored
+        Format::Webp => image
+            .to_rgba8()
+            .write_with_encoder(WebPEncoder::new_lossless(&mut output))?,
+        Format::Avif => image
+            .to_rgba8()
+            .write_with_encoder(AvifEncoder::new_with_speed_quality(&mut output, 8, quality))?,
+    }
+    Ok(output)
+}
+
+// removes EXIF, XMP and IPTC metadata from a jpeg without re-encoding it,
+// returns None if there is nothing to remove
+fn strip_jpeg(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    const SOI: u8 = 0xD8;
+    const SOS: u8 = 0xDA;
+    const APP1: u8 = 0xE1; // EXIF and XMP
+    const APP13: u8 = 0xED; // IPTC
+
+    if data.get(..2) != Some(&[0xFF, SOI]) {
+        bail!("not a jpeg");
+    }
+
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(&data[..2]);
+    let mut position = 2;
+    let mut stripped = false;
+
+    while position + 4 <= data.len() {
+        if data[position] != 0xFF {
+            bail!("invalid jpeg marker");
+        }
+        let marker = data[position + 1];
+
+        // the compressed image data follows, which is copied as is
+        if marker == SOS {
+            break;
+        }
+
+        let length = u16::from_be_bytes([data[position + 2], data[position + 3]]) as usize;
+        let end = position + 2 + length;
+        if length < 2 || end > data.len() {
+            bail!("invalid jpeg segment");
+        }
+
+        if marker == APP1 || marker == APP13 {
+            stripped = true;
+        } else {
+            output.extend_from_slice(&data[position..end]);
+        }
+        position = end;
+    }
+
+    if !stripped {
+        return Ok(None);
+    }
+
+    // rotated images would lose their orientation, so they're re-encoded upright instead
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .into_decoder()?;
+    if decoder.orientation()? != Orientation::NoTransforms {
+        return encode(&decode(data)?, Format::Jpeg, ROTATED_QUALITY).map(Some);
+    }
+
+    output.extend_from_slice(&data[position..]);
+    Ok(Some(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // None if the query is rejected
+    fn parse(query: &str, source: Format) -> Option<Option<Transform>> {
+        Transform::parse(Some(query), source).ok()
+    }
+
+    fn resize(width: Option<u32>, height: Option<u32>, fit: Fit, format: Format) -> Transform {
+        Transform::Resize {
+            width,
+            height,
+            fit,
+            format,
+            quality: DEFAULT_QUALITY,
+        }
+    }
+
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        encode(&DynamicImage::new_rgb8(width, height), Format::Jpeg, 80).unwrap()
+    }
+
+    #[test]
+    fn parses_size_parameters() {
+        assert_eq!(
+            parse("w=800", Format::Png),
+            Some(Some(resize(Some(800), None, Fit::Contain, Format::Png)))
+        );
+        assert_eq!(
+            parse("width=10&height=20&fit=crop&fm=webp&v=2", Format::Png),
+            Some(Some(resize(Some(10), Some(20), Fit::Cover, Format::Webp)))
+        );
+        assert_eq!(
+            parse("format=jpg&q=50", Format::Png),
+            Some(Some(Transform::Resize {
+                width: None,
+                height: None,
+                fit: Fit::Contain,
+                format: Format::Jpeg,
+                quality: 50,
+            }))
+        );
+    }
+
+    #[test]
+    fn strips_jpegs_without_parameters() {
+        assert_eq!(parse("", Format::Jpeg), Some(Some(Transform::Strip)));
+        assert_eq!(parse("v=2", Format::Jpeg), Some(Some(Transform::Strip)));
+        assert_eq!(parse("", Format::Png), Some(None));
+        assert_eq!(Transform::Strip.format(Format::Jpeg), Format::Jpeg);
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        for query in [
+            "w=0",
+            "w=4097",
+            "h=-1",
+            "w=abc",
+            "fit=stretch",
+            "format=gif",
+            "q=0",
+            "q=101",
+            "fit=cover&w=10",
+        ] {
+            assert!(parse(query, Format::Png).is_none(), "{query}");
+        }
+    }
+
+    #[test]
+    fn strips_metadata_from_jpegs() {
+        let data = jpeg(4, 4);
+        assert!(strip_jpeg(&data).unwrap().is_none());
+
+        // an EXIF segment right after the start of image marker
+        let exif = [0xFF, 0xE1, 0x00, 0x08, b'E', b'x', b'i', b'f', 0, 0];
+        let mut with_exif = data[..2].to_vec();
+        with_exif.extend_from_slice(&exif);
+        with_exif.extend_from_slice(&data[2..]);
+
+        assert_eq!(strip_jpeg(&with_exif).unwrap(), Some(data));
+        assert!(strip_jpeg(b"GIF89a").is_err());
+    }
+
+    #[test]
+    fn resizes_without_scaling_up() {
+        let dir = std::env::temp_dir().join(format!("dawdle-images-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (source, target) = (dir.join("source.jpg"), dir.join("target.png"));
+        std::fs::write(&source, jpeg(40, 20)).unwrap();
+
+        let size = |transform| {
+            assert!(process(&source, &target, transform).unwrap());
+            let image = image::open(&target).unwrap();
+            (image.width(), image.height())
+        };
+        assert_eq!(
+            size(resize(Some(10), None, Fit::Contain, Format::Png)),
+            (10, 5)
+        );
+        assert_eq!(
+            size(resize(Some(10), Some(10), Fit::Cover, Format::Png)),
+            (10, 10)
+        );
+        assert_eq!(
+            size(resize(Some(100), Some(100), Fit::Contain, Format::Png)),
+            (40, 20)
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
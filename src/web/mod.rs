@@ -1,10 +1,12 @@
 use crate::{
     app::{App, Website},
+    config::WebConfig,
+    ssg::themes,
     web::errors::APIError,
 };
 use axum::{
     body::Body,
-    extract::Request,
+    extract::{DefaultBodyLimit, Request},
     handler::HandlerWithoutStateExt,
     http::{header, HeaderValue, StatusCode},
     response::IntoResponse,
@@ -14,24 +16,34 @@ use axum::{
 
 use errors::ApiErrorExt;
 use eyre::Result;
-use std::net::SocketAddr;
-use tower::{Service, ServiceBuilder, ServiceExt};
+use std::{net::SocketAddr, sync::Arc};
+use tower::{util::BoxCloneService, Service, ServiceBuilder, ServiceExt};
 use tower_http::set_header::SetResponseHeaderLayer;
 
 use self::{
+    acme::Acme,
     errors::{APIResult, NOT_FOUND},
-    files::create_dir_service,
+    files::{create_dir_service, SiteUrl},
+    tls::Certificates,
 };
 
+mod acme;
 mod api;
 mod api_admin;
+mod autoindex;
 mod chat;
+mod compression;
 mod errors;
 mod files;
+mod images;
 mod middleware;
+mod site_config;
+pub mod tls;
 mod webdav;
 
 pub async fn run(state: App, addr: SocketAddr) -> Result<()> {
+    images::init(state.config.image_cache_dir());
+
     let admin_router = Router::new()
         .route("/", post(api_admin::is_admin))
         .route("/applications", get(api_admin::get_applications))
@@ -49,14 +61,17 @@ pub async fn run(state: App, addr: SocketAddr) -> Result<()> {
         )
         .route("/applications", delete(api_admin::delete_application))
         .route("/users", get(api_admin::get_users))
-        .route("/user/{username}", delete(api_admin::delete_user));
-
-    let www_path = state
-        .config
-        .user_home("henry")
-        .unwrap()
-        .join("sites")
-        .join("dawdle.space");
+        .route("/user/{username}", delete(api_admin::delete_user))
+        .route("/themes", get(api_admin::get_themes))
+        .route(
+            "/themes",
+            post(api_admin::install_theme)
+                .layer(DefaultBodyLimit::max(themes::MAX_INSTALL_REQUEST_SIZE)),
+        )
+        .route("/chat/filters", get(api_admin::get_chat_filters))
+        .route("/chat/filters", post(api_admin::add_chat_filter))
+        .route("/chat/filters", delete(api_admin::delete_chat_filter))
+        .route("/chat/moderators", post(api_admin::set_chat_moderator));
 
     let router = Router::new()
         .nest(
@@ -71,6 +86,9 @@ pub async fn run(state: App, addr: SocketAddr) -> Result<()> {
                 .route("/minecraft", post(api::update_minecraft_username))
                 .route("/public_key", post(api::add_public_key))
                 .route("/public_key", delete(api::remove_public_key))
+                .route("/app_passwords", get(api::get_app_passwords))
+                .route("/app_passwords", post(api::create_app_password))
+                .route("/app_passwords", delete(api::remove_app_password))
                 .route("/apply", post(api::apply))
                 .route("/claim", post(api::claim))
                 .route("/sites", get(api::get_sites))
@@ -80,19 +98,23 @@ pub async fn run(state: App, addr: SocketAddr) -> Result<()> {
         )
         .route("/api/webdav", any(webdav::handler))
         .route("/api/webdav/", any(webdav::handler))
-        .route("/api/webdav/*rest", any(webdav::handler))
-        .fallback_service(create_dir_service(
-            www_path.clone(),
-            www_path.join("404.html"),
+        .route("/api/webdav/*rest", any(webdav::handler));
+
+    let router = match state.config.main_site_path() {
+        Some(path) => router.fallback_service(create_dir_service(
+            path.clone(),
+            path.join("404.html"),
             NOT_FOUND,
-        ))
-        .with_state(state.clone());
+        )),
+        None => router.fallback(|| async { NOT_FOUND }),
+    }
+    .with_state(state.clone());
 
     // only construct the router service once
     let mut router_service = ServiceBuilder::new()
         .layer(SetResponseHeaderLayer::if_not_present(
             header::SERVER,
-            HeaderValue::from_static("dawdle.space"),
+            HeaderValue::from_str(&state.config.web.domain)?,
         ))
         .layer(SetResponseHeaderLayer::if_not_present(
             header::REFERRER_POLICY,
@@ -114,21 +136,56 @@ pub async fn run(state: App, addr: SocketAddr) -> Result<()> {
 
     router_service.ready().await?;
 
+    let tls = match state.config.web.tls.clone() {
+        Some(tls_config) => {
+            let certificates = Arc::new(Certificates::load(state.config.certs_dir())?);
+            let acme = Arc::new(Acme::new(
+                state.clone(),
+                tls_config.clone(),
+                certificates.clone(),
+            ));
+            Some((tls_config, certificates, acme))
+        }
+        None => None,
+    };
+
     // Use a different service based on the hostname
-    let app = |request: Request| async move {
+    let app = |mut request: Request| async move {
         let hostname_header = request
             .headers()
             .get("HOST")
             .api_error(StatusCode::BAD_REQUEST, Some("no hostname"))?
             .to_str()
-            .api_error(StatusCode::BAD_REQUEST, Some("invalid hostname"))?;
+            .api_error(StatusCode::BAD_REQUEST, Some("invalid hostname"))?
+            .to_string();
 
-        let site = match select_service(hostname_header) {
+        let config = &state.config.web;
+        let scheme = match request.extensions().get::<Https>() {
+            Some(_) => "https",
+            None => "http",
+        };
+        let site_url = |hostname: &str| {
+            let port = match hostname_header.split_once(':') {
+                Some((_, port)) => format!(":{port}"),
+                None => String::new(),
+            };
+            SiteUrl(format!("{scheme}://{hostname}{port}"))
+        };
+
+        let site = match select_service(&hostname_header, config) {
             Ok(SelectedService::DawdleSpace) => {
+                request.extensions_mut().insert(site_url(&config.domain));
                 return APIResult::Ok(router_service.call(request).await.into_response());
             }
-            Ok(SelectedService::Subdomain(subdomain)) => state.sites.get(&subdomain),
-            Ok(SelectedService::CustomDomain(hostname)) => state.sites.get(&hostname),
+            Ok(SelectedService::Subdomain(subdomain)) => {
+                let hostname = format!("{subdomain}.{}", config.domain);
+                request.extensions_mut().insert(site_url(&hostname));
+                state.sites.get(&subdomain)
+            }
+            Ok(SelectedService::CustomDomain(hostname)) => {
+                request.extensions_mut().insert(site_url(&hostname));
+                state.sites.get(&hostname)
+            }
             Err(err) => return APIResult::Err(err),
         };
 
@@ -153,12 +210,60 @@ pub async fn run(state: App, addr: SocketAddr) -> Result<()> {
         }
     };
 
+    let Some((tls_config, certificates, acme)) = tls else {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        axum::serve(listener, app.into_service()).await?;
+        return Ok(());
+    };
+
+    let tls_addr = SocketAddr::new(addr.ip(), tls_config.port);
+    let https_app = {
+        let app = app.clone();
+        move |mut request: Request| {
+            request.extensions_mut().insert(Https);
+            app(request)
+        }
+    };
+    let tls_server = tls::serve(
+        tls_addr,
+        certificates.clone(),
+        BoxCloneService::new(https_app.into_service()),
+    );
+
+    // plain HTTP answers ACME challenges and redirects to HTTPS once a certificate exists
+    let http_acme = acme.clone();
+    let http_app = move |request: Request| async move {
+        if let Some(res) = http_acme.challenge_response(&request) {
+            return res;
+        }
+
+        if tls_config.redirect_http {
+            if let Some(res) = tls::https_redirect(&request, &certificates, tls_config.port) {
+                return res;
+            }
+        }
+
+        app(request).await.into_response()
+    };
+
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app.into_service()).await?;
+    let http_server = axum::serve(listener, http_app.into_service());
+
+    log::info!("tls server listening on {}", tls_addr);
+
+    tokio::select! {
+        r = http_server => r?,
+        r = tls_server => r?,
+        r = acme.run() => r?,
+    };
 
     Ok(())
 }
 
+// set for requests from the tls listener
+#[derive(Clone, Copy)]
+struct Https;
+
 #[derive(Debug)]
 enum SelectedService {
     DawdleSpace,
@@ -166,53 +271,45 @@ enum SelectedService {
     CustomDomain(String),
 }
 
-fn select_service(hostname_header: &str) -> APIResult<SelectedService> {
-    let (hostname, port) = if let Some(colon) = hostname_header.find(':') {
-        let (hostname, port) = hostname_header.split_at(colon);
-        (hostname, &port[1..])
-    } else {
-        (hostname_header, "80")
+fn select_service(hostname_header: &str, config: &WebConfig) -> APIResult<SelectedService> {
+    let (hostname, port) = match hostname_header.split_once(':') {
+        Some((hostname, port)) => (hostname, Some(port)),
+        None => (hostname_header, None),
     };
 
-    let Ok(domain) = addr::parse_domain_name(hostname) else {
+    if addr::parse_domain_name(hostname).is_err() {
         return Err(APIError::new(StatusCode::BAD_REQUEST, "invalid hostname"));
-    };
+    }
+
+    if let Some(port) = port {
+        let port = port
+            .parse::<u16>()
+            .api_error(StatusCode::BAD_REQUEST, Some("invalid port"))?;
 
-    if !cfg!(debug_assertions) && port != "80" {
-        return Err(APIError::new(StatusCode::BAD_REQUEST, "invalid port"));
+        if !config.public_ports.is_empty() && !config.public_ports.contains(&port) {
+            return Err(APIError::new(StatusCode::BAD_REQUEST, "invalid port"));
+        }
     }
 
-    if is_api(domain) {
+    let hostname = hostname.to_ascii_lowercase();
+    if is_api(&hostname, config) {
         return Ok(SelectedService::DawdleSpace);
     }
 
-    Ok(match is_on_dawdle_space(domain) {
-        true => {
-            let subdomain = domain
-                .prefix()
-                .map(|s| s.to_string())
-                .api_error(StatusCode::BAD_REQUEST, Some("invalid hostname"))?;
-            SelectedService::Subdomain(subdomain)
-        }
-        false => SelectedService::CustomDomain(hostname.to_string()),
+    Ok(match platform_subdomain(&hostname, config) {
+        Some(subdomain) => SelectedService::Subdomain(subdomain.to_string()),
+        None => SelectedService::CustomDomain(hostname),
     })
 }
 
-fn is_on_dawdle_space(domain: addr::domain::Name) -> bool {
-    if cfg!(debug_assertions) {
-        (domain.root() == Some("dawdle.localhost") && domain.suffix() == "localhost")
-            || (domain.root().is_none() && domain.suffix() == "localhost")
-    } else {
-        domain.root() == Some("dawdle.space") && domain.suffix() == "space"
-    }
+// the part of the hostname in front of the platform domain, e.g. `henry` for `henry.dawdle.space`
+fn platform_subdomain<'a>(hostname: &'a str, config: &WebConfig) -> Option<&'a str> {
+    hostname
+        .strip_suffix(config.domain.as_str())?
+        .strip_suffix('.')
+        .filter(|subdomain| !subdomain.is_empty())
 }
 
-fn is_api(domain: addr::domain::Name) -> bool {
-    if cfg!(debug_assertions) {
-        is_on_dawdle_space(domain) && domain.prefix().is_none()
-    } else {
-        domain.root() == Some("dawdle.space")
-            && domain.prefix().is_none()
-            && domain.suffix() == "space"
-    }
+fn is_api(hostname: &str, config: &WebConfig) -> bool {
+    hostname == config.domain || config.api_hostnames.iter().any(|h| h == hostname)
 }
@@ -0,0 +1,421 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock},
+    time::SystemTime,
+};
+
+use axum::{
+    http::{header, HeaderName, HeaderValue, StatusCode, Uri},
+    response::Response,
+};
+use dashmap::DashMap;
+use eyre::Result;
+use serde::Deserialize;
+
+// per-site configuration, read from the root of a site:
+// - `_dawdle.toml`
+// - `_redirects` and `_headers` in the format used by netlify
+const CONFIG_FILE: &str = "_dawdle.toml";
+const REDIRECTS_FILE: &str = "_redirects";
+const HEADERS_FILE: &str = "_headers";
+const CONFIG_FILES: [&str; 3] = [CONFIG_FILE, REDIRECTS_FILE, HEADERS_FILE];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SiteConfig {
+    /// Custom 404 page, relative to the site root
+    pub not_found: Option<String>,
+
+    /// Serve `index.html` for all paths that don't exist
+    pub spa: bool,
+
+    /// Directories (path globs) to list if they have no index page
+    pub autoindex: Vec<String>,
+
+    pub redirects: Vec<RedirectRule>,
+    pub headers: Vec<HeaderRule>,
+    pub cache: Vec<CacheRule>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RedirectRule {
+    pub from: String,
+    pub to: String,
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HeaderRule {
+    #[serde(rename = "for")]
+    pub path: String,
+    pub values: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CacheRule {
+    #[serde(rename = "for")]
+    pub path: String,
+    pub control: String,
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+pub enum Redirect {
+    // send the client elsewhere
+    Redirect(StatusCode, String),
+    // serve a different path (status 200)
+    Rewrite(Uri),
+}
+
+impl SiteConfig {
+    pub fn redirect(&self, uri: &Uri) -> Option<Redirect> {
+        let path = match uri.path().trim_end_matches('/') {
+            "" => "/",
+            path => path,
+        };
+
+        self.redirects.iter().find_map(|rule| {
+            let params = match_route(&rule.from, path)?;
+            let mut to = rule.to.clone();
+            for (name, value) in &params {
+                to = to.replace(&format!(":{name}"), value);
+            }
+
+            // keep the query string unless the target sets its own
+            if let Some(query) = uri.query() {
+                if !to.contains('?') {
+                    to = format!("{to}?{query}");
+                }
+            }
+
+            match rule.status {
+                200 => to.parse().ok().map(Redirect::Rewrite),
+                status => {
+                    let status = StatusCode::from_u16(status)
+                        .ok()
+                        .filter(|s| s.is_redirection())
+                        .unwrap_or(StatusCode::MOVED_PERMANENTLY);
+                    Some(Redirect::Redirect(status, to))
+                }
+            }
+        })
+    }
+
+    pub fn fallback_file(&self, base_path: &Path) -> Option<PathBuf> {
+        if self.spa {
+            return Some(base_path.join("index.html"));
+        }
+
+        let not_found = self.not_found.as_ref()?;
+        super::files::build_and_validate_path(base_path, not_found)
+    }
+
+    pub fn autoindex_enabled(&self, path: &str) -> bool {
+        self.autoindex.iter().any(|glob| glob_match(glob, path))
+    }
+
+    pub fn apply_headers(&self, path: &str, res: &mut Response) {
+        for rule in self.headers.iter().filter(|r| glob_match(&r.path, path)) {
+            for (name, value) in &rule.values {
+                match (
+                    HeaderName::try_from(name.as_str()),
+                    HeaderValue::try_from(value.as_str()),
+                ) {
+                    (Ok(name), Ok(value)) => {
+                        res.headers_mut().insert(name, value);
+                    }
+                    _ => log::warn!("invalid header in site config: {name}: {value}"),
+                }
+            }
+        }
+
+        if let Some(rule) = self.cache.iter().rev().find(|r| glob_match(&r.path, path)) {
+            if let Ok(value) = HeaderValue::try_from(rule.control.as_str()) {
+                res.headers_mut().insert(header::CACHE_CONTROL, value);
+            }
+        }
+    }
+
+    fn parse(base_path: &Path) -> Result<Self> {
+        let mut config = match std::fs::read_to_string(base_path.join(CONFIG_FILE)) {
+            Ok(toml) => toml::from_str::<SiteConfig>(&toml)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => SiteConfig::default(),
+            Err(err) => return Err(err.into()),
+        };
+
+        if let Ok(redirects) = std::fs::read_to_string(base_path.join(REDIRECTS_FILE)) {
+            config.redirects.extend(parse_redirects(&redirects));
+        }
+
+        if let Ok(headers) = std::fs::read_to_string(base_path.join(HEADERS_FILE)) {
+            config.headers.extend(parse_headers(&headers));
+        }
+
+        Ok(config)
+    }
+}
+
+// the config files themselves are never served, `path` is the decoded path relative to the site
+pub fn is_config_file(path: &Path) -> bool {
+    CONFIG_FILES.iter().any(|file| path == Path::new(file))
+}
+
+struct CachedConfig {
+    mtimes: Vec<Option<SystemTime>>,
+    config: Arc<SiteConfig>,
+}
+
+static CONFIGS: LazyLock<DashMap<PathBuf, CachedConfig>> = LazyLock::new(DashMap::new);
+
+// returns the config for the site at `base_path`, re-parsing it only when one of the files changed
+pub async fn load(base_path: &Path) -> Arc<SiteConfig> {
+    let mut mtimes = Vec::with_capacity(CONFIG_FILES.len());
+    for file in CONFIG_FILES {
+        let mtime = tokio::fs::metadata(base_path.join(file))
+            .await
+            .and_then(|meta| meta.modified())
+            .ok();
+        mtimes.push(mtime);
+    }
+
+    if let Some(cached) = CONFIGS.get(base_path) {
+        if cached.mtimes == mtimes {
+            return cached.config.clone();
+        }
+    }
+
+    let config = if mtimes.iter().all(Option::is_none) {
+        SiteConfig::default()
+    } else {
+        let path = base_path.to_path_buf();
+        match tokio::task::spawn_blocking(move || SiteConfig::parse(&path)).await {
+            Ok(Ok(config)) => config,
+            Ok(Err(err)) => {
+                log::warn!("invalid site config in {}: {err}", base_path.display());
+                SiteConfig::default()
+            }
+            Err(err) => {
+                log::error!("failed to load site config: {err}");
+                SiteConfig::default()
+            }
+        }
+    };
+
+    let config = Arc::new(config);
+    CONFIGS.insert(
+        base_path.to_path_buf(),
+        CachedConfig {
+            mtimes,
+            config: config.clone(),
+        },
+    );
+    config
+}
+
+// `from to [status]` per line
+fn parse_redirects(input: &str) -> Vec<RedirectRule> {
+    input
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let from = parts.next()?.to_string();
+            let to = parts.next()?.to_string();
+            let status = parts
+                .next()
+                .and_then(|s| s.trim_end_matches('!').parse().ok())
+                .unwrap_or_else(default_redirect_status);
+            Some(RedirectRule { from, to, status })
+        })
+        .collect()
+}
+
+// a path on its own line, followed by indented `Name: value` lines
+fn parse_headers(input: &str) -> Vec<HeaderRule> {
+    let mut rules: Vec<HeaderRule> = Vec::new();
+
+    for line in input.lines() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+
+        if !line.starts_with(char::is_whitespace) {
+            rules.push(HeaderRule {
+                path: line.trim().to_string(),
+                values: BTreeMap::new(),
+            });
+            continue;
+        }
+
+        let (Some(rule), Some((name, value))) = (rules.last_mut(), line.split_once(':')) else {
+            continue;
+        };
+        rule.values
+            .insert(name.trim().to_string(), value.trim().to_string());
+    }
+
+    rules
+}
+
+// matches paths like `/blog/:year/:slug` or `/old/*`, returning the named segments
+// and everything matched by a trailing `*` as `splat`
+fn match_route(pattern: &str, path: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    let mut pattern_parts = pattern.trim_end_matches('/').split('/');
+    let mut path_parts = path.trim_end_matches('/').split('/');
+
+    loop {
+        match (pattern_parts.next(), path_parts.next()) {
+            (Some("*"), Some(part)) => {
+                let rest = std::iter::once(part).chain(path_parts).collect::<Vec<_>>();
+                params.insert("splat".to_string(), rest.join("/"));
+                return Some(params);
+            }
+            (Some("*"), None) => {
+                params.insert("splat".to_string(), String::new());
+                return Some(params);
+            }
+            (Some(p), Some(part)) if p.starts_with(':') && !part.is_empty() => {
+                params.insert(p[1..].to_string(), part.to_string());
+            }
+            (Some(p), Some(part)) if p == part => {}
+            (None, None) => return Some(params),
+            _ => return None,
+        }
+    }
+}
+
+// `*` matches any number of characters, including `/`
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let Some((first, rest)) = pattern.split_once('*') else {
+        return pattern == path;
+    };
+
+    let Some(mut remaining) = path.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts = rest.split('*').collect::<Vec<_>>();
+    for (i, part) in parts.iter().enumerate() {
+        if i == parts.len() - 1 {
+            return remaining.ends_with(part);
+        }
+
+        match remaining.find(part) {
+            Some(idx) => remaining = &remaining[idx + part.len()..],
+            None => return false,
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pattern: &str, path: &str) -> Option<BTreeMap<String, String>> {
+        match_route(pattern, path).map(|params| params.into_iter().collect())
+    }
+
+    #[test]
+    fn matches_routes() {
+        assert_eq!(params("/about", "/about"), Some(BTreeMap::new()));
+        assert_eq!(params("/about/", "/about"), Some(BTreeMap::new()));
+        assert_eq!(params("/about", "/contact"), None);
+        assert_eq!(params("/about", "/about/team"), None);
+
+        let params = params("/blog/:year/:slug", "/blog/2024/hello").unwrap();
+        assert_eq!(params["year"], "2024");
+        assert_eq!(params["slug"], "hello");
+        assert_eq!(super::match_route("/blog/:year", "/blog//"), None);
+    }
+
+    #[test]
+    fn matches_splats() {
+        assert_eq!(params("/old/*", "/old/a/b").unwrap()["splat"], "a/b");
+        assert_eq!(params("/old/*", "/old").unwrap()["splat"], "");
+        assert_eq!(params("/*", "/").unwrap()["splat"], "");
+        assert_eq!(params("/old/*", "/older/a"), None);
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("/index.html", "/index.html"));
+        assert!(!glob_match("/index.html", "/index.htm"));
+        assert!(glob_match("/*", "/a/b/c.css"));
+        assert!(glob_match("/assets/*.css", "/assets/css/site.css"));
+        assert!(!glob_match("/assets/*.css", "/assets/site.js"));
+        assert!(glob_match("/*/*.png", "/a/b.png"));
+        assert!(!glob_match("/a*a", "/a"));
+        assert!(glob_match("/a*b*b", "/abb"));
+    }
+
+    #[test]
+    fn parses_redirects() {
+        let rules = parse_redirects(
+            "# comment\n\n/old /new\n/blog/:slug /posts/:slug 302\n/app/* /index.html 200!\n/broken\n",
+        );
+        let rules = rules
+            .iter()
+            .map(|r| (r.from.as_str(), r.to.as_str(), r.status))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rules,
+            [
+                ("/old", "/new", 301),
+                ("/blog/:slug", "/posts/:slug", 302),
+                ("/app/*", "/index.html", 200),
+            ]
+        );
+    }
+
+    #[test]
+    fn detects_config_files() {
+        assert!(is_config_file(Path::new("_redirects")));
+        assert!(is_config_file(Path::new("_dawdle.toml")));
+        assert!(!is_config_file(Path::new("docs/_redirects")));
+        assert!(!is_config_file(Path::new("_headers.txt")));
+    }
+
+    #[test]
+    fn parses_headers() {
+        let rules = parse_headers(
+            "/*\n  X-Frame-Options: DENY\n  # comment\n\n/assets/*\n  Cache-Control: max-age=60, public\n  invalid\n",
+        );
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].path, "/*");
+        assert_eq!(rules[0].values["X-Frame-Options"], "DENY");
+        assert_eq!(rules[1].path, "/assets/*");
+        assert_eq!(rules[1].values["Cache-Control"], "max-age=60, public");
+        assert_eq!(rules[1].values.len(), 1);
+    }
+
+    #[test]
+    fn redirects_with_params_and_query() {
+        let config = SiteConfig {
+            redirects: parse_redirects("/blog/:slug /posts/:slug\n/app/* /index.html 200"),
+            ..Default::default()
+        };
+
+        let uri = "/blog/hello/?ref=feed".parse().unwrap();
+        let Some(Redirect::Redirect(status, to)) = config.redirect(&uri) else {
+            panic!("expected a redirect");
+        };
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(to, "/posts/hello?ref=feed");
+
+        let uri = "/app/settings".parse().unwrap();
+        let Some(Redirect::Rewrite(uri)) = config.redirect(&uri) else {
+            panic!("expected a rewrite");
+        };
+        assert_eq!(uri.path(), "/index.html");
+
+        assert!(config.redirect(&"/other".parse().unwrap()).is_none());
+    }
+}
@@ -0,0 +1,219 @@
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, StatusCode},
+    response::Response,
+};
+use dashmap::DashMap;
+use eyre::{eyre, Result};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use std::convert::Infallible;
+use tokio_rustls::{
+    rustls::{
+        crypto::ring::{default_provider, sign::any_supported_type},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tower::{util::BoxCloneService, ServiceExt};
+
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+
+#[derive(Debug)]
+struct StoredCertificate {
+    key: Arc<CertifiedKey>,
+    not_after: time::OffsetDateTime,
+}
+
+// certificates are stored as `<certs_dir>/<domain>/{cert.pem,key.pem}`
+#[derive(Debug)]
+pub struct Certificates {
+    dir: PathBuf,
+    certs: DashMap<String, StoredCertificate>,
+}
+
+impl Certificates {
+    pub fn load(dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let certs = Self {
+            dir,
+            certs: DashMap::new(),
+        };
+        certs.reload()?;
+        Ok(certs)
+    }
+
+    // reads every certificate from disk, the irc server calls this periodically to pick up
+    // certificates issued or renewed by the web server
+    pub fn reload(&self) -> Result<()> {
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let domain = entry.file_name().to_string_lossy().to_string();
+            let cert_pem = std::fs::read(entry.path().join(CERT_FILE));
+            let key_pem = std::fs::read(entry.path().join(KEY_FILE));
+
+            let (Ok(cert_pem), Ok(key_pem)) = (cert_pem, key_pem) else {
+                log::warn!("incomplete certificate for {domain}, ignoring");
+                continue;
+            };
+
+            match parse_certificate(&cert_pem, &key_pem) {
+                Ok(cert) => {
+                    log::info!("loaded certificate for {domain}");
+                    self.certs.insert(domain, cert);
+                }
+                Err(err) => log::warn!("invalid certificate for {domain}: {err}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn has(&self, domain: &str) -> bool {
+        self.certs.contains_key(domain)
+    }
+
+    pub fn expires_at(&self, domain: &str) -> Option<time::OffsetDateTime> {
+        self.certs.get(domain).map(|cert| cert.not_after)
+    }
+
+    pub fn store(&self, domain: &str, cert_pem: &str, key_pem: &str) -> Result<()> {
+        let cert = parse_certificate(cert_pem.as_bytes(), key_pem.as_bytes())?;
+
+        let dir = self.dir.join(domain);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join(CERT_FILE), cert_pem)?;
+        std::fs::write(dir.join(KEY_FILE), key_pem)?;
+
+        self.certs.insert(domain.to_string(), cert);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let domain = client_hello.server_name()?.to_ascii_lowercase();
+        self.certs.get(&domain).map(|cert| cert.key.clone())
+    }
+}
+
+fn parse_certificate(cert_pem: &[u8], key_pem: &[u8]) -> Result<StoredCertificate> {
+    let chain = CertificateDer::pem_slice_iter(cert_pem).collect::<Result<Vec<_>, _>>()?;
+    let leaf = chain
+        .first()
+        .ok_or_else(|| eyre!("empty certificate chain"))?;
+
+    let (_, parsed) = x509_parser::parse_x509_certificate(leaf)?;
+    let not_after =
+        time::OffsetDateTime::from_unix_timestamp(parsed.validity().not_after.timestamp())?;
+
+    let key = PrivateKeyDer::from_pem_slice(key_pem)?;
+    let key = any_supported_type(&key)?;
+
+    Ok(StoredCertificate {
+        key: Arc::new(CertifiedKey::new(chain, key)),
+        not_after,
+    })
+}
+
+// redirect to https if we have a certificate for the requested host
+pub fn https_redirect(req: &Request, certificates: &Certificates, port: u16) -> Option<Response> {
+    let host = req.headers().get(header::HOST)?.to_str().ok()?;
+    let host = host.split(':').next()?.to_ascii_lowercase();
+
+    if !certificates.has(&host) {
+        return None;
+    }
+
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+
+    let location = match port {
+        443 => format!("https://{host}{path_and_query}"),
+        port => format!("https://{host}:{port}{path_and_query}"),
+    };
+
+    Some(
+        Response::builder()
+            .status(StatusCode::MOVED_PERMANENTLY)
+            .header(header::LOCATION, location)
+            .body(Body::empty())
+            .unwrap(),
+    )
+}
+
+// picks the certificate by the SNI hostname
+pub fn acceptor(
+    certificates: Arc<Certificates>,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> Result<TlsAcceptor> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(certificates);
+    config.alpn_protocols = alpn_protocols;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+pub async fn serve(
+    addr: SocketAddr,
+    certificates: Arc<Certificates>,
+    service: BoxCloneService<Request, Response, Infallible>,
+) -> Result<()> {
+    let acceptor = acceptor(certificates, vec![b"h2".to_vec(), b"http/1.1".to_vec()])?;
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::error!("failed to accept tls connection: {err}");
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let service = service
+            .clone()
+            .map_request(|req: Request<hyper::body::Incoming>| req.map(Body::new));
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::debug!("tls handshake with {remote} failed: {err}");
+                    return;
+                }
+            };
+
+            let res = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(
+                    TokioIo::new(stream),
+                    TowerToHyperService::new(service),
+                )
+                .await;
+
+            if let Err(err) = res {
+                log::debug!("tls connection with {remote} failed: {err}");
+            }
+        });
+    }
+}